@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use anyhow::Context as _;
 use glam::UVec2;
 use log::{error, info, trace};
@@ -7,8 +9,8 @@ use crate::in_flight_frames::InFlightFrames;
 use crate::vulkan::entry::Entry;
 use crate::vulkan::utils::physicalsize_to_uvec2;
 use crate::vulkan::Context;
-use crate::{OctaResult, SemaphoreSubmitInfo};
-use crate::{controls::Controls, gui::Gui, hot_reloading::HotReloadConfig, stats::FrameStats, CommandBuffer, CommandPool, Swapchain};
+use crate::OctaResult;
+use crate::{controls::Controls, gui::Gui, hot_reloading::HotReloadConfig, stats::FrameStats, CommandBuffer, CommandPool, StagingBelt, Swapchain, ThreadCommandPools};
 
 use ash::vk::{self};
 use winit::{
@@ -36,11 +38,22 @@ pub struct EngineConfig {
 
     pub backtrace: bool,
     pub ray_tracing: EngineFeatureValue,
+    /// VK_KHR_ray_query with acceleration structures, without the ray tracing pipeline.
+    pub ray_query: EngineFeatureValue,
     pub compute_rendering: EngineFeatureValue,
     pub validation_layers: EngineFeatureValue,
     pub shader_debug_printing: EngineFeatureValue,
     pub shader_debug_clock: EngineFeatureValue,
     pub gl_ext_scalar_block_layout: EngineFeatureValue,
+    /// multiDrawIndirect, drawIndirectCount and drawIndirectFirstInstance
+    pub indirect_drawing: EngineFeatureValue,
+    pub mesh_shading: EngineFeatureValue,
+    /// Synchronize the in flight frames with one timeline semaphore instead of a fence per frame.
+    pub timeline_frame_sync: EngineFeatureValue,
+    /// VK_EXT_descriptor_buffer, for writing descriptors directly into buffers with DescriptorBuffer.
+    pub descriptor_buffer: EngineFeatureValue,
+    /// VK_KHR_push_descriptor, for CommandBuffer::push_descriptor_set.
+    pub push_descriptors: EngineFeatureValue,
     
     pub required_extensions: Vec<String>,
     pub wanted_extensions: Vec<String>,
@@ -50,6 +63,12 @@ pub struct EngineConfig {
 
     pub hot_reload_config: Option<HotReloadConfig>,
     pub num_frames_in_flight: usize,
+    pub staging_belt_size: u64,
+    /// Number of command pools per in flight frame for recording secondary command buffers on multiple threads.
+    pub num_recording_threads: usize,
+    /// Pipeline cache file that is loaded on startup and saved on exit. Defaults to <executable>.pipeline_cache next to the executable.
+    /// None keeps the cache in memory only.
+    pub pipeline_cache_path: Option<PathBuf>,
 }
 
 impl Default for EngineConfig {
@@ -58,22 +77,36 @@ impl Default for EngineConfig {
             name: "No Name".to_string(), 
             start_size: UVec2 { x: 1080, y: 720 }, 
             ray_tracing: EngineFeatureValue::NotUsed, 
+            ray_query: EngineFeatureValue::NotUsed,
             compute_rendering: EngineFeatureValue::NotUsed, 
             validation_layers: EngineFeatureValue::NotUsed, 
             shader_debug_printing: EngineFeatureValue::NotUsed, 
             shader_debug_clock: EngineFeatureValue::NotUsed, 
             gl_ext_scalar_block_layout: EngineFeatureValue::NotUsed, 
+            indirect_drawing: EngineFeatureValue::NotUsed,
+            mesh_shading: EngineFeatureValue::NotUsed,
+            timeline_frame_sync: EngineFeatureValue::NotUsed,
+            descriptor_buffer: EngineFeatureValue::NotUsed,
+            push_descriptors: EngineFeatureValue::NotUsed,
             required_extensions: vec![], 
             wanted_extensions: vec![], 
             required_device_features: vec![], 
             wanted_device_features: vec![], 
             hot_reload_config: None, 
             num_frames_in_flight: 2,
+            staging_belt_size: 32 * 1024 * 1024,
+            num_recording_threads: 0,
+            pipeline_cache_path: default_pipeline_cache_path(),
             backtrace: true,
         }
     }
 }
 
+fn default_pipeline_cache_path() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    Some(exe.with_extension("pipeline_cache"))
+}
+
 #[derive(Debug)]
 pub struct Engine {
     pub frame_stats: FrameStats,
@@ -86,6 +119,8 @@ pub struct Engine {
     pub command_pool: CommandPool,
     pub command_buffers: Vec<CommandBuffer>,
     pub in_flight_frames: InFlightFrames,
+    pub staging_belt: StagingBelt,
+    pub thread_command_pools: ThreadCommandPools,
     pub context: Context,
 }
 
@@ -121,6 +156,16 @@ impl Engine {
         let in_flight_frames = InFlightFrames::new(
             &context, 
             swapchain.images_and_views.len(),  
+            engine_config.num_frames_in_flight,
+            engine_config.timeline_frame_sync != EngineFeatureValue::NotUsed && context.timeline_semaphores)?;
+
+        let staging_belt = StagingBelt::new(
+            &context,
+            engine_config.staging_belt_size,
+            engine_config.num_frames_in_flight)?;
+
+        let thread_command_pools = context.create_thread_command_pools(
+            engine_config.num_recording_threads,
             engine_config.num_frames_in_flight)?;
 
         let controls = Controls::default();
@@ -141,6 +186,8 @@ impl Engine {
             swapchain,
             command_buffers,
             in_flight_frames,
+            staging_belt,
+            thread_command_pools,
             controls,
             frame_stats,
             gui,
@@ -174,16 +221,15 @@ impl Engine {
 
         // Drawing the frame
         self.in_flight_frames.next();
-        self.in_flight_frames.fence().wait(None)?;
-        self.in_flight_frames.fence().reset()?;
-
-        // Can't get for gpu time on the first frames or vkGetQueryPoolResults gets stuck
-        // due to VK_QUERY_RESULT_WAIT_BIT
-        let gpu_time = (self.frame_stats.total_frame_count >= self.in_flight_frames.num_frames)
-            .then(|| self.in_flight_frames.gpu_frame_time_ms())
-            .transpose()?
-            .unwrap_or_default();
-        self.frame_stats.set_gpu_time(gpu_time);
+        self.in_flight_frames.wait_for_frame()?;
+        self.staging_belt.retire_frame(self.in_flight_frames.in_flight_index);
+        self.context.deletion_queue.retire_frames(
+            self.in_flight_frames.frame_number,
+            self.in_flight_frames.num_frames_in_flight,
+        );
+        self.thread_command_pools.reset(self.in_flight_frames.in_flight_index)?;
+
+        self.frame_stats.set_gpu_scopes(self.context.gpu_profiler.results());
         self.frame_stats.tick();
 
         if self.swapchain.acquire_next_image(
@@ -201,17 +247,9 @@ impl Engine {
 
         self.record_command_buffer(binding, render_state, logic_state)?;
 
-        self.context.graphics_queue.submit(
+        self.in_flight_frames.submit(
+            &self.context.graphics_queue,
             &self.command_buffers[self.in_flight_frames.in_flight_index],
-            Some(SemaphoreSubmitInfo {
-                semaphore: self.in_flight_frames.image_available_semaphore(),
-                stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
-            }),
-            Some(SemaphoreSubmitInfo {
-                semaphore: self.in_flight_frames.render_finished_semaphore(),
-                stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
-            }),
-            self.in_flight_frames.fence(),
         )?;
 
         let present_result = self.swapchain.queue_present(
@@ -242,12 +280,9 @@ impl Engine {
         let buffer = &self.command_buffers[self.in_flight_frames.in_flight_index];
         buffer.reset()?;
         buffer.begin(None)?;
-        buffer.reset_all_timestamp_queries_from_pool(self.in_flight_frames.timing_query_pool());
-        buffer.write_timestamp(
-            vk::PipelineStageFlags2::NONE,
-            self.in_flight_frames.timing_query_pool(),
-            0,
-        );
+        self.context.gpu_profiler.begin_frame(buffer, self.in_flight_frames.in_flight_index)?;
+        buffer.begin_gpu_scope("frame");
+        self.staging_belt.flush(buffer, self.in_flight_frames.in_flight_index);
 
         {
             #[cfg(debug_assertions)]
@@ -262,6 +297,7 @@ impl Engine {
             #[cfg(debug_assertions)]
             puffin::profile_scope!("render ui");
 
+            buffer.begin_gpu_scope("ui");
             buffer.begin_rendering(
                 &self.get_current_swapchain_image_and_view().view,
                 &self.get_current_depth_image_and_view().view,
@@ -299,14 +335,11 @@ impl Engine {
             )?;
 
             buffer.end_rendering();
+            buffer.end_gpu_scope();
         }
 
         buffer.swapchain_image_present_barrier(&self.swapchain.images_and_views[self.in_flight_frames.frame_index].image)?;
-        buffer.write_timestamp(
-            vk::PipelineStageFlags2::ALL_COMMANDS,
-            self.in_flight_frames.timing_query_pool(),
-            1,
-        );
+        buffer.end_gpu_scope();
         buffer.end()?;
 
         Ok(())
@@ -1,24 +1,24 @@
-use std::time::Duration;
-
 use ash::vk;
 
-use crate::{vulkan::{Context, Fence, Semaphore, TimestampQueryPool}, OctaResult};
+use crate::{vulkan::{CommandBuffer, Context, Fence, Queue, Semaphore, SemaphoreSubmitInfo, SubmitBatch, TimelineSemaphore, TimelineSemaphoreSubmitInfo}, OctaResult};
 
 #[derive(Debug)]
 pub struct InFlightFrames {
     per_in_flight_frame: Vec<PerInFlightFrame>,
     per_frame: Vec<PerFrame>,
+    timeline: Option<TimelineSemaphore>,
     pub in_flight_index: usize,
     pub num_frames_in_flight: usize,
     pub frame_index: usize,
     pub num_frames: usize,
+    pub frame_number: u64,
 }
 
 #[derive(Debug)]
 struct PerInFlightFrame {
     image_available_semaphore: Semaphore,
     fence: Fence,
-    timing_query_pool: TimestampQueryPool<2>,
+    timeline_value: u64,
 }
 
 #[derive(Debug)]
@@ -27,17 +27,16 @@ struct PerFrame {
 }
 
 impl InFlightFrames {
-    pub(crate) fn new(context: &Context, frame_count: usize, in_flight_count: usize) -> OctaResult<Self> {
+    pub(crate) fn new(context: &Context, frame_count: usize, in_flight_count: usize, use_timeline: bool) -> OctaResult<Self> {
         let per_in_flight_frame = (0..in_flight_count)
             .map(|_i| {
                 let image_available_semaphore = context.create_semaphore()?;
                 let fence = context.create_fence(Some(vk::FenceCreateFlags::SIGNALED))?;
-                let timing_query_pool = context.create_timestamp_query_pool()?;
 
                 Ok(PerInFlightFrame {
                     image_available_semaphore,
                     fence,
-                    timing_query_pool,
+                    timeline_value: 0,
                 })
             })
             .collect::<OctaResult<Vec<_>>>()?;
@@ -52,18 +51,25 @@ impl InFlightFrames {
             })
             .collect::<OctaResult<Vec<_>>>()?;
 
+        let timeline = use_timeline
+            .then(|| context.create_timeline_semaphore(0))
+            .transpose()?;
+
         Ok(Self {
             per_in_flight_frame,
             per_frame,
+            timeline,
             in_flight_index: 0,
             num_frames_in_flight: in_flight_count,
             frame_index: 0,
             num_frames: frame_count,
+            frame_number: 0,
         })
     }
 
     pub(crate) fn next(&mut self) {
         self.in_flight_index = (self.in_flight_index + 1) % self.per_in_flight_frame.len();
+        self.frame_number += 1;
     }
 
     pub(crate) fn set_frame_index(&mut self, frame_index: usize) {
@@ -78,18 +84,55 @@ impl InFlightFrames {
         &self.per_frame[self.frame_index].render_finished_semaphore
     }
 
-    pub(crate) fn fence(&self) -> &Fence {
-        &self.per_in_flight_frame[self.in_flight_index].fence
+    /// Waits until the gpu finished the last submit of the current in flight frame.
+    pub(crate) fn wait_for_frame(&self) -> OctaResult<()> {
+        let frame = &self.per_in_flight_frame[self.in_flight_index];
+        match &self.timeline {
+            Some(timeline) => timeline.wait(frame.timeline_value, None)?,
+            None => {
+                frame.fence.wait(None)?;
+                frame.fence.reset()?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub(crate) fn timing_query_pool(&self) -> &TimestampQueryPool<2> {
-        &self.per_in_flight_frame[self.in_flight_index].timing_query_pool
+    pub(crate) fn submit(&mut self, queue: &Queue, command_buffer: &CommandBuffer) -> OctaResult<()> {
+        let frame_number = self.frame_number;
+        let frame = &self.per_in_flight_frame[self.in_flight_index];
+
+        let mut batch = SubmitBatch {
+            command_buffers: vec![command_buffer],
+            wait_semaphores: vec![SemaphoreSubmitInfo {
+                semaphore: &frame.image_available_semaphore,
+                stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            }],
+            signal_semaphores: vec![SemaphoreSubmitInfo {
+                semaphore: self.render_finished_semaphore(),
+                stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+            }],
+            ..Default::default()
+        };
+
+        match &self.timeline {
+            Some(timeline) => {
+                batch.signal_timeline_semaphores.push(TimelineSemaphoreSubmitInfo {
+                    semaphore: timeline,
+                    value: frame_number,
+                    stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+                });
+                queue.submit_batch(&[batch], None)?;
+            }
+            None => queue.submit_batch(&[batch], Some(&frame.fence))?,
+        }
+
+        self.per_in_flight_frame[self.in_flight_index].timeline_value = frame_number;
+
+        Ok(())
     }
 
-    pub(crate) fn gpu_frame_time_ms(&self) -> OctaResult<Duration> {
-        let result = self.timing_query_pool().wait_for_all_results()?;
-        let time = Duration::from_nanos(result[1].saturating_sub(result[0]));
-
-        Ok(time)
+    pub fn timeline(&self) -> Option<&TimelineSemaphore> {
+        self.timeline.as_ref()
     }
 }
@@ -1,6 +1,7 @@
 #![forbid(unused_must_use)]
 
 pub extern crate anyhow;
+pub extern crate bytemuck;
 pub extern crate glam;
 pub extern crate log;
 pub extern crate simplelog;
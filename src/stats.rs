@@ -1,8 +1,10 @@
 use std::time::Duration;
-use egui::Align2;
+use egui::{Align2, Color32, FontId, Rect, Sense};
 #[cfg(debug_assertions)]
 use puffin_egui::puffin;
 
+use crate::vulkan::GpuScope;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum StatsDisplayMode {
     None,
@@ -29,6 +31,7 @@ pub struct FrameStats {
     previous_compute_time: Duration,
     pub compute_time: Duration,
     pub gpu_time: Duration,
+    gpu_scopes: Vec<GpuScope>,
     frame_time_ms_log: Queue<f32>,
     compute_time_ms_log: Queue<f32>,
     gpu_time_ms_log: Queue<f32>,
@@ -53,6 +56,7 @@ impl FrameStats {
             previous_compute_time: Default::default(),
             compute_time: Default::default(),
             gpu_time: Default::default(),
+            gpu_scopes: vec![],
             frame_time_ms_log: Queue::new(FrameStats::MAX_LOG_SIZE),
             compute_time_ms_log: Queue::new(FrameStats::MAX_LOG_SIZE),
             gpu_time_ms_log: Queue::new(FrameStats::MAX_LOG_SIZE),
@@ -97,8 +101,18 @@ impl FrameStats {
         self.compute_time = compute_time;
     }
 
-    pub(crate) fn set_gpu_time(&mut self, gpu_time: Duration) {
-        self.gpu_time = gpu_time;
+    pub(crate) fn set_gpu_scopes(&mut self, gpu_scopes: Vec<GpuScope>) {
+        self.gpu_time = gpu_scopes
+            .iter()
+            .map(|scope| scope.start + scope.duration)
+            .max()
+            .unwrap_or_default();
+        self.gpu_scopes = gpu_scopes;
+    }
+
+    /// Scopes of the last frame with GPU timings, see CommandBuffer::begin_gpu_scope.
+    pub fn gpu_scopes(&self) -> &[GpuScope] {
+        &self.gpu_scopes
     }
 
     pub(crate) fn build_perf_ui(&mut self, ctx: &egui::Context) {
@@ -140,6 +154,10 @@ impl FrameStats {
                 ui.add_space(5.0);
             });
 
+            egui::Window::new("GPU profiler")
+                .default_width(400.0)
+                .show(ctx, |ui| build_gpu_scopes_ui(ui, &self.gpu_scopes));
+
             #[cfg(debug_assertions)]
             {
                 puffin_egui::profiler_window(ctx);
@@ -170,6 +188,61 @@ fn build_frametime_plot(ui: &mut egui::Ui, id: &str, points: &[f32]) {
         });
 }
 
+/// Flame graph of the scopes followed by a list with the durations.
+fn build_gpu_scopes_ui(ui: &mut egui::Ui, scopes: &[GpuScope]) {
+    const ROW_HEIGHT: f32 = 18.0;
+    const COLORS: [Color32; 4] = [
+        Color32::from_rgb(70, 110, 170),
+        Color32::from_rgb(80, 150, 110),
+        Color32::from_rgb(170, 120, 60),
+        Color32::from_rgb(150, 80, 140),
+    ];
+
+    let total = scopes
+        .iter()
+        .map(|scope| scope.start + scope.duration)
+        .max()
+        .unwrap_or_default();
+    if total.is_zero() {
+        ui.label("No GPU scopes recorded");
+        return;
+    }
+
+    let rows = scopes.iter().map(|scope| scope.depth + 1).max().unwrap_or(1);
+    let (response, painter) = ui.allocate_painter(
+        egui::vec2(ui.available_width(), rows as f32 * ROW_HEIGHT),
+        Sense::hover(),
+    );
+    let scale = response.rect.width() / total.as_secs_f32();
+
+    for (i, scope) in scopes.iter().enumerate() {
+        let min = response.rect.min + egui::vec2(
+            scope.start.as_secs_f32() * scale,
+            scope.depth as f32 * ROW_HEIGHT,
+        );
+        let size = egui::vec2((scope.duration.as_secs_f32() * scale).max(1.0), ROW_HEIGHT - 2.0);
+        let rect = Rect::from_min_size(min, size);
+
+        painter.rect_filled(rect, 2.0, COLORS[i % COLORS.len()]);
+        painter.with_clip_rect(rect.intersect(response.rect)).text(
+            rect.left_center() + egui::vec2(3.0, 0.0),
+            Align2::LEFT_CENTER,
+            &scope.name,
+            FontId::monospace(11.0),
+            Color32::WHITE,
+        );
+    }
+
+    ui.add_space(5.0);
+    egui::Grid::new("gpu_scopes").striped(true).show(ui, |ui| {
+        for scope in scopes {
+            ui.label(format!("{}{}", "  ".repeat(scope.depth as usize), scope.name));
+            ui.label(format!("{:.3} ms", scope.duration.as_secs_f64() * 1000.0));
+            ui.end_row();
+        }
+    });
+}
+
 #[derive(Debug)]
 struct Queue<T>(Vec<T>, usize);
 
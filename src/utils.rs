@@ -1,6 +1,7 @@
 use glam::UVec2;
 use winit::window::Fullscreen;
-use crate::{vulkan::{CommandBuffer, ImageAndView}, Engine};
+use ash::vk;
+use crate::{vulkan::{CommandBuffer, ImageAndView, RenderingInheritance}, Engine};
 
 impl Engine {
     pub fn set_fullscreen(&self, value: bool) {
@@ -29,6 +30,15 @@ impl Engine {
         &self.swapchain.depht_images_and_views[self.in_flight_frames.frame_index]
     }
 
+    /// For secondary command buffers that are executed inside the default swapchain rendering.
+    pub fn get_swapchain_rendering_inheritance(&self) -> RenderingInheritance {
+        RenderingInheritance {
+            color_formats: vec![self.swapchain.format],
+            depth_format: self.swapchain.depth_format,
+            samples: vk::SampleCountFlags::TYPE_1,
+        }
+    }
+
     pub fn get_resolution(&self) -> UVec2 {
         self.swapchain.size
     }
@@ -1,13 +1,15 @@
 use std::{
-    mem::align_of, sync::{Arc, Mutex}
+    mem::{align_of, size_of_val}, sync::{Arc, Mutex}
 };
-use std::slice::from_raw_parts_mut;
+use std::ffi::c_void;
+use std::ptr;
 
 
 use crate::vulkan::align::Align;
 use crate::vulkan::{Context, Device};
 use anyhow::Result;
 use ash::vk;
+use bytemuck::Pod;
 use gpu_allocator::vulkan::AllocationScheme;
 use gpu_allocator::{
     vulkan::{Allocation, AllocationCreateDesc, Allocator},
@@ -23,6 +25,17 @@ pub struct Buffer {
     pub size: vk::DeviceSize,
 }
 
+/// Typed view for uniform and storage texel buffer descriptors.
+#[derive(Debug)]
+pub struct BufferView {
+    device: Arc<Device>,
+    pub(crate) inner: vk::BufferView,
+    pub(crate) buffer: vk::Buffer,
+    pub format: vk::Format,
+    pub offset: vk::DeviceSize,
+    pub range: vk::DeviceSize,
+}
+
 impl Buffer {
     pub(crate) fn new(
         device: Arc<Device>,
@@ -57,109 +70,141 @@ impl Buffer {
         })
     }
 
-    pub fn copy_data_to_buffer<T: Copy>(&self, data: &[T]) {
+    pub fn copy_data_to_buffer<T: Copy>(&mut self, data: &[T]) {
         self.copy_data_to_buffer_complex(data, 0, align_of::<T>())
     }
 
     pub fn copy_data_to_buffer_complex<T: Copy>(
-        &self,
+        &mut self,
         data: &[T],
         offset: usize,
         alignment: usize,
     ) {
+        let alignment = self.check_aligned_range::<T>(data.len(), offset, alignment);
         unsafe {
-            let data_ptr = self
-                .allocation
-                .as_ref()
-                .unwrap()
-                .mapped_ptr()
-                .unwrap()
-                .as_ptr();
-
-            let mut align: Align<T> = Align::new(data_ptr, alignment as _, data.len(), offset);
+            let mut align: Align<T> = Align::new(self.mapped_ptr(), alignment as _, data.len(), offset);
             align.copy_from_slice(data);
         };
     }
 
     pub fn copy_data_to_buffer_without_aligment<T: Copy>(
-        &self,
+        &mut self,
         data: &[T],
         offset: usize,
     ) {
+        let size = size_of_val(data);
+        self.check_range(offset, size);
         unsafe {
-            let data_ptr = self
-                .allocation
-                .as_ref()
-                .unwrap()
-                .mapped_ptr()
-                .unwrap()
-                .as_ptr()
-                .cast::<u8>()
-                .offset(offset as isize);
-
-            let mapped_slice = from_raw_parts_mut(data_ptr.cast(), data.len());
-            mapped_slice.copy_from_slice(data);
+            // Byte wise copy, because offset does not have to be aligned to T
+            ptr::copy_nonoverlapping(
+                data.as_ptr().cast::<u8>(),
+                self.mapped_ptr().cast::<u8>().add(offset),
+                size,
+            );
         };
     }
 
-    pub fn get_mapped_slice<T>(&self) -> &mut [T] {
-        let mapped_slice;
-        unsafe {
-            let data_ptr = self
-                .allocation
-                .as_ref()
-                .unwrap()
-                .mapped_ptr()
-                .unwrap()
-                .as_ptr()
-                .cast::<T>();
-
-            mapped_slice = from_raw_parts_mut(data_ptr, self.size as usize / size_of::<T>());
-        }
-        mapped_slice
+    /// Panics if the buffer is not host visible or the mapped memory is not aligned to T.
+    pub fn get_mapped_slice<T: Pod>(&mut self) -> &mut [T] {
+        let bytes = self.get_mapped_bytes();
+        let len = bytes.len() - bytes.len() % size_of::<T>();
+        bytemuck::cast_slice_mut(&mut bytes[..len])
+    }
+
+    pub fn get_mapped_bytes(&mut self) -> &mut [u8] {
+        let size = self.size as usize;
+        let allocation = self.allocation.as_mut().unwrap();
+        &mut allocation
+            .mapped_slice_mut()
+            .expect("Buffer is not host visible")[..size]
+    }
+
+    pub fn is_host_visible(&self) -> bool {
+        self.allocation.as_ref().unwrap().mapped_ptr().is_some()
     }
 
     pub fn ptr(&self) -> *const u8 {
-        self
-            .allocation
-            .as_ref()
-            .unwrap()
-            .mapped_ptr()
-            .unwrap()
-            .as_ptr() as *const u8 
+        self.mapped_ptr() as *const u8
     }
 
-    pub fn get_data_from_buffer<T: Copy>(&self, count: usize) -> Vec<T> {
+    pub fn get_data_from_buffer<T: Pod>(&self, count: usize) -> Vec<T> {
         self.get_data_from_buffer_complex(count, 0, align_of::<T>())
     }
  
-    pub fn get_data_from_buffer_complex<T: Copy>(
+    pub fn get_data_from_buffer_complex<T: Pod>(
         &self,
         count: usize,
         offset: usize,
         alignment: usize,
     ) -> Vec<T> {
+        let alignment = self.check_aligned_range::<T>(count, offset, alignment);
         let data;
         unsafe {
-            let data_ptr = self
-                .allocation
-                .as_ref()
-                .unwrap()
-                .mapped_ptr()
-                .unwrap()
-                .as_ptr();
-
-            let mut align: Align<T> = Align::new(data_ptr, alignment as _, count, offset);
+            let mut align: Align<T> = Align::new(self.mapped_ptr(), alignment as _, count, offset);
             data = align.copy_to_slice(count)
         };
 
         data
     }
 
+    fn mapped_ptr(&self) -> *mut c_void {
+        self.allocation
+            .as_ref()
+            .unwrap()
+            .mapped_ptr()
+            .expect("Buffer is not host visible")
+            .as_ptr()
+    }
+
+    fn check_range(&self, offset: usize, size: usize) {
+        assert!(
+            offset + size <= self.size as usize,
+            "Access of {size} bytes at offset {offset} is out of bounds for buffer of size {}",
+            self.size
+        );
+    }
+
+    /// Alignment rounded up to a power of two that is at least the alignment of T,
+    /// the element stride of the aligned copies is size_of::<T>() rounded up to it.
+    pub fn aligned_element_size<T>(alignment: usize) -> (usize, usize) {
+        let alignment = alignment.max(align_of::<T>()).next_power_of_two();
+        (alignment, size_of::<T>().next_multiple_of(alignment))
+    }
+
+    /// Returns the normalized alignment of aligned_element_size.
+    fn check_aligned_range<T>(&self, count: usize, offset: usize, alignment: usize) -> usize {
+        let (alignment, elem_size) = Self::aligned_element_size::<T>(alignment);
+        if count != 0 {
+            self.check_range((offset + count - 1) * elem_size, size_of::<T>());
+        }
+
+        alignment
+    }
+
     pub fn get_device_address(&self) -> u64 {
         let addr_info = vk::BufferDeviceAddressInfo::default().buffer(self.inner);
         unsafe { self.device.inner.get_buffer_device_address(&addr_info) }
     }
+
+    /// The buffer needs UNIFORM_TEXEL_BUFFER or STORAGE_TEXEL_BUFFER usage.
+    pub fn create_buffer_view(&self, format: vk::Format, offset: vk::DeviceSize, range: vk::DeviceSize) -> Result<BufferView> {
+        let view_info = vk::BufferViewCreateInfo::default()
+            .buffer(self.inner)
+            .format(format)
+            .offset(offset)
+            .range(range);
+
+        let inner = unsafe { self.device.inner.create_buffer_view(&view_info, None)? };
+
+        Ok(BufferView {
+            device: self.device.clone(),
+            inner,
+            buffer: self.inner,
+            format,
+            offset,
+            range: if range == vk::WHOLE_SIZE { self.size - offset } else { range },
+        })
+    }
 }
 
 impl Context {
@@ -189,3 +234,9 @@ impl Drop for Buffer {
             .unwrap();
     }
 }
+
+impl Drop for BufferView {
+    fn drop(&mut self) {
+        unsafe { self.device.inner.destroy_buffer_view(self.inner, None) };
+    }
+}
@@ -2,12 +2,13 @@ use core::slice;
 use std::{mem, sync::Arc};
 use anyhow::Result;
 use ash::vk::{self, Extent2D, IndexType, Offset2D};
-use glam::{UVec2, Vec2};
+use glam::{IVec2, UVec2, Vec2};
 use crate::{
-    vulkan::device::Device, Buffer, ComputePipeline, Context, DescriptorSet, GraphicsPipeline,
+    vulkan::device::Device, Buffer, ComputePipeline, Context, DescriptorSet, GpuProfiler, GraphicsPipeline,
     Image, ImageView, PipelineLayout, QueueFamily, RayTracingContext, RayTracingPipeline,
-    ShaderBindingTable, TimestampQueryPool,
+    ShaderBindingTable, TimestampQueryPool, WriteDescriptorSet,
 };
+use crate::vulkan::descriptor::with_vk_writes;
 
 #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
 use ash::khr::{DynamicRendering, Synchronization2};
@@ -18,6 +19,8 @@ use super::utils::{uvec2_to_offset3d, uvec3_to_extend3d};
 pub struct CommandPool {
     device: Arc<Device>,
     ray_tracing: Option<Arc<RayTracingContext>>,
+    gpu_profiler: Arc<GpuProfiler>,
+    supports_timestamps: bool,
     pub inner: vk::CommandPool,
 
     #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -31,6 +34,7 @@ impl CommandPool {
     pub(crate) fn new(
         device: Arc<Device>,
         ray_tracing: Option<Arc<RayTracingContext>>,
+        gpu_profiler: Arc<GpuProfiler>,
         queue_family: QueueFamily,
         flags: Option<vk::CommandPoolCreateFlags>,
 
@@ -50,6 +54,8 @@ impl CommandPool {
         Ok(Self {
             device,
             ray_tracing,
+            gpu_profiler,
+            supports_timestamps: queue_family.supports_timestamp_queries(),
             inner,
 
             #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -76,6 +82,8 @@ impl CommandPool {
             .map(|inner| CommandBuffer {
                 device: self.device.clone(),
                 ray_tracing: self.ray_tracing.clone(),
+                gpu_profiler: self.gpu_profiler.clone(),
+                supports_timestamps: self.supports_timestamps,
                 inner,
 
                 #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -99,6 +107,17 @@ impl CommandPool {
         Ok(buffer)
     }
 
+    /// Resets all command buffers allocated from this pool. None of them can be in use by the device.
+    pub fn reset(&self) -> Result<()> {
+        unsafe {
+            self.device
+                .inner
+                .reset_command_pool(self.inner, vk::CommandPoolResetFlags::empty())?
+        };
+
+        Ok(())
+    }
+
     pub fn free_command_buffers(&self, buffer: &[CommandBuffer]) {
         let buffs = buffer.iter().map(|b| b.inner).collect::<Vec<_>>();
         unsafe { self.device.inner.free_command_buffers(self.inner, &buffs) };
@@ -122,6 +141,7 @@ impl Context {
         CommandPool::new(
             self.device.clone(),
             self.ray_tracing.clone(),
+            self.gpu_profiler.clone(),
             queue_family,
             flags,
 
@@ -142,8 +162,10 @@ impl Drop for CommandPool {
 
 #[derive(Debug)]
 pub struct CommandBuffer {
-    device: Arc<Device>,
-    ray_tracing: Option<Arc<RayTracingContext>>,
+    pub(crate) device: Arc<Device>,
+    pub(crate) ray_tracing: Option<Arc<RayTracingContext>>,
+    pub(crate) gpu_profiler: Arc<GpuProfiler>,
+    supports_timestamps: bool,
     pub inner: vk::CommandBuffer,
 
     #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -166,6 +188,32 @@ impl CommandBuffer {
         Ok(())
     }
 
+    /// Begins a secondary command buffer that is executed inside dynamic rendering of a primary command buffer.
+    pub fn begin_secondary(&self, inheritance: &RenderingInheritance) -> Result<()> {
+        let mut rendering_info = vk::CommandBufferInheritanceRenderingInfo::default()
+            .color_attachment_formats(&inheritance.color_formats)
+            .depth_attachment_format(inheritance.depth_format)
+            .rasterization_samples(inheritance.samples);
+        let inheritance_info = vk::CommandBufferInheritanceInfo::default()
+            .push_next(&mut rendering_info);
+
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT | vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
+            .inheritance_info(&inheritance_info);
+        unsafe {
+            self.device
+                .inner
+                .begin_command_buffer(self.inner, &begin_info)?
+        };
+
+        Ok(())
+    }
+
+    pub fn execute_commands(&self, command_buffers: &[&CommandBuffer]) {
+        let buffers = command_buffers.iter().map(|b| b.inner).collect::<Vec<_>>();
+        unsafe { self.device.inner.cmd_execute_commands(self.inner, &buffers) };
+    }
+
     pub fn end(&self) -> Result<()> {
         unsafe { self.device.inner.end_command_buffer(self.inner)? };
 
@@ -293,6 +341,17 @@ impl CommandBuffer {
         layout: &PipelineLayout,
         first_set: u32,
         sets: &[&DescriptorSet],
+    ) {
+        self.bind_descriptor_sets_complex(bind_point, layout, first_set, sets, &[]);
+    }
+
+    pub fn bind_descriptor_sets_complex(
+        &self,
+        bind_point: vk::PipelineBindPoint,
+        layout: &PipelineLayout,
+        first_set: u32,
+        sets: &[&DescriptorSet],
+        dynamic_offsets: &[u32],
     ) {
         let sets = sets.iter().map(|s| s.inner).collect::<Vec<_>>();
         unsafe {
@@ -302,11 +361,29 @@ impl CommandBuffer {
                 layout.inner,
                 first_set,
                 &sets,
-                &[],
+                dynamic_offsets,
             )
         }
     }
 
+    /// Writes the descriptors of set directly into the command buffer, without a DescriptorSet.
+    /// The set layout of the pipeline layout has to be created with PUSH_DESCRIPTOR_KHR.
+    pub fn push_descriptor_set(
+        &self,
+        bind_point: vk::PipelineBindPoint,
+        layout: &PipelineLayout,
+        set: u32,
+        writes: &[WriteDescriptorSet],
+    ) {
+        let push_descriptor_fn = self.device.push_descriptor_fn.as_ref().unwrap_or_else(|| {
+            panic!("Cannot call CommandBuffer::push_descriptor_set when push descriptors are not enabled")
+        });
+
+        with_vk_writes(writes, vk::DescriptorSet::null(), |vk_writes| unsafe {
+            push_descriptor_fn.cmd_push_descriptor_set(self.inner, bind_point, layout.inner, set, vk_writes)
+        });
+    }
+
     pub fn pipeline_buffer_barriers(&self, barriers: &[BufferBarrier]) {
         let barriers = barriers
             .iter()
@@ -390,14 +467,83 @@ impl CommandBuffer {
                     .subresource_range(vk::ImageSubresourceRange {
                         aspect_mask: vk::ImageAspectFlags::COLOR,
                         base_mip_level: 0,
-                        level_count: 1,
+                        level_count: b.image.mip_levels,
                         base_array_layer: 0,
                         layer_count: 1,
                     })
             })
             .collect::<Vec<_>>();
 
-        let dependency_info = vk::DependencyInfo::default().image_memory_barriers(&barriers);
+        self.raw_image_barriers(&barriers);
+    }
+
+    /// First half of a queue family ownership transfer, recorded on the src queue. Only the src masks of the barrier are used.
+    /// The same barrier has to be acquired on the dst queue afterward.
+    pub fn release_buffer_ownership(&self, barrier: &BufferBarrier, src_queue_family: QueueFamily, dst_queue_family: QueueFamily) {
+        self.raw_buffer_barriers(&[vk::BufferMemoryBarrier2::default()
+            .src_stage_mask(barrier.src_stage_mask)
+            .src_access_mask(barrier.src_access_mask)
+            .src_queue_family_index(src_queue_family.index)
+            .dst_queue_family_index(dst_queue_family.index)
+            .buffer(barrier.buffer.inner)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)]);
+    }
+
+    /// Second half of a queue family ownership transfer, recorded on the dst queue. Only the dst masks of the barrier are used.
+    pub fn acquire_buffer_ownership(&self, barrier: &BufferBarrier, src_queue_family: QueueFamily, dst_queue_family: QueueFamily) {
+        self.raw_buffer_barriers(&[vk::BufferMemoryBarrier2::default()
+            .dst_stage_mask(barrier.dst_stage_mask)
+            .dst_access_mask(barrier.dst_access_mask)
+            .src_queue_family_index(src_queue_family.index)
+            .dst_queue_family_index(dst_queue_family.index)
+            .buffer(barrier.buffer.inner)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)]);
+    }
+
+    /// Like release_buffer_ownership. The layout transition is part of both halves.
+    pub fn release_image_ownership(&self, barrier: &ImageBarrier, src_queue_family: QueueFamily, dst_queue_family: QueueFamily) {
+        self.raw_image_barriers(&[vk::ImageMemoryBarrier2::default()
+            .src_stage_mask(barrier.src_stage_mask)
+            .src_access_mask(barrier.src_access_mask)
+            .old_layout(barrier.old_layout)
+            .new_layout(barrier.new_layout)
+            .src_queue_family_index(src_queue_family.index)
+            .dst_queue_family_index(dst_queue_family.index)
+            .image(barrier.image.inner)
+            .subresource_range(full_color_subresource_range(barrier.image))]);
+    }
+
+    pub fn acquire_image_ownership(&self, barrier: &ImageBarrier, src_queue_family: QueueFamily, dst_queue_family: QueueFamily) {
+        self.raw_image_barriers(&[vk::ImageMemoryBarrier2::default()
+            .dst_stage_mask(barrier.dst_stage_mask)
+            .dst_access_mask(barrier.dst_access_mask)
+            .old_layout(barrier.old_layout)
+            .new_layout(barrier.new_layout)
+            .src_queue_family_index(src_queue_family.index)
+            .dst_queue_family_index(dst_queue_family.index)
+            .image(barrier.image.inner)
+            .subresource_range(full_color_subresource_range(barrier.image))]);
+    }
+
+    pub(crate) fn raw_buffer_barriers(&self, barriers: &[vk::BufferMemoryBarrier2]) {
+        let dependency_info = vk::DependencyInfo::default().buffer_memory_barriers(barriers);
+
+        unsafe {
+            #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
+            self.synchronization2
+                .cmd_pipeline_barrier2(self.inner, &dependency_info);
+
+            #[cfg(vulkan_1_3)]
+            self.device
+                .inner
+                .cmd_pipeline_barrier2(self.inner, &dependency_info)
+        };
+    }
+
+    pub(crate) fn raw_image_barriers(&self, barriers: &[vk::ImageMemoryBarrier2]) {
+        let dependency_info = vk::DependencyInfo::default().image_memory_barriers(barriers);
 
         unsafe {
             #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -468,6 +614,101 @@ impl CommandBuffer {
         };
     }
 
+    pub fn copy_buffer_to_image_regions(&self, src: &Buffer, dst: &Image, layout: vk::ImageLayout, regions: &[vk::BufferImageCopy]) {
+        unsafe {
+            self.device.inner.cmd_copy_buffer_to_image(
+                self.inner,
+                src.inner,
+                dst.inner,
+                layout,
+                regions,
+            );
+        };
+    }
+
+    /// Fills mip levels 1.. of the image by blitting down from level 0.
+    /// All levels have to be in TRANSFER_DST_OPTIMAL and end up in final_layout.
+    pub fn generate_mipmaps(&self, image: &Image, final_layout: vk::ImageLayout, dst_stage_mask: vk::PipelineStageFlags2) {
+        let level_barrier = |level: u32, old_layout, new_layout, src_access_mask, dst_access_mask, dst_stage_mask| {
+            vk::ImageMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                .src_access_mask(src_access_mask)
+                .old_layout(old_layout)
+                .dst_stage_mask(dst_stage_mask)
+                .dst_access_mask(dst_access_mask)
+                .new_layout(new_layout)
+                .image(image.inner)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: level,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+        };
+
+        let mut size = image.size.truncate().as_ivec2();
+        for level in 1..image.mip_levels {
+            self.raw_image_barriers(&[level_barrier(
+                level - 1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags2::TRANSFER_WRITE,
+                vk::AccessFlags2::TRANSFER_READ,
+                vk::PipelineStageFlags2::TRANSFER,
+            )]);
+
+            let next_size = (size / 2).max(IVec2::ONE);
+            let blit = vk::ImageBlit::default()
+                .src_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level - 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .src_offsets([vk::Offset3D::default(), vk::Offset3D { x: size.x, y: size.y, z: 1 }])
+                .dst_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .dst_offsets([vk::Offset3D::default(), vk::Offset3D { x: next_size.x, y: next_size.y, z: 1 }]);
+
+            unsafe {
+                self.device.inner.cmd_blit_image(
+                    self.inner,
+                    image.inner,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image.inner,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    std::slice::from_ref(&blit),
+                    vk::Filter::LINEAR,
+                )
+            };
+
+            self.raw_image_barriers(&[level_barrier(
+                level - 1,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                final_layout,
+                vk::AccessFlags2::TRANSFER_READ,
+                vk::AccessFlags2::SHADER_READ,
+                dst_stage_mask,
+            )]);
+
+            size = next_size;
+        }
+
+        self.raw_image_barriers(&[level_barrier(
+            image.mip_levels - 1,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            final_layout,
+            vk::AccessFlags2::TRANSFER_WRITE,
+            vk::AccessFlags2::SHADER_READ,
+            dst_stage_mask,
+        )]);
+    }
+
     pub fn build_acceleration_structures(
         &self,
         as_build_geo_info: &vk::AccelerationStructureBuildGeometryInfoKHR,
@@ -489,21 +730,33 @@ impl CommandBuffer {
     }
 
     pub fn trace_rays(&self, shader_binding_table: &ShaderBindingTable, width: u32, height: u32) {
+        self.trace_rays_complex(shader_binding_table, 0, width, height, 1);
+    }
+
+    /// raygen_index selects the n-th raygen group of the pipeline, panics if it is out of range.
+    pub fn trace_rays_complex(
+        &self,
+        shader_binding_table: &ShaderBindingTable,
+        raygen_index: u32,
+        width: u32,
+        height: u32,
+        depth: u32,
+    ) {
         let ray_tracing = self
             .ray_tracing
             .as_ref()
             .expect("Cannot call CommandBuffer::trace_rays when ray tracing is not enabled");
 
         unsafe {
-            ray_tracing.pipeline_fn.cmd_trace_rays(
+            ray_tracing.pipeline("CommandBuffer::trace_rays").pipeline_fn.cmd_trace_rays(
                 self.inner,
-                &shader_binding_table.raygen_region,
+                &shader_binding_table.raygen_region(raygen_index),
                 &shader_binding_table.miss_region,
                 &shader_binding_table.hit_region,
-                &vk::StridedDeviceAddressRegionKHR::default(),
+                &shader_binding_table.callable_region,
                 width,
                 height,
-                1,
+                depth,
             )
         };
     }
@@ -515,6 +768,19 @@ impl CommandBuffer {
         size: UVec2,
         load_op: vk::AttachmentLoadOp,
         clear_color: Option<[f32; 4]>,
+    ) {
+        self.begin_rendering_complex(image_view, depth_view, size, load_op, clear_color, vk::RenderingFlags::empty());
+    }
+
+    /// Use RenderingFlags::CONTENTS_SECONDARY_COMMAND_BUFFERS when the rendering is recorded in secondary command buffers.
+    pub fn begin_rendering_complex(
+        &self,
+        image_view: &ImageView,
+        depth_view: &ImageView,
+        size: UVec2,
+        load_op: vk::AttachmentLoadOp,
+        clear_color: Option<[f32; 4]>,
+        flags: vk::RenderingFlags,
     ) {
         let color_attachment_info = vk::RenderingAttachmentInfo::default()
             .image_view(image_view.inner)
@@ -533,6 +799,7 @@ impl CommandBuffer {
                 extent: Extent2D{ width: size.x, height: size.y },
             })
             .layer_count(1)
+            .flags(flags)
             .color_attachments(std::slice::from_ref(&color_attachment_info));
 
         let depth_attachment_info = vk::RenderingAttachmentInfo::default()
@@ -561,6 +828,74 @@ impl CommandBuffer {
         };
     }
 
+    /// Dynamic rendering into multiple color attachments, for example a G-buffer.
+    pub fn begin_rendering_attachments(&self, attachments: &RenderingAttachments) {
+        let color_attachment_infos = attachments.color_views.iter()
+            .map(|view| {
+                vk::RenderingAttachmentInfo::default()
+                    .image_view(view.inner)
+                    .image_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
+                    .load_op(attachments.load_op)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .clear_value(vk::ClearValue {
+                        color: vk::ClearColorValue {
+                            float32: attachments.clear_color,
+                        },
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let clear_value = vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: attachments.depth_clear_value,
+                stencil: attachments.stencil_clear_value,
+            },
+        };
+        let depth_attachment_info = attachments.depth_view.map(|view| {
+            vk::RenderingAttachmentInfo::default()
+                .image_view(view.inner)
+                .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .load_op(attachments.depth_load_op)
+                .store_op(attachments.depth_store_op)
+                .clear_value(clear_value)
+        });
+        let stencil_attachment_info = attachments.stencil_view.map(|view| {
+            vk::RenderingAttachmentInfo::default()
+                .image_view(view.inner)
+                .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .load_op(attachments.stencil_load_op)
+                .store_op(attachments.stencil_store_op)
+                .clear_value(clear_value)
+        });
+
+        let mut rendering_info = vk::RenderingInfo::default()
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: Extent2D{ width: attachments.size.x, height: attachments.size.y },
+            })
+            .layer_count(1)
+            .flags(attachments.flags)
+            .color_attachments(&color_attachment_infos);
+
+        if let Some(depth_attachment_info) = depth_attachment_info.as_ref() {
+            rendering_info = rendering_info.depth_attachment(depth_attachment_info);
+        }
+        if let Some(stencil_attachment_info) = stencil_attachment_info.as_ref() {
+            rendering_info = rendering_info.stencil_attachment(stencil_attachment_info);
+        }
+
+        unsafe {
+            #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
+            self.dynamic_rendering
+                .cmd_begin_rendering(self.inner, &rendering_info);
+
+            #[cfg(vulkan_1_3)]
+            self.device
+                .inner
+                .cmd_begin_rendering(self.inner, &rendering_info)
+        };
+    }
+
     pub fn end_rendering(&self) {
         unsafe {
             #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -628,20 +963,93 @@ impl CommandBuffer {
     ) {
         assert!(query_index < C as u32, "Query index must be < {C}");
 
+        self.write_timestamp_to_pool(stage, pool.inner, query_index);
+    }
+
+    pub(crate) fn write_timestamp_to_pool(
+        &self,
+        stage: vk::PipelineStageFlags2,
+        pool: vk::QueryPool,
+        query_index: u32,
+    ) {
         unsafe {
             #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
             self.synchronization2
-                .cmd_write_timestamp2(self.inner, stage, pool.inner, query_index);
+                .cmd_write_timestamp2(self.inner, stage, pool, query_index);
 
 
             #[cfg(vulkan_1_3)]
             self.device
                 .inner
-                .cmd_write_timestamp2(self.inner, stage, pool.inner, query_index);
+                .cmd_write_timestamp2(self.inner, stage, pool, query_index);
+        }
+    }
+
+    /// Starts a named timing scope, shown in the GPU profiler of the stats ui.
+    /// Scopes can be nested and have to be ended in the same command buffer.
+    /// Only recorded in the frame command buffer of the engine and secondary command buffers of ThreadCommandPools,
+    /// does nothing in other command buffers and on queue families without timestamps.
+    pub fn begin_gpu_scope(&self, name: &str) {
+        if self.supports_timestamps {
+            self.gpu_profiler.begin_scope(self, name);
+        }
+    }
+
+    pub fn end_gpu_scope(&self) {
+        if self.supports_timestamps {
+            self.gpu_profiler.end_scope(self);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderingAttachments<'a> {
+    pub color_views: &'a [&'a ImageView],
+    pub depth_view: Option<&'a ImageView>,
+    /// Usually the depth view, when its format has a stencil aspect.
+    pub stencil_view: Option<&'a ImageView>,
+    pub size: UVec2,
+    pub load_op: vk::AttachmentLoadOp,
+    pub clear_color: [f32; 4],
+    pub depth_load_op: vk::AttachmentLoadOp,
+    /// STORE to keep the depth for a later pass.
+    pub depth_store_op: vk::AttachmentStoreOp,
+    /// 0.0 for reverse-Z
+    pub depth_clear_value: f32,
+    pub stencil_load_op: vk::AttachmentLoadOp,
+    pub stencil_store_op: vk::AttachmentStoreOp,
+    pub stencil_clear_value: u32,
+    pub flags: vk::RenderingFlags,
+}
+
+impl Default for RenderingAttachments<'_> {
+    fn default() -> Self {
+        Self {
+            color_views: &[],
+            depth_view: None,
+            stencil_view: None,
+            size: UVec2::ZERO,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            clear_color: [0.0; 4],
+            depth_load_op: vk::AttachmentLoadOp::CLEAR,
+            depth_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            depth_clear_value: 1.0,
+            stencil_load_op: vk::AttachmentLoadOp::CLEAR,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            stencil_clear_value: 0,
+            flags: vk::RenderingFlags::empty(),
         }
     }
 }
 
+/// Attachment formats of the dynamic rendering a secondary command buffer is executed in.
+#[derive(Debug, Clone)]
+pub struct RenderingInheritance {
+    pub color_formats: Vec<vk::Format>,
+    pub depth_format: vk::Format,
+    pub samples: vk::SampleCountFlags,
+}
+
 #[derive(Clone, Copy)]
 pub struct BufferBarrier<'a> {
     pub buffer: &'a Buffer,
@@ -668,3 +1076,13 @@ pub struct ImageBarrier<'a> {
     pub src_stage_mask: vk::PipelineStageFlags2,
     pub dst_stage_mask: vk::PipelineStageFlags2,
 }
+
+fn full_color_subresource_range(image: &Image) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: image.mip_levels,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt, sync::{Arc, Mutex}};
+use std::{collections::HashSet, fmt, sync::{Arc, Mutex}};
 use anyhow::{Context as _, Result};
 use ash::{vk};
 use gpu_allocator::{
@@ -6,7 +6,7 @@ use gpu_allocator::{
     AllocatorDebugSettings,
 };
 use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
-use crate::{CommandBuffer, CommandPool, EngineConfig, RayTracingContext, engine::EngineFeatureValue, vulkan::{Fence, device::Device, instance::Instance, queue::Queue, surface::Surface}};
+use crate::{CommandBuffer, CommandPool, DeletionQueue, DescriptorBufferProperties, EngineConfig, GpuProfiler, PipelineCache, RayTracingContext, engine::EngineFeatureValue, vulkan::{Fence, device::Device, instance::Instance, queue::Queue, surface::Surface}};
 
 #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
 use ash::extensions::khr::{DynamicRendering, Synchronization2};
@@ -19,15 +19,27 @@ pub const DEBUG_GPU_ALLOCATOR: bool = false;
 pub struct Context {
     pub allocator: Arc<Mutex<Allocator>>,
     pub command_pool: CommandPool,
+    pub compute_command_pool: Option<CommandPool>,
+    pub transfer_command_pool: Option<CommandPool>,
+    pub pipeline_cache: PipelineCache,
+    pub deletion_queue: DeletionQueue,
     pub ray_tracing: Option<Arc<RayTracingContext>>,
+    pub gpu_profiler: Arc<GpuProfiler>,
     pub graphics_queue: Queue,
     pub present_queue: Queue,
+    pub compute_queue: Option<Queue>,
+    pub transfer_queue: Option<Queue>,
     pub device: Arc<Device>,
     pub physical_device: PhysicalDevice,
     pub surface: Surface,
     pub instance: Instance,
     pub debug_printing: bool,
     pub shader_clock: bool,
+    pub scalar_block_layout: bool,
+    pub timeline_semaphores: bool,
+    pub mesh_shading: bool,
+    /// Some when VK_EXT_descriptor_buffer is enabled.
+    pub descriptor_buffer_properties: Option<DescriptorBufferProperties>,
     pub entry: Entry,
 
     #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -122,13 +134,93 @@ impl Context {
             ]);
         };
 
+        if engine_config.timeline_frame_sync == EngineFeatureValue::Wanted {
+            if cfg!(any(vulkan_1_0, vulkan_1_1)) {
+                wanted_extensions.push("VK_KHR_timeline_semaphore".to_owned());
+            }
+            wanted_device_features.push("timelineSemaphore".to_owned());
+        } else if engine_config.timeline_frame_sync == EngineFeatureValue::Needed {
+            if cfg!(any(vulkan_1_0, vulkan_1_1)) {
+                required_extensions.push("VK_KHR_timeline_semaphore".to_owned());
+            }
+            required_device_features.push("timelineSemaphore".to_owned());
+        }
+
+        // Update after bind features of the descriptor heap, create_descriptor_heap_complex checks the ones it needs.
+        wanted_device_features.append(&mut vec![
+            "descriptorBindingPartiallyBound".to_owned(),
+            "descriptorBindingUpdateUnusedWhilePending".to_owned(),
+            "descriptorBindingSampledImageUpdateAfterBind".to_owned(),
+            "descriptorBindingStorageImageUpdateAfterBind".to_owned(),
+            "descriptorBindingStorageBufferUpdateAfterBind".to_owned(),
+            "descriptorBindingUniformBufferUpdateAfterBind".to_owned(),
+        ]);
+        if engine_config.ray_tracing != EngineFeatureValue::NotUsed || engine_config.ray_query != EngineFeatureValue::NotUsed {
+            wanted_device_features.push("descriptorBindingAccelerationStructureUpdateAfterBind".to_owned());
+        }
+
+        let indirect_drawing_features = vec![
+            "multiDrawIndirect".to_owned(),
+            "drawIndirectCount".to_owned(),
+            "drawIndirectFirstInstance".to_owned(),
+        ];
+        if engine_config.indirect_drawing == EngineFeatureValue::Wanted {
+            if cfg!(any(vulkan_1_0, vulkan_1_1)) {
+                wanted_extensions.push("VK_KHR_draw_indirect_count".to_owned());
+            }
+            wanted_device_features.extend(indirect_drawing_features);
+        } else if engine_config.indirect_drawing == EngineFeatureValue::Needed {
+            if cfg!(any(vulkan_1_0, vulkan_1_1)) {
+                required_extensions.push("VK_KHR_draw_indirect_count".to_owned());
+            }
+            required_device_features.extend(indirect_drawing_features);
+        }
+
+        if engine_config.mesh_shading == EngineFeatureValue::Wanted {
+            wanted_extensions.push("VK_EXT_mesh_shader".to_owned());
+
+            wanted_device_features.append(&mut vec![
+                "meshShader".to_owned(),
+                "taskShader".to_owned(),
+            ]);
+        } else if engine_config.mesh_shading == EngineFeatureValue::Needed {
+            required_extensions.push("VK_EXT_mesh_shader".to_owned());
+
+            required_device_features.append(&mut vec![
+                "meshShader".to_owned(),
+                "taskShader".to_owned(),
+            ]);
+        }
+
+        if engine_config.descriptor_buffer == EngineFeatureValue::Wanted {
+            wanted_extensions.push("VK_EXT_descriptor_buffer".to_owned());
+
+            wanted_device_features.append(&mut vec![
+                "descriptorBuffer".to_owned(),
+                "bufferDeviceAddress".to_owned(),
+            ]);
+        } else if engine_config.descriptor_buffer == EngineFeatureValue::Needed {
+            required_extensions.push("VK_EXT_descriptor_buffer".to_owned());
+
+            required_device_features.append(&mut vec![
+                "descriptorBuffer".to_owned(),
+                "bufferDeviceAddress".to_owned(),
+            ]);
+        }
+
+        if engine_config.push_descriptors == EngineFeatureValue::Wanted {
+            wanted_extensions.push("VK_KHR_push_descriptor".to_owned());
+        } else if engine_config.push_descriptors == EngineFeatureValue::Needed {
+            required_extensions.push("VK_KHR_push_descriptor".to_owned());
+        }
+
         if engine_config.gl_ext_scalar_block_layout == EngineFeatureValue::Wanted {
             wanted_extensions.push("VK_EXT_scalar_block_layout".to_owned());
         } else if engine_config.gl_ext_scalar_block_layout == EngineFeatureValue::Needed {
             required_extensions.push("VK_EXT_scalar_block_layout".to_owned());
         };
 
-        if engine_config.ray_tracing == EngineFeatureValue::Wanted {
+        if engine_config.ray_tracing == EngineFeatureValue::Needed {
             required_extensions.append(&mut vec![
                 "VK_KHR_ray_tracing_pipeline".to_owned(),
                 "VK_KHR_acceleration_structure".to_owned(),
@@ -141,7 +233,7 @@ impl Context {
                 "runtimeDescriptorArray".to_owned(),
                 "bufferDeviceAddress".to_owned(),
             ]);
-        } else if engine_config.ray_tracing == EngineFeatureValue::Needed {
+        } else if engine_config.ray_tracing == EngineFeatureValue::Wanted {
             wanted_extensions.append(&mut vec![
                 "VK_KHR_ray_tracing_pipeline".to_owned(),
                 "VK_KHR_acceleration_structure".to_owned(),
@@ -156,6 +248,38 @@ impl Context {
             ]);
         }
 
+        if engine_config.ray_query == EngineFeatureValue::Needed {
+            required_extensions.append(&mut vec![
+                "VK_KHR_ray_query".to_owned(),
+                "VK_KHR_acceleration_structure".to_owned(),
+                "VK_KHR_deferred_host_operations".to_owned(),
+            ]);
+
+            required_device_features.append(&mut vec![
+                "rayQuery".to_owned(),
+                "accelerationStructure".to_owned(),
+                "bufferDeviceAddress".to_owned(),
+            ]);
+        } else if engine_config.ray_query == EngineFeatureValue::Wanted {
+            wanted_extensions.append(&mut vec![
+                "VK_KHR_ray_query".to_owned(),
+                "VK_KHR_acceleration_structure".to_owned(),
+                "VK_KHR_deferred_host_operations".to_owned(),
+            ]);
+
+            wanted_device_features.append(&mut vec![
+                "rayQuery".to_owned(),
+                "accelerationStructure".to_owned(),
+                "bufferDeviceAddress".to_owned(),
+            ]);
+        }
+
+        // Ray tracing and ray query share extensions and features
+        dedup_names(&mut required_extensions);
+        dedup_names(&mut wanted_extensions);
+        dedup_names(&mut required_device_features);
+        dedup_names(&mut wanted_device_features);
+
         instance.load_possible_physical_devices_capabilities(
             &surface,
             &required_extensions,
@@ -174,8 +298,30 @@ impl Context {
         let debug_printing = instance.debug_printing && *physical_device.wanted_extensions.get("VK_KHR_shader_non_semantic_info").unwrap_or(&false);
         let shader_clock = *physical_device.wanted_device_features.get("deviceClock").unwrap_or(&false)
             && *physical_device.wanted_extensions.get("VK_KHR_shader_clock").unwrap_or(&false);
+        let scalar_block_layout = *physical_device.wanted_extensions.get("VK_EXT_scalar_block_layout").unwrap_or(&false)
+            || required_extensions.contains(&"VK_EXT_scalar_block_layout".to_owned());
+        let mesh_shading = *physical_device.wanted_device_features.get("meshShader").unwrap_or(&false)
+            && *physical_device.wanted_extensions.get("VK_EXT_mesh_shader").unwrap_or(&false)
+            || required_extensions.contains(&"VK_EXT_mesh_shader".to_owned());
+        let descriptor_buffer = *physical_device.wanted_device_features.get("descriptorBuffer").unwrap_or(&false)
+            && *physical_device.wanted_extensions.get("VK_EXT_descriptor_buffer").unwrap_or(&false)
+            || required_extensions.contains(&"VK_EXT_descriptor_buffer".to_owned());
+        let acceleration_structures = *physical_device.wanted_device_features.get("accelerationStructure").unwrap_or(&false)
+            && *physical_device.wanted_extensions.get("VK_KHR_acceleration_structure").unwrap_or(&false)
+            || required_extensions.contains(&"VK_KHR_acceleration_structure".to_owned());
+        let ray_tracing_pipeline = *physical_device.wanted_device_features.get("rayTracingPipeline").unwrap_or(&false)
+            && *physical_device.wanted_extensions.get("VK_KHR_ray_tracing_pipeline").unwrap_or(&false)
+            || required_extensions.contains(&"VK_KHR_ray_tracing_pipeline".to_owned());
+        let ray_query = *physical_device.wanted_device_features.get("rayQuery").unwrap_or(&false)
+            && *physical_device.wanted_extensions.get("VK_KHR_ray_query").unwrap_or(&false)
+            || required_extensions.contains(&"VK_KHR_ray_query".to_owned());
+        let timeline_semaphores = (*physical_device.wanted_device_features.get("timelineSemaphore").unwrap_or(&false)
+            || required_device_features.contains(&"timelineSemaphore".to_owned()))
+            && (cfg!(any(vulkan_1_2, vulkan_1_3))
+                || *physical_device.wanted_extensions.get("VK_KHR_timeline_semaphore").unwrap_or(&false)
+                || required_extensions.contains(&"VK_KHR_timeline_semaphore".to_owned()));
         
-        let possible_extensions = physical_device.wanted_extensions.iter().filter_map(|(name, b)| {
+        let mut possible_extensions = physical_device.wanted_extensions.iter().filter_map(|(name, b)| {
                 if *b {
                     Some(name.to_owned())
                 } else {
@@ -185,7 +331,7 @@ impl Context {
             .chain(required_extensions.into_iter())
             .collect();
 
-        let possible_device_features = physical_device.wanted_device_features.iter().filter_map(|(name, b)| {
+        let mut possible_device_features = physical_device.wanted_device_features.iter().filter_map(|(name, b)| {
                 if *b {
                     Some(name.to_owned())
                 } else {
@@ -194,6 +340,8 @@ impl Context {
             })
             .chain(required_device_features.into_iter())
             .collect();
+        dedup_names(&mut possible_extensions);
+        dedup_names(&mut possible_device_features);
         
         let device = Arc::new(Device::new(
             &instance,
@@ -224,13 +372,35 @@ impl Context {
             synchronization2.to_owned()
         );
 
-        let ray_tracing = possible_extensions.contains(&"VK_KHR_ray_tracing_pipeline".to_owned()).then(|| {
-            let ray_tracing =
-                Arc::new(RayTracingContext::new(&instance, &physical_device, &device));
-            log::debug!(
-                "Ray tracing pipeline properties {:#?}",
-                ray_tracing.pipeline_properties
-            );
+        let compute_queue = physical_device.compute_queue_family.map(|family| device.get_queue(
+            family,
+            0,
+
+            #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
+            synchronization2.to_owned()
+        ));
+        let transfer_queue = physical_device.transfer_queue_family.map(|family| device.get_queue(
+            family,
+            0,
+
+            #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
+            synchronization2.to_owned()
+        ));
+
+        let ray_tracing = acceleration_structures.then(|| {
+            let ray_tracing = Arc::new(RayTracingContext::new(
+                &instance,
+                &physical_device,
+                &device,
+                ray_tracing_pipeline,
+                ray_query,
+            ));
+            if let Some(pipeline) = &ray_tracing.pipeline {
+                log::debug!(
+                    "Ray tracing pipeline properties {:#?}",
+                    pipeline.properties
+                );
+            }
             log::debug!(
                 "Acceleration structure properties {:#?}",
                 ray_tracing.acceleration_structure_properties
@@ -238,9 +408,22 @@ impl Context {
             ray_tracing
         });
 
+        let descriptor_buffer_properties = descriptor_buffer.then(|| {
+            let properties = DescriptorBufferProperties::new(&instance, &physical_device);
+            log::debug!("Descriptor buffer properties {:#?}", properties);
+            properties
+        });
+
+        let gpu_profiler = Arc::new(GpuProfiler::new(
+            device.clone(),
+            physical_device.limits.timestamp_period as _,
+            physical_device.graphics_queue_family.inner.timestamp_valid_bits,
+        ));
+
         let command_pool = CommandPool::new(
             device.clone(),
             ray_tracing.clone(),
+            gpu_profiler.clone(),
             physical_device.graphics_queue_family,
             Some(vk::CommandPoolCreateFlags::TRANSIENT),
 
@@ -251,6 +434,28 @@ impl Context {
             dynamic_rendering.to_owned(),
         )?;
 
+        let create_async_command_pool = |family| CommandPool::new(
+            device.clone(),
+            ray_tracing.clone(),
+            gpu_profiler.clone(),
+            family,
+            Some(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
+
+            #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
+            synchronization2.to_owned(),
+
+            #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
+            dynamic_rendering.to_owned(),
+        );
+        let compute_command_pool = physical_device.compute_queue_family.map(create_async_command_pool).transpose()?;
+        let transfer_command_pool = physical_device.transfer_queue_family.map(create_async_command_pool).transpose()?;
+
+        let pipeline_cache = PipelineCache::new(
+            device.clone(),
+            &physical_device,
+            engine_config.pipeline_cache_path.to_owned(),
+        )?;
+
         // Gpu allocator
         let allocator = Allocator::new(&AllocatorCreateDesc {
             instance: instance.inner.clone(),
@@ -271,15 +476,26 @@ impl Context {
         Ok(Self {
             allocator: Arc::new(Mutex::new(allocator)),
             command_pool,
+            compute_command_pool,
+            transfer_command_pool,
+            pipeline_cache,
+            deletion_queue: DeletionQueue::default(),
             ray_tracing,
+            gpu_profiler,
             present_queue,
             graphics_queue,
+            compute_queue,
+            transfer_queue,
             device,
             physical_device,
             surface,
             instance,
             debug_printing,
             shader_clock,
+            scalar_block_layout,
+            timeline_semaphores,
+            mesh_shading,
+            descriptor_buffer_properties,
             entry,
 
             #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -294,6 +510,7 @@ impl Context {
 impl Context {
     pub fn device_wait_idle(&self) -> Result<()> {
         unsafe { self.device.inner.device_wait_idle()? };
+        self.deletion_queue.retire_all();
 
         Ok(())
     }
@@ -302,8 +519,38 @@ impl Context {
         &self,
         executor: F,
     ) -> Result<R> {
-        let command_buffer = self
-            .command_pool
+        self.execute_one_time_commands_complex(&self.graphics_queue, &self.command_pool, executor)
+    }
+
+    /// Runs on the async compute queue if there is one, otherwise on the graphics queue.
+    pub fn execute_one_time_compute_commands<R, F: FnOnce(&CommandBuffer) -> R>(
+        &self,
+        executor: F,
+    ) -> Result<R> {
+        match (&self.compute_queue, &self.compute_command_pool) {
+            (Some(queue), Some(pool)) => self.execute_one_time_commands_complex(queue, pool, executor),
+            _ => self.execute_one_time_commands(executor),
+        }
+    }
+
+    /// Runs on the async transfer queue if there is one, otherwise on the graphics queue.
+    pub fn execute_one_time_transfer_commands<R, F: FnOnce(&CommandBuffer) -> R>(
+        &self,
+        executor: F,
+    ) -> Result<R> {
+        match (&self.transfer_queue, &self.transfer_command_pool) {
+            (Some(queue), Some(pool)) => self.execute_one_time_commands_complex(queue, pool, executor),
+            _ => self.execute_one_time_commands(executor),
+        }
+    }
+
+    pub fn execute_one_time_commands_complex<R, F: FnOnce(&CommandBuffer) -> R>(
+        &self,
+        queue: &Queue,
+        command_pool: &CommandPool,
+        executor: F,
+    ) -> Result<R> {
+        let command_buffer = command_pool
             .allocate_command_buffer(vk::CommandBufferLevel::PRIMARY, )?;
 
         // Begin recording
@@ -317,12 +564,11 @@ impl Context {
 
         // Submit and wait
         let fence = self.create_fence(None)?;
-        self.graphics_queue
-            .submit(&command_buffer, None, None, &fence)?;
+        queue.submit(&command_buffer, None, None, &fence)?;
         fence.wait(None)?;
 
         // Free
-        self.command_pool.free_command_buffer(&command_buffer)?;
+        command_pool.free_command_buffer(&command_buffer)?;
 
         Ok(executor_result)
     }
@@ -333,15 +579,25 @@ impl fmt::Debug for Context {
         f.debug_struct("Context")
             .field("allocator", &self.allocator)
             .field("command_pool", &self.command_pool)
+            .field("compute_command_pool", &self.compute_command_pool)
+            .field("transfer_command_pool", &self.transfer_command_pool)
+            .field("pipeline_cache", &self.pipeline_cache)
+            .field("deletion_queue", &self.deletion_queue)
             .field("ray_tracing", &self.ray_tracing)
             .field("graphics_queue", &self.graphics_queue)
             .field("present_queue", &self.present_queue)
+            .field("compute_queue", &self.compute_queue)
+            .field("transfer_queue", &self.transfer_queue)
             .field("device", &self.device)
             .field("physical_device", &self.physical_device)
             .field("surface", &self.surface)
             .field("instance", &self.instance)
             .field("debug_printing", &self.debug_printing)
             .field("shader_clock", &self.shader_clock)
+            .field("scalar_block_layout", &self.scalar_block_layout)
+            .field("timeline_semaphores", &self.timeline_semaphores)
+            .field("mesh_shading", &self.mesh_shading)
+            .field("descriptor_buffer_properties", &self.descriptor_buffer_properties)
             .field("entry", &())
             //.field("synchronization2", &self.synchronization2)
             //.field("dynamic_rendering", &self.dynamic_rendering)
@@ -349,4 +605,8 @@ impl fmt::Debug for Context {
     }
 }
 
-
+/// Removes repeated names and keeps the first occurrence.
+fn dedup_names(names: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    names.retain(|name| seen.insert(name.to_owned()));
+}
@@ -0,0 +1,84 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Keeps dropped resources alive until every frame that could still use them finished on the gpu.
+/// Cloning gives another handle to the same queue.
+#[derive(Clone, Default)]
+pub struct DeletionQueue {
+    inner: Arc<Mutex<DeletionQueueInner>>,
+}
+
+#[derive(Default)]
+struct DeletionQueueInner {
+    frame_number: u64,
+    completed_frame_number: u64,
+    /// Resources with the frame number they were dropped in.
+    pending: VecDeque<(u64, Box<dyn Any + Send>)>,
+}
+
+impl DeletionQueue {
+    /// Drops the resource once the current frame finished on the gpu. Use this instead of dropping
+    /// buffers, images and other resources that a frame in flight might still read.
+    pub fn push<T: Send + 'static>(&self, resource: T) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.frame_number <= inner.completed_frame_number {
+            // No frame is in flight
+            drop(inner);
+            drop(resource);
+            return;
+        }
+
+        let frame_number = inner.frame_number;
+        inner.pending.push_back((frame_number, Box::new(resource)));
+    }
+
+    /// The frame that is currently recorded.
+    pub fn frame_number(&self) -> u64 {
+        self.inner.lock().unwrap().frame_number
+    }
+
+    /// All frames up to and including this one finished on the gpu.
+    pub fn completed_frame_number(&self) -> u64 {
+        self.inner.lock().unwrap().completed_frame_number
+    }
+
+    /// Has to be called after waiting for the in flight frame that is reused for frame_number.
+    pub(crate) fn retire_frames(&self, frame_number: u64, in_flight_count: usize) {
+        let retired = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.frame_number = frame_number;
+            inner.completed_frame_number = frame_number.saturating_sub(in_flight_count as u64);
+
+            let completed = inner.completed_frame_number;
+            let count = inner.pending.iter().take_while(|(frame, _)| *frame <= completed).count();
+            inner.pending.drain(..count).collect::<Vec<_>>()
+        };
+
+        // Dropped outside of the lock, so resources can push to the queue in their drop.
+        drop(retired);
+    }
+
+    /// Has to be called when the device is idle.
+    pub(crate) fn retire_all(&self) {
+        let retired = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.completed_frame_number = inner.frame_number;
+            inner.pending.drain(..).collect::<Vec<_>>()
+        };
+
+        drop(retired);
+    }
+}
+
+impl fmt::Debug for DeletionQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("DeletionQueue")
+            .field("frame_number", &inner.frame_number)
+            .field("completed_frame_number", &inner.completed_frame_number)
+            .field("pending", &inner.pending.len())
+            .finish()
+    }
+}
@@ -1,14 +1,16 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk;
 
-use crate::{vulkan::{device::Device, AccelerationStructure, Buffer, Context, ImageView, Sampler}, OctaResult};
+use crate::{vulkan::{device::Device, AccelerationStructure, Buffer, BufferView, Context, ImageView, Sampler}, OctaResult};
 
 #[derive(Debug)]
 pub struct DescriptorSetLayout {
     device: Arc<Device>,
     pub(crate) inner: vk::DescriptorSetLayout,
+    /// Created for a DescriptorBuffer instead of a DescriptorPool.
+    pub(crate) descriptor_buffer: bool,
 }
 
 impl DescriptorSetLayout {
@@ -28,7 +30,11 @@ impl DescriptorSetLayout {
 
         let inner = unsafe { device.inner.create_descriptor_set_layout(&dsl_info, None)? };
 
-        Ok(Self { device, inner })
+        Ok(Self {
+            device,
+            inner,
+            descriptor_buffer: flags.contains(vk::DescriptorSetLayoutCreateFlags::DESCRIPTOR_BUFFER_EXT),
+        })
     }
 }
 
@@ -129,121 +135,189 @@ pub struct DescriptorSet {
 }
 
 impl DescriptorSet {
-    // TODO: see how to re-implement version from above (1 call to update_descriptor_sets)
     pub fn update(&self, writes: &[WriteDescriptorSet]) {
-        use WriteDescriptorSetKind::*;
-
-        for write in writes {
-            match write.kind {
-                StorageImage { view, layout } => {
-                    let img_info = vk::DescriptorImageInfo::default()
-                        .image_view(view.inner)
-                        .image_layout(layout);
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .image_info(std::slice::from_ref(&img_info));
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-                SampledImage { view, layout } => {
-                    let img_info = vk::DescriptorImageInfo::default()
-                        .image_view(view.inner)
-                        .image_layout(layout);
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .image_info(std::slice::from_ref(&img_info));
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-                AccelerationStructure {
-                    acceleration_structure,
-                } => {
-                    let mut write_set_as =
-                        vk::WriteDescriptorSetAccelerationStructureKHR::default()
-                            .acceleration_structures(std::slice::from_ref(
-                                &acceleration_structure.inner,
-                            ));
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                        .descriptor_count(1)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .push_next(&mut write_set_as);
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-                UniformBuffer { buffer } => {
-                    let buffer_info = vk::DescriptorBufferInfo::default()
-                        .buffer(buffer.inner)
-                        .range(vk::WHOLE_SIZE);
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .buffer_info(std::slice::from_ref(&buffer_info));
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-                UniformBufferDynamic {
-                    buffer,
-                    byte_stride,
-                } => {
-                    let buffer_info = vk::DescriptorBufferInfo::default()
-                        .buffer(buffer.inner)
-                        .range(byte_stride);
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .buffer_info(std::slice::from_ref(&buffer_info));
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-                StorageBuffer { buffer } => {
-                    let buffer_info = vk::DescriptorBufferInfo::default()
-                        .buffer(buffer.inner)
-                        .range(vk::WHOLE_SIZE);
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .buffer_info(std::slice::from_ref(&buffer_info));
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-                CombinedImageSampler {
-                    view,
-                    sampler,
-                    layout,
-                } => {
-                    let img_info = vk::DescriptorImageInfo::default()
-                        .image_view(view.inner)
-                        .sampler(sampler.inner)
-                        .image_layout(layout);
-
-                    let wds = vk::WriteDescriptorSet::default()
-                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                        .dst_binding(write.binding)
-                        .dst_set(self.inner)
-                        .image_info(std::slice::from_ref(&img_info));
-
-                    unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
-                }
-            };
+        self.update_complex(writes, &[]);
+    }
+
+    /// Everything is written with one update_descriptor_sets call.
+    /// Writes to consecutive array elements of the same binding are merged into one vk::WriteDescriptorSet.
+    pub fn update_complex(&self, writes: &[WriteDescriptorSet], copies: &[CopyDescriptorSet]) {
+        let vk_copies = copies
+            .iter()
+            .map(|copy| {
+                vk::CopyDescriptorSet::default()
+                    .src_set(copy.src_set.inner)
+                    .src_binding(copy.src_binding)
+                    .src_array_element(copy.src_array_element)
+                    .dst_set(self.inner)
+                    .dst_binding(copy.dst_binding)
+                    .dst_array_element(copy.dst_array_element)
+                    .descriptor_count(copy.descriptor_count)
+            })
+            .collect::<Vec<_>>();
+
+        with_vk_writes(writes, self.inner, |vk_writes| unsafe {
+            self.device.inner.update_descriptor_sets(vk_writes, &vk_copies)
+        });
+    }
+}
+
+/// Builds the vk::WriteDescriptorSets for writes, they only live for the call of f.
+pub(crate) fn with_vk_writes<R>(
+    writes: &[WriteDescriptorSet],
+    dst_set: vk::DescriptorSet,
+    f: impl FnOnce(&[vk::WriteDescriptorSet]) -> R,
+) -> R {
+    use WriteDescriptorSetKind::*;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum InfoKind {
+        Image,
+        Buffer,
+        TexelBuffer,
+        AccelerationStructure,
+    }
+
+    struct Group {
+        binding: u32,
+        array_element: u32,
+        ty: vk::DescriptorType,
+        info_kind: InfoKind,
+        start: usize,
+        count: usize,
+    }
+
+    let mut image_infos = vec![];
+    let mut buffer_infos = vec![];
+    let mut texel_buffer_views = vec![];
+    let mut acceleration_structures = vec![];
+    let mut groups: Vec<Group> = vec![];
+
+    for write in writes {
+        let image = |view: &ImageView, sampler, layout| {
+            vk::DescriptorImageInfo::default()
+                .image_view(view.inner)
+                .sampler(sampler)
+                .image_layout(layout)
+        };
+        let buffer = |buffer: &Buffer, offset, range| {
+            vk::DescriptorBufferInfo::default()
+                .buffer(buffer.inner)
+                .offset(offset)
+                .range(range)
+        };
+
+        let (ty, info_kind, start) = match write.kind {
+            StorageImage { view, layout } => {
+                image_infos.push(image(view, vk::Sampler::null(), layout));
+                (vk::DescriptorType::STORAGE_IMAGE, InfoKind::Image, image_infos.len() - 1)
+            }
+            SampledImage { view, layout } => {
+                image_infos.push(image(view, vk::Sampler::null(), layout));
+                (vk::DescriptorType::SAMPLED_IMAGE, InfoKind::Image, image_infos.len() - 1)
+            }
+            InputAttachment { view, layout } => {
+                image_infos.push(image(view, vk::Sampler::null(), layout));
+                (vk::DescriptorType::INPUT_ATTACHMENT, InfoKind::Image, image_infos.len() - 1)
+            }
+            Sampler { sampler } => {
+                image_infos.push(vk::DescriptorImageInfo::default().sampler(sampler.inner));
+                (vk::DescriptorType::SAMPLER, InfoKind::Image, image_infos.len() - 1)
+            }
+            CombinedImageSampler { view, sampler, layout } => {
+                image_infos.push(image(view, sampler.inner, layout));
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, InfoKind::Image, image_infos.len() - 1)
+            }
+            AccelerationStructure { acceleration_structure } => {
+                acceleration_structures.push(acceleration_structure.inner);
+                (vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, InfoKind::AccelerationStructure, acceleration_structures.len() - 1)
+            }
+            UniformBuffer { buffer: b } => {
+                buffer_infos.push(buffer(b, 0, vk::WHOLE_SIZE));
+                (vk::DescriptorType::UNIFORM_BUFFER, InfoKind::Buffer, buffer_infos.len() - 1)
+            }
+            UniformBufferRange { buffer: b, offset, range } => {
+                buffer_infos.push(buffer(b, offset, range));
+                (vk::DescriptorType::UNIFORM_BUFFER, InfoKind::Buffer, buffer_infos.len() - 1)
+            }
+            UniformBufferDynamic { buffer: b, byte_stride } => {
+                buffer_infos.push(buffer(b, 0, byte_stride));
+                (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, InfoKind::Buffer, buffer_infos.len() - 1)
+            }
+            StorageBuffer { buffer: b } => {
+                buffer_infos.push(buffer(b, 0, vk::WHOLE_SIZE));
+                (vk::DescriptorType::STORAGE_BUFFER, InfoKind::Buffer, buffer_infos.len() - 1)
+            }
+            StorageBufferRange { buffer: b, offset, range } => {
+                buffer_infos.push(buffer(b, offset, range));
+                (vk::DescriptorType::STORAGE_BUFFER, InfoKind::Buffer, buffer_infos.len() - 1)
+            }
+            StorageBufferDynamic { buffer: b, byte_stride } => {
+                buffer_infos.push(buffer(b, 0, byte_stride));
+                (vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, InfoKind::Buffer, buffer_infos.len() - 1)
+            }
+            UniformTexelBuffer { view } => {
+                texel_buffer_views.push(view.inner);
+                (vk::DescriptorType::UNIFORM_TEXEL_BUFFER, InfoKind::TexelBuffer, texel_buffer_views.len() - 1)
+            }
+            StorageTexelBuffer { view } => {
+                texel_buffer_views.push(view.inner);
+                (vk::DescriptorType::STORAGE_TEXEL_BUFFER, InfoKind::TexelBuffer, texel_buffer_views.len() - 1)
+            }
+        };
+
+        match groups.last_mut() {
+            Some(group) if group.binding == write.binding
+                && group.ty == ty
+                && group.array_element + group.count as u32 == write.array_element => {
+                group.count += 1;
+            }
+            _ => groups.push(Group {
+                binding: write.binding,
+                array_element: write.array_element,
+                ty,
+                info_kind,
+                start,
+                count: 1,
+            }),
         }
     }
+
+    let mut acceleration_structure_writes = groups
+        .iter()
+        .filter(|group| group.info_kind == InfoKind::AccelerationStructure)
+        .map(|group| {
+            vk::WriteDescriptorSetAccelerationStructureKHR::default()
+                .acceleration_structures(&acceleration_structures[group.start..group.start + group.count])
+        })
+        .collect::<Vec<_>>();
+    let mut acceleration_structure_writes = acceleration_structure_writes.iter_mut();
+
+    let vk_writes = groups
+        .iter()
+        .map(|group| {
+            let range = group.start..group.start + group.count;
+            let wds = vk::WriteDescriptorSet::default()
+                .dst_set(dst_set)
+                .dst_binding(group.binding)
+                .dst_array_element(group.array_element)
+                .descriptor_type(group.ty);
+
+            match group.info_kind {
+                InfoKind::Image => wds.image_info(&image_infos[range]),
+                InfoKind::Buffer => wds.buffer_info(&buffer_infos[range]),
+                InfoKind::TexelBuffer => wds.texel_buffer_view(&texel_buffer_views[range]),
+                InfoKind::AccelerationStructure => wds
+                    .descriptor_count(group.count as u32)
+                    .push_next(acceleration_structure_writes.next().unwrap()),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    f(&vk_writes)
 }
 
+
 impl Context {
     pub fn create_descriptor_set_layout(
         &self,
@@ -252,6 +326,20 @@ impl Context {
         DescriptorSetLayout::new(self.device.clone(), bindings, vk::DescriptorSetLayoutCreateFlags::empty(), &[])
     }
 
+    /// PUSH_DESCRIPTOR_KHR needs the push_descriptors engine feature.
+    pub fn create_descriptor_set_layout_complex(
+        &self,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        flags: vk::DescriptorSetLayoutCreateFlags,
+        binding_flags: &[vk::DescriptorBindingFlags],
+    ) -> Result<DescriptorSetLayout> {
+        if flags.contains(vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR) && self.device.push_descriptor_fn.is_none() {
+            bail!("Push descriptors are not enabled, set EngineConfig::push_descriptors");
+        }
+
+        DescriptorSetLayout::new(self.device.clone(), bindings, flags, binding_flags)
+    }
+
     pub fn create_descriptor_pool(
         &self,
         max_sets: u32,
@@ -264,9 +352,25 @@ impl Context {
 #[derive(Clone, Copy)]
 pub struct WriteDescriptorSet<'a> {
     pub binding: u32,
+    /// Index into an array binding, 0 for bindings that are not arrays.
+    pub array_element: u32,
     pub kind: WriteDescriptorSetKind<'a>,
 }
 
+impl<'a> WriteDescriptorSet<'a> {
+    pub fn new(binding: u32, kind: WriteDescriptorSetKind<'a>) -> Self {
+        Self::array(binding, 0, kind)
+    }
+
+    pub fn array(binding: u32, array_element: u32, kind: WriteDescriptorSetKind<'a>) -> Self {
+        Self {
+            binding,
+            array_element,
+            kind,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum WriteDescriptorSetKind<'a> {
     StorageImage {
@@ -277,12 +381,24 @@ pub enum WriteDescriptorSetKind<'a> {
         view: &'a ImageView,
         layout: vk::ImageLayout,
     },
+    InputAttachment {
+        view: &'a ImageView,
+        layout: vk::ImageLayout,
+    },
+    Sampler {
+        sampler: &'a Sampler,
+    },
     AccelerationStructure {
         acceleration_structure: &'a AccelerationStructure,
     },
     UniformBuffer {
         buffer: &'a Buffer,
     },
+    UniformBufferRange {
+        buffer: &'a Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    },
     UniformBufferDynamic {
         buffer: &'a Buffer,
         byte_stride: vk::DeviceSize,
@@ -290,9 +406,34 @@ pub enum WriteDescriptorSetKind<'a> {
     StorageBuffer {
         buffer: &'a Buffer,
     },
+    StorageBufferRange {
+        buffer: &'a Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    },
+    StorageBufferDynamic {
+        buffer: &'a Buffer,
+        byte_stride: vk::DeviceSize,
+    },
+    UniformTexelBuffer {
+        view: &'a BufferView,
+    },
+    StorageTexelBuffer {
+        view: &'a BufferView,
+    },
     CombinedImageSampler {
         view: &'a ImageView,
         sampler: &'a Sampler,
         layout: vk::ImageLayout,
     },
 }
+
+#[derive(Clone, Copy)]
+pub struct CopyDescriptorSet<'a> {
+    pub src_set: &'a DescriptorSet,
+    pub src_binding: u32,
+    pub src_array_element: u32,
+    pub dst_binding: u32,
+    pub dst_array_element: u32,
+    pub descriptor_count: u32,
+}
@@ -0,0 +1,375 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use ash::vk;
+use gpu_allocator::vulkan::Allocator;
+use gpu_allocator::MemoryLocation;
+
+use crate::vulkan::{device::Device, instance::Instance, physical_device::PhysicalDevice};
+use crate::vulkan::{Buffer, CommandBuffer, Context, DescriptorSetLayout, ImageView, PipelineLayout, WriteDescriptorSet, WriteDescriptorSetKind};
+
+#[derive(Debug, Clone, Copy)]
+pub struct DescriptorBufferProperties {
+    pub combined_image_sampler_descriptor_single_array: bool,
+    pub descriptor_buffer_offset_alignment: vk::DeviceSize,
+    pub max_descriptor_buffer_bindings: u32,
+    pub max_resource_descriptor_buffer_bindings: u32,
+    pub max_sampler_descriptor_buffer_bindings: u32,
+    pub sampler_descriptor_size: usize,
+    pub combined_image_sampler_descriptor_size: usize,
+    pub sampled_image_descriptor_size: usize,
+    pub storage_image_descriptor_size: usize,
+    pub uniform_texel_buffer_descriptor_size: usize,
+    pub storage_texel_buffer_descriptor_size: usize,
+    pub uniform_buffer_descriptor_size: usize,
+    pub storage_buffer_descriptor_size: usize,
+    pub input_attachment_descriptor_size: usize,
+    pub acceleration_structure_descriptor_size: usize,
+}
+
+impl DescriptorBufferProperties {
+    pub(crate) fn new(instance: &Instance, pdevice: &PhysicalDevice) -> Self {
+        let mut properties = vk::PhysicalDeviceDescriptorBufferPropertiesEXT::default();
+        let mut pproperties2 = vk::PhysicalDeviceProperties2::default().push_next(&mut properties);
+        unsafe {
+            instance
+                .inner
+                .get_physical_device_properties2(pdevice.inner, &mut pproperties2)
+        };
+
+        properties.into()
+    }
+
+    pub fn descriptor_size(&self, ty: vk::DescriptorType) -> Option<usize> {
+        let size = match ty {
+            vk::DescriptorType::SAMPLER => self.sampler_descriptor_size,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER => self.combined_image_sampler_descriptor_size,
+            vk::DescriptorType::SAMPLED_IMAGE => self.sampled_image_descriptor_size,
+            vk::DescriptorType::STORAGE_IMAGE => self.storage_image_descriptor_size,
+            vk::DescriptorType::UNIFORM_TEXEL_BUFFER => self.uniform_texel_buffer_descriptor_size,
+            vk::DescriptorType::STORAGE_TEXEL_BUFFER => self.storage_texel_buffer_descriptor_size,
+            vk::DescriptorType::UNIFORM_BUFFER => self.uniform_buffer_descriptor_size,
+            vk::DescriptorType::STORAGE_BUFFER => self.storage_buffer_descriptor_size,
+            vk::DescriptorType::INPUT_ATTACHMENT => self.input_attachment_descriptor_size,
+            vk::DescriptorType::ACCELERATION_STRUCTURE_KHR => self.acceleration_structure_descriptor_size,
+            _ => return None,
+        };
+        Some(size)
+    }
+}
+
+impl From<vk::PhysicalDeviceDescriptorBufferPropertiesEXT<'_>> for DescriptorBufferProperties {
+    fn from(p: vk::PhysicalDeviceDescriptorBufferPropertiesEXT<'_>) -> Self {
+        Self {
+            combined_image_sampler_descriptor_single_array: p.combined_image_sampler_descriptor_single_array == vk::TRUE,
+            descriptor_buffer_offset_alignment: p.descriptor_buffer_offset_alignment,
+            max_descriptor_buffer_bindings: p.max_descriptor_buffer_bindings,
+            max_resource_descriptor_buffer_bindings: p.max_resource_descriptor_buffer_bindings,
+            max_sampler_descriptor_buffer_bindings: p.max_sampler_descriptor_buffer_bindings,
+            sampler_descriptor_size: p.sampler_descriptor_size,
+            combined_image_sampler_descriptor_size: p.combined_image_sampler_descriptor_size,
+            sampled_image_descriptor_size: p.sampled_image_descriptor_size,
+            storage_image_descriptor_size: p.storage_image_descriptor_size,
+            uniform_texel_buffer_descriptor_size: p.uniform_texel_buffer_descriptor_size,
+            storage_texel_buffer_descriptor_size: p.storage_texel_buffer_descriptor_size,
+            uniform_buffer_descriptor_size: p.uniform_buffer_descriptor_size,
+            storage_buffer_descriptor_size: p.storage_buffer_descriptor_size,
+            input_attachment_descriptor_size: p.input_attachment_descriptor_size,
+            acceleration_structure_descriptor_size: p.acceleration_structure_descriptor_size,
+        }
+    }
+}
+
+/// Descriptor sets written directly into host visible buffer memory with VK_EXT_descriptor_buffer,
+/// instead of allocating them from a DescriptorPool.
+/// Holds set_count sets of the same layout, pipelines using the layout are created for descriptor buffers automatically.
+#[derive(Debug)]
+pub struct DescriptorBuffer {
+    device: Arc<Device>,
+    pub layout: DescriptorSetLayout,
+    pub buffer: Buffer,
+    pub usage: vk::BufferUsageFlags,
+    pub set_count: u32,
+    set_size: vk::DeviceSize,
+    bindings: Vec<DescriptorBufferBinding>,
+    properties: DescriptorBufferProperties,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DescriptorBufferBinding {
+    binding: u32,
+    ty: vk::DescriptorType,
+    count: u32,
+    offset: vk::DeviceSize,
+}
+
+impl DescriptorBuffer {
+    pub(crate) fn new(
+        device: Arc<Device>,
+        allocator: Arc<Mutex<Allocator>>,
+        properties: DescriptorBufferProperties,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        set_count: u32,
+    ) -> Result<Self> {
+        let layout = DescriptorSetLayout::new(
+            device.clone(),
+            bindings,
+            vk::DescriptorSetLayoutCreateFlags::DESCRIPTOR_BUFFER_EXT,
+            &[],
+        )?;
+
+        let descriptor_buffer_fn = device.descriptor_buffer_fn.as_ref().unwrap();
+        let set_size = unsafe { descriptor_buffer_fn.get_descriptor_set_layout_size(layout.inner) }
+            .next_multiple_of(properties.descriptor_buffer_offset_alignment);
+
+        let mut usage = vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        let bindings = bindings
+            .iter()
+            .map(|b| {
+                if properties.descriptor_size(b.descriptor_type).is_none() {
+                    bail!("Descriptor type {:?} of binding {} is not supported in descriptor buffers", b.descriptor_type, b.binding);
+                }
+
+                usage |= match b.descriptor_type {
+                    vk::DescriptorType::SAMPLER => vk::BufferUsageFlags::SAMPLER_DESCRIPTOR_BUFFER_EXT,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER => vk::BufferUsageFlags::SAMPLER_DESCRIPTOR_BUFFER_EXT
+                        | vk::BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT,
+                    _ => vk::BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT,
+                };
+
+                let offset = unsafe { descriptor_buffer_fn.get_descriptor_set_layout_binding_offset(layout.inner, b.binding) };
+                Ok(DescriptorBufferBinding {
+                    binding: b.binding,
+                    ty: b.descriptor_type,
+                    count: b.descriptor_count,
+                    offset,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let buffer = Buffer::new(
+            device.clone(),
+            allocator,
+            usage,
+            MemoryLocation::CpuToGpu,
+            set_size.max(1) * set_count.max(1) as vk::DeviceSize,
+        )?;
+
+        Ok(Self {
+            device,
+            layout,
+            buffer,
+            usage,
+            set_count,
+            set_size,
+            bindings,
+            properties,
+        })
+    }
+
+    pub fn address(&self) -> vk::DeviceAddress {
+        self.buffer.get_device_address()
+    }
+
+    /// Offset of the set for set_descriptor_buffer_offsets.
+    pub fn set_offset(&self, set_index: u32) -> vk::DeviceSize {
+        self.set_size * set_index as vk::DeviceSize
+    }
+
+    /// Writes the descriptors with vkGetDescriptorEXT into the memory of the set.
+    /// Sets that are read by a frame in flight must not be written.
+    pub fn write(&mut self, set_index: u32, writes: &[WriteDescriptorSet]) -> Result<()> {
+        use WriteDescriptorSetKind::*;
+
+        if set_index >= self.set_count {
+            bail!("Set index {set_index} out of range, descriptor buffer has {} sets", self.set_count);
+        }
+
+        let descriptor_buffer_fn = self.device.descriptor_buffer_fn.as_ref().unwrap();
+        let set_offset = self.set_offset(set_index) as usize;
+
+        for write in writes {
+            let Some(binding) = self.bindings.iter().find(|b| b.binding == write.binding).copied() else {
+                bail!("Binding {} is not part of the descriptor buffer layout", write.binding);
+            };
+            if write.array_element >= binding.count {
+                bail!("Array element {} out of range for binding {} with {} descriptors", write.array_element, binding.binding, binding.count);
+            }
+
+            let image = |view: &ImageView, sampler, layout| {
+                vk::DescriptorImageInfo::default()
+                    .image_view(view.inner)
+                    .sampler(sampler)
+                    .image_layout(layout)
+            };
+            let address = |buffer: &Buffer, offset: vk::DeviceSize, range: vk::DeviceSize| -> Result<vk::DescriptorAddressInfoEXT> {
+                if offset > buffer.size || (range != vk::WHOLE_SIZE && offset.checked_add(range).is_none_or(|end| end > buffer.size)) {
+                    bail!("Range with offset {offset} and size {range} out of bounds for buffer with {} bytes", buffer.size);
+                }
+
+                Ok(vk::DescriptorAddressInfoEXT::default()
+                    .address(buffer.get_device_address() + offset)
+                    .range(if range == vk::WHOLE_SIZE { buffer.size - offset } else { range }))
+            };
+
+            let image_info;
+            let address_info;
+            let sampler;
+            let (ty, data) = match write.kind {
+                StorageImage { view, layout } => {
+                    image_info = image(view, vk::Sampler::null(), layout);
+                    (vk::DescriptorType::STORAGE_IMAGE, vk::DescriptorDataEXT { p_storage_image: &image_info })
+                }
+                SampledImage { view, layout } => {
+                    image_info = image(view, vk::Sampler::null(), layout);
+                    (vk::DescriptorType::SAMPLED_IMAGE, vk::DescriptorDataEXT { p_sampled_image: &image_info })
+                }
+                InputAttachment { view, layout } => {
+                    image_info = image(view, vk::Sampler::null(), layout);
+                    (vk::DescriptorType::INPUT_ATTACHMENT, vk::DescriptorDataEXT { p_input_attachment_image: &image_info })
+                }
+                CombinedImageSampler { view, sampler, layout } => {
+                    image_info = image(view, sampler.inner, layout);
+                    (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::DescriptorDataEXT { p_combined_image_sampler: &image_info })
+                }
+                Sampler { sampler: s } => {
+                    sampler = s.inner;
+                    (vk::DescriptorType::SAMPLER, vk::DescriptorDataEXT { p_sampler: &sampler })
+                }
+                AccelerationStructure { acceleration_structure } => {
+                    (vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, vk::DescriptorDataEXT { acceleration_structure: acceleration_structure.address })
+                }
+                UniformBuffer { buffer } => {
+                    address_info = address(buffer, 0, vk::WHOLE_SIZE)?;
+                    (vk::DescriptorType::UNIFORM_BUFFER, vk::DescriptorDataEXT { p_uniform_buffer: &address_info })
+                }
+                UniformBufferRange { buffer, offset, range } => {
+                    address_info = address(buffer, offset, range)?;
+                    (vk::DescriptorType::UNIFORM_BUFFER, vk::DescriptorDataEXT { p_uniform_buffer: &address_info })
+                }
+                StorageBuffer { buffer } => {
+                    address_info = address(buffer, 0, vk::WHOLE_SIZE)?;
+                    (vk::DescriptorType::STORAGE_BUFFER, vk::DescriptorDataEXT { p_storage_buffer: &address_info })
+                }
+                StorageBufferRange { buffer, offset, range } => {
+                    address_info = address(buffer, offset, range)?;
+                    (vk::DescriptorType::STORAGE_BUFFER, vk::DescriptorDataEXT { p_storage_buffer: &address_info })
+                }
+                UniformTexelBuffer { view } | StorageTexelBuffer { view } => {
+                    let buffer_address = unsafe {
+                        self.device.inner.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(view.buffer))
+                    };
+                    address_info = vk::DescriptorAddressInfoEXT::default()
+                        .address(buffer_address + view.offset)
+                        .range(view.range)
+                        .format(view.format);
+                    if matches!(write.kind, UniformTexelBuffer { .. }) {
+                        (vk::DescriptorType::UNIFORM_TEXEL_BUFFER, vk::DescriptorDataEXT { p_uniform_texel_buffer: &address_info })
+                    } else {
+                        (vk::DescriptorType::STORAGE_TEXEL_BUFFER, vk::DescriptorDataEXT { p_storage_texel_buffer: &address_info })
+                    }
+                }
+                UniformBufferDynamic { .. } | StorageBufferDynamic { .. } => {
+                    bail!("Dynamic buffers are not supported in descriptor buffers, use a range and set_descriptor_buffer_offsets instead");
+                }
+            };
+
+            if ty != binding.ty {
+                bail!("Binding {} has type {:?} but was written with {:?}", binding.binding, binding.ty, ty);
+            }
+
+            let info = vk::DescriptorGetInfoEXT::default()
+                .ty(ty)
+                .data(data);
+            let size = self.properties.descriptor_size(ty).unwrap();
+            let binding_offset = set_offset + binding.offset as usize;
+            let bytes = self.buffer.get_mapped_bytes();
+
+            if ty == vk::DescriptorType::COMBINED_IMAGE_SAMPLER && !self.properties.combined_image_sampler_descriptor_single_array {
+                // The array is split into all image descriptors followed by all sampler descriptors.
+                let mut descriptor = vec![0; size];
+                unsafe { descriptor_buffer_fn.get_descriptor(&info, &mut descriptor) };
+
+                let image_size = self.properties.sampled_image_descriptor_size;
+                let sampler_size = size - image_size;
+                let image_offset = binding_offset + write.array_element as usize * image_size;
+                let sampler_offset = binding_offset + binding.count as usize * image_size + write.array_element as usize * sampler_size;
+                bytes[image_offset..image_offset + image_size].copy_from_slice(&descriptor[..image_size]);
+                bytes[sampler_offset..sampler_offset + sampler_size].copy_from_slice(&descriptor[image_size..]);
+            } else {
+                let offset = binding_offset + write.array_element as usize * size;
+                unsafe { descriptor_buffer_fn.get_descriptor(&info, &mut bytes[offset..offset + size]) };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Context {
+    /// Creates the set layout and a buffer with room for set_count sets of it.
+    /// Needs the descriptor_buffer engine feature.
+    pub fn create_descriptor_buffer(
+        &self,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        set_count: u32,
+    ) -> Result<DescriptorBuffer> {
+        let Some(properties) = self.descriptor_buffer_properties else {
+            bail!("Descriptor buffers are not enabled, set EngineConfig::descriptor_buffer");
+        };
+
+        DescriptorBuffer::new(
+            self.device.clone(),
+            self.allocator.clone(),
+            properties,
+            bindings,
+            set_count,
+        )
+    }
+}
+
+impl CommandBuffer {
+    /// Replaces all bound descriptor buffers, the index into buffers is the buffer index for set_descriptor_buffer_offsets.
+    pub fn bind_descriptor_buffers(&self, buffers: &[&DescriptorBuffer]) {
+        let binding_infos = buffers
+            .iter()
+            .map(|b| {
+                vk::DescriptorBufferBindingInfoEXT::default()
+                    .address(b.address())
+                    .usage(b.usage)
+            })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            self.descriptor_buffer_fn("bind_descriptor_buffers")
+                .cmd_bind_descriptor_buffers(self.inner, &binding_infos)
+        };
+    }
+
+    /// Sets first_set.. of the layout read from the bound buffers at buffer_indices, starting at offsets.
+    pub fn set_descriptor_buffer_offsets(
+        &self,
+        bind_point: vk::PipelineBindPoint,
+        layout: &PipelineLayout,
+        first_set: u32,
+        buffer_indices: &[u32],
+        offsets: &[vk::DeviceSize],
+    ) {
+        unsafe {
+            self.descriptor_buffer_fn("set_descriptor_buffer_offsets").cmd_set_descriptor_buffer_offsets(
+                self.inner,
+                bind_point,
+                layout.inner,
+                first_set,
+                buffer_indices,
+                offsets,
+            )
+        };
+    }
+
+    fn descriptor_buffer_fn(&self, name: &str) -> &ash::ext::descriptor_buffer::Device {
+        self.device.descriptor_buffer_fn.as_ref().unwrap_or_else(|| {
+            panic!("Cannot call CommandBuffer::{name} when descriptor buffers are not enabled")
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc, sync::Arc, usize};
+use std::{collections::VecDeque, sync::{Arc, Mutex}, usize};
 use anyhow::bail;
 use ash::vk;
 use index_pool::IndexPool;
@@ -6,97 +6,249 @@ use log::{trace};
 
 use crate::OctaResult;
 
-use super::{Context, DescriptorPool, DescriptorSet, DescriptorSetLayout, Device, ImageView};
+use super::{AccelerationStructure, Buffer, Context, DeletionQueue, DescriptorPool, DescriptorSet, DescriptorSetLayout, Device, ImageView, Sampler};
 
+/// One global descriptor set with an array binding per descriptor type, indexed with the handle values.
+/// Handles can be created and dropped from any thread.
+/// The layout uses update after bind and partially bound bindings, the Context enables the matching
+/// descriptorBinding*UpdateAfterBind features when the device supports them.
 #[derive(Debug)]
-pub struct ImageDescriptorHeap {
+pub struct BindlessHeap {
     device: Arc<Device>,
     pub pool: DescriptorPool,
     pub layout: DescriptorSetLayout,
     pub set: DescriptorSet,
-    allocator: Rc<RefCell<IndexPool>>,
-    size: usize,
+    allocator: Arc<Mutex<HandleAllocator>>,
+    storage_buffer_allocator: Arc<Mutex<HandleAllocator>>,
+    uniform_buffer_allocator: Arc<Mutex<HandleAllocator>>,
+    combined_image_sampler_allocator: Arc<Mutex<HandleAllocator>>,
+    sampler_allocator: Arc<Mutex<HandleAllocator>>,
+    acceleration_structure_allocator: Arc<Mutex<HandleAllocator>>,
+    pub sizes: BindlessHeapSizes,
+    /// Descriptor writes to the set have to be externally synchronized.
+    write_lock: Mutex<()>,
+}
+
+pub type ImageDescriptorHeap = BindlessHeap;
+
+/// Descriptor count of each binding of a BindlessHeap, bindings with 0 descriptors are left out of the layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BindlessHeapSizes {
+    pub sampled_images: u32,
+    pub storage_images: u32,
+    pub storage_buffers: u32,
+    pub uniform_buffers: u32,
+    pub combined_image_samplers: u32,
+    pub samplers: u32,
+    /// Needs ray tracing or ray query to be enabled.
+    pub acceleration_structures: u32,
+}
+
+impl BindlessHeapSizes {
+    /// Only the sampled and storage image bindings.
+    pub fn images(size: u32) -> Self {
+        Self {
+            sampled_images: size,
+            storage_images: size,
+            ..Default::default()
+        }
+    }
+
+    /// Binding, type, count and the update after bind feature of each binding with descriptors.
+    fn bindings(&self) -> Vec<(u32, vk::DescriptorType, u32, &'static str)> {
+        [
+            (BindlessHeap::SAMPLED_IMAGE_BINDING, vk::DescriptorType::SAMPLED_IMAGE, self.sampled_images,
+                "descriptorBindingSampledImageUpdateAfterBind"),
+            (BindlessHeap::STORAGE_IMAGE_BINDING, vk::DescriptorType::STORAGE_IMAGE, self.storage_images,
+                "descriptorBindingStorageImageUpdateAfterBind"),
+            (BindlessHeap::STORAGE_BUFFER_BINDING, vk::DescriptorType::STORAGE_BUFFER, self.storage_buffers,
+                "descriptorBindingStorageBufferUpdateAfterBind"),
+            (BindlessHeap::UNIFORM_BUFFER_BINDING, vk::DescriptorType::UNIFORM_BUFFER, self.uniform_buffers,
+                "descriptorBindingUniformBufferUpdateAfterBind"),
+            (BindlessHeap::COMBINED_IMAGE_SAMPLER_BINDING, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, self.combined_image_samplers,
+                "descriptorBindingSampledImageUpdateAfterBind"),
+            // Samplers can always be updated after bind
+            (BindlessHeap::SAMPLER_BINDING, vk::DescriptorType::SAMPLER, self.samplers, ""),
+            (BindlessHeap::ACCELERATION_STRUCTURE_BINDING, vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, self.acceleration_structures,
+                "descriptorBindingAccelerationStructureUpdateAfterBind"),
+        ]
+        .into_iter()
+        .filter(|(_, _, count, _)| *count > 0)
+        .collect()
+    }
 }
 
 pub type DescriptorHandleValue = u32;
 
+/// Values of dropped handles are only reused after the frames that could still read them finished,
+/// because the heap bindings are UPDATE_UNUSED_WHILE_PENDING.
 #[derive(Debug)]
-pub struct ImageDescriptorHandle {
-    pub value: DescriptorHandleValue,
-    allocator: Rc<RefCell<IndexPool>>
+struct HandleAllocator {
+    pool: IndexPool,
+    /// Dropped values with the frame number they were dropped in.
+    retired: VecDeque<(u64, DescriptorHandleValue)>,
+    deletion_queue: DeletionQueue,
 }
 
-impl ImageDescriptorHeap {
-    pub(crate) fn new(device: Arc<Device>, size: usize) -> OctaResult<Self> {
-
-        let heap_types = vec![
-            vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::SAMPLED_IMAGE,
-                descriptor_count: size as u32,
-            },
-            vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::STORAGE_IMAGE,
-                descriptor_count: size as u32,
-            },
-        ];
-        
+impl HandleAllocator {
+    fn new(deletion_queue: DeletionQueue) -> Self {
+        Self {
+            pool: IndexPool::new(),
+            retired: VecDeque::new(),
+            deletion_queue,
+        }
+    }
+
+    fn allocate(&mut self) -> DescriptorHandleValue {
+        let completed = self.deletion_queue.completed_frame_number();
+        while self.retired.front().is_some_and(|(frame, _)| *frame <= completed) {
+            let (_, value) = self.retired.pop_front().unwrap();
+            self.pool.return_id(value as usize)
+                .expect("Dropped DescriptorHandle with already retured value");
+        }
+
+        self.pool.new_id() as _
+    }
+
+    fn free(&mut self, value: DescriptorHandleValue) {
+        let frame_number = self.deletion_queue.frame_number();
+        self.retired.push_back((frame_number, value));
+    }
+}
+
+macro_rules! descriptor_handle {
+    ($name:ident) => {
+        #[derive(Debug)]
+        pub struct $name {
+            pub value: DescriptorHandleValue,
+            allocator: Arc<Mutex<HandleAllocator>>
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.allocator.lock().unwrap().free(self.value);
+            }
+        }
+    };
+}
+
+descriptor_handle!(ImageDescriptorHandle);
+descriptor_handle!(StorageBufferDescriptorHandle);
+descriptor_handle!(UniformBufferDescriptorHandle);
+descriptor_handle!(CombinedImageSamplerDescriptorHandle);
+descriptor_handle!(SamplerDescriptorHandle);
+descriptor_handle!(AccelerationStructureDescriptorHandle);
+
+impl BindlessHeap {
+    pub const SAMPLED_IMAGE_BINDING: u32 = 0;
+    pub const STORAGE_IMAGE_BINDING: u32 = 1;
+    pub const STORAGE_BUFFER_BINDING: u32 = 2;
+    pub const UNIFORM_BUFFER_BINDING: u32 = 3;
+    pub const COMBINED_IMAGE_SAMPLER_BINDING: u32 = 4;
+    pub const SAMPLER_BINDING: u32 = 5;
+    pub const ACCELERATION_STRUCTURE_BINDING: u32 = 6;
+
+    pub(crate) fn new(device: Arc<Device>, sizes: BindlessHeapSizes, deletion_queue: DeletionQueue) -> OctaResult<Self> {
+        let heap_types = sizes.bindings();
+        if heap_types.is_empty() {
+            bail!("Descriptor Heap needs at least one binding");
+        }
+
+        let pool_sizes = heap_types.iter()
+            .map(|(_, ty, count, _)| vk::DescriptorPoolSize {
+                ty: *ty,
+                descriptor_count: *count,
+            })
+            .collect::<Vec<_>>();
+
         let pool = DescriptorPool::new(
-            device.clone(), 
+            device.clone(),
             1,
-            &heap_types,
+            &pool_sizes,
             vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND
         )?;
 
         let (bindings, flags) = heap_types.iter()
-            .enumerate()
-            .map(|(i, t)| {
+            .map(|(binding, ty, count, _)| {
                 (vk::DescriptorSetLayoutBinding {
-                    binding: i as _,
-                    descriptor_count: t.descriptor_count,
-                    descriptor_type: t.ty,
+                    binding: *binding,
+                    descriptor_count: *count,
+                    descriptor_type: *ty,
                     stage_flags: vk::ShaderStageFlags::ALL,
                     ..Default::default()
                 },
-                vk::DescriptorBindingFlags::PARTIALLY_BOUND 
+                vk::DescriptorBindingFlags::PARTIALLY_BOUND
                     | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
                     | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING
                 )
             })
             .collect::<(Vec<_>, Vec<_>)>();
-        
+
         let layout = DescriptorSetLayout::new(
-            device.clone(), 
-            &bindings, 
+            device.clone(),
+            &bindings,
             vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL,
             &flags)?;
 
         let set = pool.allocate_set(&layout)?;
-      
-        let allocator = Rc::new(RefCell::new(IndexPool::new()));
+
+        let new_allocator = || Arc::new(Mutex::new(HandleAllocator::new(deletion_queue.clone())));
 
         Ok(Self {
             device,
             pool,
             layout,
             set,
-            allocator,
-            size,
+            allocator: new_allocator(),
+            storage_buffer_allocator: new_allocator(),
+            uniform_buffer_allocator: new_allocator(),
+            combined_image_sampler_allocator: new_allocator(),
+            sampler_allocator: new_allocator(),
+            acceleration_structure_allocator: new_allocator(),
+            sizes,
+            write_lock: Mutex::new(()),
         })
     }
 
-    pub fn create_image_handle(&mut self, view: &ImageView, usage: vk::ImageUsageFlags) -> OctaResult<ImageDescriptorHandle> {
-        let handle = self.allocator.borrow_mut().new_id() as u32;
-        if handle >= self.size as u32 {
-            bail!("Descriptor Heap of size {} full!", self.size);
+    fn update(&self, writes: &[vk::WriteDescriptorSet]) {
+        let _lock = self.write_lock.lock().unwrap();
+        unsafe { self.device.inner.update_descriptor_sets(writes, &[]) };
+    }
+
+    fn new_handle_value(&self, allocator: &Arc<Mutex<HandleAllocator>>, size: u32, kind: &str) -> OctaResult<DescriptorHandleValue> {
+        if size == 0 {
+            bail!("Descriptor Heap has no {kind} binding");
         }
 
+        let mut allocator = allocator.lock().unwrap();
+        let handle = allocator.allocate();
+        if handle >= size {
+            allocator.pool.return_id(handle as usize).expect("Returned new handle value");
+            bail!("Descriptor Heap {kind} binding of size {size} full!");
+        }
+
+        Ok(handle)
+    }
+
+    pub fn create_image_handle(&self, view: &ImageView, usage: vk::ImageUsageFlags) -> OctaResult<ImageDescriptorHandle> {
+        // Sampled and storage images share the handle values
+        let handle = match (usage.contains(vk::ImageUsageFlags::SAMPLED), usage.contains(vk::ImageUsageFlags::STORAGE)) {
+            (true, true) => self.new_handle_value(
+                &self.allocator,
+                self.sizes.sampled_images.min(self.sizes.storage_images),
+                "sampled and storage image",
+            )?,
+            (false, true) => self.new_handle_value(&self.allocator, self.sizes.storage_images, "storage image")?,
+            _ => self.new_handle_value(&self.allocator, self.sizes.sampled_images, "sampled image")?,
+        };
+
         if usage.contains(vk::ImageUsageFlags::SAMPLED) {
             let img_info = vk::DescriptorImageInfo::default()
                 .image_view(view.inner)
                 .image_layout(
-                    if usage.contains(vk::ImageUsageFlags::STORAGE) { 
-                        vk::ImageLayout::GENERAL 
-                    } else { 
+                    if usage.contains(vk::ImageUsageFlags::STORAGE) {
+                        vk::ImageLayout::GENERAL
+                    } else {
                         vk::ImageLayout::READ_ONLY_OPTIMAL
                     }
                 );
@@ -106,12 +258,12 @@ impl ImageDescriptorHeap {
                 .dst_array_element(handle)
                 .descriptor_count(1)
                 .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
-                .dst_binding(0) // The index in heap_types
+                .dst_binding(Self::SAMPLED_IMAGE_BINDING)
                 .image_info(std::slice::from_ref(&img_info));
-            
-            unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
+
+            self.update(&[wds]);
             trace!("Creating Sampled Image Handle {handle} with usage flags {usage:?} and layout {:?}", img_info.image_layout);
-        } 
+        }
 
         if usage.contains(vk::ImageUsageFlags::STORAGE) {
             let img_info = vk::DescriptorImageInfo::default()
@@ -123,29 +275,186 @@ impl ImageDescriptorHeap {
                 .dst_array_element(handle)
                 .descriptor_count(1)
                 .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                .dst_binding(1) // The index in heap_types
+                .dst_binding(Self::STORAGE_IMAGE_BINDING)
                 .image_info(std::slice::from_ref(&img_info));
 
-            unsafe { self.device.inner.update_descriptor_sets(&[wds], &[]) };
+            self.update(&[wds]);
             trace!("Creating Storage Image Handle {handle} with usage flags {usage:?} and layout {:?}", img_info.image_layout);
-        } 
+        }
 
         Ok(ImageDescriptorHandle { value: handle, allocator: self.allocator.clone() })
     }
+
+    pub fn create_storage_buffer_handle(&self, buffer: &Buffer) -> OctaResult<StorageBufferDescriptorHandle> {
+        let handle = self.new_handle_value(&self.storage_buffer_allocator, self.sizes.storage_buffers, "storage buffer")?;
+        self.write_buffer(handle, buffer, vk::DescriptorType::STORAGE_BUFFER, Self::STORAGE_BUFFER_BINDING);
+        trace!("Creating Storage Buffer Handle {handle}");
+
+        Ok(StorageBufferDescriptorHandle { value: handle, allocator: self.storage_buffer_allocator.clone() })
+    }
+
+    pub fn create_uniform_buffer_handle(&self, buffer: &Buffer) -> OctaResult<UniformBufferDescriptorHandle> {
+        let handle = self.new_handle_value(&self.uniform_buffer_allocator, self.sizes.uniform_buffers, "uniform buffer")?;
+        self.write_buffer(handle, buffer, vk::DescriptorType::UNIFORM_BUFFER, Self::UNIFORM_BUFFER_BINDING);
+        trace!("Creating Uniform Buffer Handle {handle}");
+
+        Ok(UniformBufferDescriptorHandle { value: handle, allocator: self.uniform_buffer_allocator.clone() })
+    }
+
+    fn write_buffer(&self, handle: DescriptorHandleValue, buffer: &Buffer, ty: vk::DescriptorType, binding: u32) {
+        let buffer_info = vk::DescriptorBufferInfo::default()
+            .buffer(buffer.inner)
+            .range(vk::WHOLE_SIZE);
+
+        let wds = vk::WriteDescriptorSet::default()
+            .dst_set(self.set.inner)
+            .dst_array_element(handle)
+            .descriptor_count(1)
+            .descriptor_type(ty)
+            .dst_binding(binding)
+            .buffer_info(std::slice::from_ref(&buffer_info));
+
+        self.update(&[wds]);
+    }
+
+    pub fn create_combined_image_sampler_handle(
+        &self,
+        view: &ImageView,
+        sampler: &Sampler,
+        layout: vk::ImageLayout,
+    ) -> OctaResult<CombinedImageSamplerDescriptorHandle> {
+        let handle = self.new_handle_value(
+            &self.combined_image_sampler_allocator,
+            self.sizes.combined_image_samplers,
+            "combined image sampler",
+        )?;
+
+        let img_info = vk::DescriptorImageInfo::default()
+            .image_view(view.inner)
+            .sampler(sampler.inner)
+            .image_layout(layout);
+
+        let wds = vk::WriteDescriptorSet::default()
+            .dst_set(self.set.inner)
+            .dst_array_element(handle)
+            .descriptor_count(1)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .dst_binding(Self::COMBINED_IMAGE_SAMPLER_BINDING)
+            .image_info(std::slice::from_ref(&img_info));
+
+        self.update(&[wds]);
+        trace!("Creating Combined Image Sampler Handle {handle} with layout {layout:?}");
+
+        Ok(CombinedImageSamplerDescriptorHandle { value: handle, allocator: self.combined_image_sampler_allocator.clone() })
+    }
+
+    pub fn create_sampler_handle(&self, sampler: &Sampler) -> OctaResult<SamplerDescriptorHandle> {
+        let handle = self.new_handle_value(&self.sampler_allocator, self.sizes.samplers, "sampler")?;
+
+        let img_info = vk::DescriptorImageInfo::default()
+            .sampler(sampler.inner);
+
+        let wds = vk::WriteDescriptorSet::default()
+            .dst_set(self.set.inner)
+            .dst_array_element(handle)
+            .descriptor_count(1)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .dst_binding(Self::SAMPLER_BINDING)
+            .image_info(std::slice::from_ref(&img_info));
+
+        self.update(&[wds]);
+        trace!("Creating Sampler Handle {handle}");
+
+        Ok(SamplerDescriptorHandle { value: handle, allocator: self.sampler_allocator.clone() })
+    }
+
+    pub fn create_acceleration_structure_handle(
+        &self,
+        acceleration_structure: &AccelerationStructure,
+    ) -> OctaResult<AccelerationStructureDescriptorHandle> {
+        let handle = self.new_handle_value(
+            &self.acceleration_structure_allocator,
+            self.sizes.acceleration_structures,
+            "acceleration structure",
+        )?;
+
+        let mut write_set_as = vk::WriteDescriptorSetAccelerationStructureKHR::default()
+            .acceleration_structures(std::slice::from_ref(&acceleration_structure.inner));
+
+        let wds = vk::WriteDescriptorSet::default()
+            .dst_set(self.set.inner)
+            .dst_array_element(handle)
+            .descriptor_count(1)
+            .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+            .dst_binding(Self::ACCELERATION_STRUCTURE_BINDING)
+            .push_next(&mut write_set_as);
+
+        self.update(&[wds]);
+        trace!("Creating Acceleration Structure Handle {handle}");
+
+        Ok(AccelerationStructureDescriptorHandle { value: handle, allocator: self.acceleration_structure_allocator.clone() })
+    }
 }
 
 impl Context {
+    /// Heap with size sampled and size storage image descriptors.
     pub fn create_descriptor_heap(
         &self,
         size: usize,
-    ) -> OctaResult<ImageDescriptorHeap> {
-        ImageDescriptorHeap::new(self.device.clone(), size)
+    ) -> OctaResult<BindlessHeap> {
+        self.create_descriptor_heap_complex(BindlessHeapSizes::images(size as u32))
     }
-}
 
-impl Drop for ImageDescriptorHandle {
-    fn drop(&mut self) {
-        self.allocator.borrow_mut().return_id(self.value as usize)
-            .expect("Dropped DescriptorHandle with already retured value");
+    /// Checks the sizes against the update after bind features and limits of the device.
+    pub fn create_descriptor_heap_complex(
+        &self,
+        sizes: BindlessHeapSizes,
+    ) -> OctaResult<BindlessHeap> {
+        if sizes.acceleration_structures > 0 && self.ray_tracing.is_none() {
+            bail!("Descriptor Heap acceleration structure binding needs ray tracing or ray query to be enabled");
+        }
+
+        let enabled = |feature: &str| *self.physical_device.wanted_device_features.get(feature).unwrap_or(&false);
+        for feature in ["descriptorBindingPartiallyBound", "descriptorBindingUpdateUnusedWhilePending"]
+            .into_iter()
+            .chain(sizes.bindings().into_iter().map(|(_, _, _, feature)| feature))
+            .filter(|feature| !feature.is_empty())
+        {
+            if !enabled(feature) {
+                bail!("Descriptor Heap with sizes {sizes:?} needs the {feature} device feature");
+            }
+        }
+
+        let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut properties2 = vk::PhysicalDeviceProperties2::default().push_next(&mut indexing_properties);
+        unsafe {
+            self.instance
+                .inner
+                .get_physical_device_properties2(self.physical_device.inner, &mut properties2)
+        };
+
+        // Every binding is visible to all stages, so the per stage limits apply.
+        let limits = [
+            ("sampled images", sizes.sampled_images + sizes.combined_image_samplers,
+                indexing_properties.max_per_stage_descriptor_update_after_bind_sampled_images),
+            ("storage images", sizes.storage_images,
+                indexing_properties.max_per_stage_descriptor_update_after_bind_storage_images),
+            ("storage buffers", sizes.storage_buffers,
+                indexing_properties.max_per_stage_descriptor_update_after_bind_storage_buffers),
+            ("uniform buffers", sizes.uniform_buffers,
+                indexing_properties.max_per_stage_descriptor_update_after_bind_uniform_buffers),
+            ("samplers", sizes.samplers + sizes.combined_image_samplers,
+                indexing_properties.max_per_stage_descriptor_update_after_bind_samplers),
+            ("acceleration structures", sizes.acceleration_structures, self.ray_tracing
+                .as_ref()
+                .map_or(0, |rt| rt.acceleration_structure_properties.max_per_stage_descriptor_update_after_bind_acceleration_structures)),
+        ];
+        for (kind, count, limit) in limits {
+            if count > limit {
+                bail!("Descriptor Heap with {count} {kind} exceeds the device limit of {limit} per stage");
+            }
+        }
+
+        BindlessHeap::new(self.device.clone(), sizes, self.deletion_queue.clone())
     }
 }
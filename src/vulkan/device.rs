@@ -11,6 +11,18 @@ use crate::vulkan::physical_device::{PhysicalDevice, PhysicalDeviceFeatures};
 
 pub struct Device {
     pub inner: AshDevice,
+    pub mesh_shader_fn: Option<ash::ext::mesh_shader::Device>,
+    pub descriptor_buffer_fn: Option<ash::ext::descriptor_buffer::Device>,
+    pub push_descriptor_fn: Option<ash::khr::push_descriptor::Device>,
+    /// Indirect draws with a draw count above 1 need it.
+    pub multi_draw_indirect: bool,
+    /// Count buffer draws need the drawIndirectCount device feature, before vulkan 1.2 VK_KHR_draw_indirect_count.
+    pub draw_indirect_count: bool,
+    #[cfg(any(vulkan_1_0, vulkan_1_1))]
+    pub draw_indirect_count_fn: Option<ash::khr::draw_indirect_count::Device>,
+    /// Timeline semaphores are core since vulkan 1.2.
+    #[cfg(any(vulkan_1_0, vulkan_1_1))]
+    pub timeline_semaphore_fn: Option<ash::khr::timeline_semaphore::Device>,
 }
 
 impl Device {
@@ -22,9 +34,15 @@ impl Device {
     ) -> Result<Self> {
         let queue_priorities = [1.0f32];
         
-        let  queue_families = [physical_device.graphics_queue_family, physical_device.present_queue_family];
+        let queue_families = [
+            Some(physical_device.graphics_queue_family),
+            Some(physical_device.present_queue_family),
+            physical_device.compute_queue_family,
+            physical_device.transfer_queue_family,
+        ];
         let queue_create_infos = {
-            let mut indices = queue_families.iter().map(|f| f.index).collect::<Vec<_>>();
+            let mut indices = queue_families.iter().flatten().map(|f| f.index).collect::<Vec<_>>();
+            indices.sort();
             indices.dedup();
 
             indices
@@ -61,8 +79,35 @@ impl Device {
                 .create_device(physical_device.inner, &device_create_info, None)?
         };
 
+        let mesh_shader_fn = extensions.contains(&"VK_EXT_mesh_shader".to_owned())
+            .then(|| ash::ext::mesh_shader::Device::new(&instance.inner, &inner));
+        let descriptor_buffer_fn = extensions.contains(&"VK_EXT_descriptor_buffer".to_owned())
+            .then(|| ash::ext::descriptor_buffer::Device::new(&instance.inner, &inner));
+        let push_descriptor_fn = extensions.contains(&"VK_KHR_push_descriptor".to_owned())
+            .then(|| ash::khr::push_descriptor::Device::new(&instance.inner, &inner));
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        let timeline_semaphore_fn = extensions.contains(&"VK_KHR_timeline_semaphore".to_owned())
+            .then(|| ash::khr::timeline_semaphore::Device::new(&instance.inner, &inner));
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        let draw_indirect_count_fn = extensions.contains(&"VK_KHR_draw_indirect_count".to_owned())
+            .then(|| ash::khr::draw_indirect_count::Device::new(&instance.inner, &inner));
+
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        let draw_indirect_count = draw_indirect_count_fn.is_some();
+        #[cfg(any(vulkan_1_2, vulkan_1_3))]
+        let draw_indirect_count = device_features.contains(&"drawIndirectCount".to_owned());
+
         Ok(Self {
-            inner
+            inner,
+            mesh_shader_fn,
+            descriptor_buffer_fn,
+            push_descriptor_fn,
+            multi_draw_indirect: device_features.contains(&"multiDrawIndirect".to_owned()),
+            draw_indirect_count,
+            #[cfg(any(vulkan_1_0, vulkan_1_1))]
+            draw_indirect_count_fn,
+            #[cfg(any(vulkan_1_0, vulkan_1_1))]
+            timeline_semaphore_fn,
         })
     }
 
@@ -0,0 +1,130 @@
+use anyhow::{bail, Result};
+use ash::vk;
+use bytemuck::Pod;
+use gpu_allocator::MemoryLocation;
+use log::trace;
+
+use crate::in_flight_frames::InFlightFrames;
+use crate::vulkan::{Buffer, Context, WriteDescriptorSetKind};
+
+/// Host visible buffer split into one region per in flight frame.
+/// Allocations return aligned offsets that can be used as dynamic offsets.
+/// A region is reused once its in flight frame comes around again, which is after its fence signaled.
+#[derive(Debug)]
+pub struct FrameRingBuffer {
+    buffer: Buffer,
+    alignment: vk::DeviceSize,
+    frame_size: vk::DeviceSize,
+    in_flight_count: usize,
+    cursor: vk::DeviceSize,
+    in_flight_index: usize,
+    frame_number: u64,
+}
+
+impl Context {
+    /// Creates one region per frame in flight of in_flight_frames.
+    pub fn create_frame_ring_buffer(
+        &self,
+        frame_size: vk::DeviceSize,
+        in_flight_frames: &InFlightFrames,
+    ) -> Result<FrameRingBuffer> {
+        self.create_frame_ring_buffer_complex(vk::BufferUsageFlags::UNIFORM_BUFFER, frame_size, in_flight_frames)
+    }
+
+    pub fn create_frame_ring_buffer_complex(
+        &self,
+        usage: vk::BufferUsageFlags,
+        frame_size: vk::DeviceSize,
+        in_flight_frames: &InFlightFrames,
+    ) -> Result<FrameRingBuffer> {
+        let in_flight_count = in_flight_frames.num_frames_in_flight;
+        let limits = &self.physical_device.limits;
+        let mut alignment = 1;
+        if usage.contains(vk::BufferUsageFlags::UNIFORM_BUFFER) {
+            alignment = alignment.max(limits.min_uniform_buffer_offset_alignment);
+        }
+        if usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
+            alignment = alignment.max(limits.min_storage_buffer_offset_alignment);
+        }
+
+        let frame_size = frame_size.next_multiple_of(alignment);
+        let size = frame_size * in_flight_count as vk::DeviceSize;
+        if size > u32::MAX as vk::DeviceSize {
+            bail!("Frame ring buffer of size {size} is to big for u32 dynamic offsets.");
+        }
+
+        trace!("Creating frame ring buffer with {in_flight_count} regions of {frame_size} bytes and alignment {alignment}");
+        let buffer = self.create_buffer(usage, MemoryLocation::CpuToGpu, size)?;
+
+        Ok(FrameRingBuffer {
+            buffer,
+            alignment,
+            frame_size,
+            in_flight_count,
+            cursor: 0,
+            in_flight_index: 0,
+            frame_number: 0,
+        })
+    }
+}
+
+impl FrameRingBuffer {
+    /// Returns the offset into the whole buffer and the mapped memory of the allocation.
+    pub fn allocate(&mut self, in_flight_frames: &InFlightFrames, size: vk::DeviceSize) -> Result<(u32, &mut [u8])> {
+        if self.frame_number != in_flight_frames.frame_number {
+            if in_flight_frames.in_flight_index >= self.in_flight_count {
+                bail!(
+                    "Frame ring buffer has {} regions, but the in flight index is {}.",
+                    self.in_flight_count,
+                    in_flight_frames.in_flight_index
+                );
+            }
+
+            self.frame_number = in_flight_frames.frame_number;
+            self.in_flight_index = in_flight_frames.in_flight_index;
+            self.cursor = 0;
+        }
+
+        if self.cursor + size > self.frame_size {
+            bail!(
+                "Frame ring buffer region of size {} is full. Tried to allocate {size} bytes at {}.",
+                self.frame_size,
+                self.cursor
+            );
+        }
+
+        let offset = self.frame_size * self.in_flight_index as vk::DeviceSize + self.cursor;
+        self.cursor = (self.cursor + size).next_multiple_of(self.alignment);
+
+        let data = &mut self.buffer.get_mapped_bytes()[offset as usize..(offset + size) as usize];
+
+        Ok((offset as u32, data))
+    }
+
+    pub fn push<T: Pod>(&mut self, in_flight_frames: &InFlightFrames, data: &[T]) -> Result<u32> {
+        let src: &[u8] = bytemuck::cast_slice(data);
+        let (offset, dst) = self.allocate(in_flight_frames, src.len() as _)?;
+        dst.copy_from_slice(src);
+
+        Ok(offset)
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub fn alignment(&self) -> vk::DeviceSize {
+        self.alignment
+    }
+
+    pub fn frame_size(&self) -> vk::DeviceSize {
+        self.frame_size
+    }
+
+    pub fn uniform_descriptor_kind(&self, byte_stride: vk::DeviceSize) -> WriteDescriptorSetKind<'_> {
+        WriteDescriptorSetKind::UniformBufferDynamic {
+            buffer: &self.buffer,
+            byte_stride,
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::{collections::HashMap, fmt, sync::{Arc, Mutex}, time::Duration};
+
+use anyhow::Result;
+use ash::vk;
+
+use crate::{CommandBuffer, Device};
+
+const INITIAL_QUERY_CAPACITY: u32 = 64;
+
+/// Timing of one begin_gpu_scope/end_gpu_scope pair of a finished frame.
+#[derive(Debug, Clone)]
+pub struct GpuScope {
+    pub name: String,
+    /// Nesting level inside the command buffer the scope was recorded in.
+    pub depth: u32,
+    /// Relative to the earliest scope of the frame.
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Records named timestamp scopes into one query pool per frame in flight.
+/// The pool of a frame is read back the next time the frame is begun, after its fence was waited on,
+/// so reading the results never stalls. A pool that ran out of queries grows for the next use.
+/// Only the frame command buffer and the secondary command buffers of ThreadCommandPools begun during the frame
+/// record scopes, other command buffers could run before the pool reset of the frame.
+pub struct GpuProfiler {
+    device: Arc<Device>,
+    timestamp_period: f64,
+    /// Mask of the timestamp_valid_bits of the graphics queue family.
+    timestamp_mask: u64,
+    inner: Mutex<GpuProfilerInner>,
+}
+
+#[derive(Default)]
+struct GpuProfilerInner {
+    frames: Vec<GpuProfilerFrame>,
+    current_frame: Option<usize>,
+    /// Command buffers that are executed in the current frame after the pool reset.
+    frame_command_buffers: Vec<vk::CommandBuffer>,
+    /// Open scopes per vk command buffer, None for scopes that did not fit into the pool.
+    open_scopes: HashMap<vk::CommandBuffer, Vec<Option<usize>>>,
+    results: Vec<GpuScope>,
+}
+
+struct GpuProfilerFrame {
+    pool: vk::QueryPool,
+    capacity: u32,
+    query_count: u32,
+    /// Query count the frame would have needed, bigger than capacity when it overflowed.
+    wanted_query_count: u32,
+    scopes: Vec<RecordedScope>,
+}
+
+struct RecordedScope {
+    name: String,
+    depth: u32,
+    /// The end timestamp is written to begin_query + 1.
+    begin_query: u32,
+    ended: bool,
+}
+
+impl GpuProfiler {
+    pub(crate) fn new(device: Arc<Device>, timestamp_period: f64, timestamp_valid_bits: u32) -> Self {
+        let timestamp_mask = match timestamp_valid_bits {
+            64.. => u64::MAX,
+            bits => (1 << bits) - 1,
+        };
+
+        Self {
+            device,
+            timestamp_period,
+            timestamp_mask,
+            inner: Default::default(),
+        }
+    }
+
+    fn create_query_pool(&self, capacity: u32) -> Result<vk::QueryPool> {
+        let create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(capacity);
+
+        let pool = unsafe { self.device.inner.create_query_pool(&create_info, None)? };
+        Ok(pool)
+    }
+
+    /// Reads back the results of the last use of the in flight frame and records the reset of its pool.
+    /// The gpu has to be done with the last submit of the frame.
+    pub(crate) fn begin_frame(&self, cmd_buffer: &CommandBuffer, in_flight_index: usize) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        while inner.frames.len() <= in_flight_index {
+            let pool = self.create_query_pool(INITIAL_QUERY_CAPACITY)?;
+            inner.frames.push(GpuProfilerFrame {
+                pool,
+                capacity: INITIAL_QUERY_CAPACITY,
+                query_count: 0,
+                wanted_query_count: 0,
+                scopes: vec![],
+            });
+        }
+
+        if let Some(results) = self.read_results(&inner.frames[in_flight_index])? {
+            inner.results = results;
+        }
+
+        let frame = &mut inner.frames[in_flight_index];
+        if frame.wanted_query_count > frame.capacity {
+            let capacity = frame.wanted_query_count.next_power_of_two();
+            let pool = self.create_query_pool(capacity)?;
+            unsafe { self.device.inner.destroy_query_pool(frame.pool, None) };
+
+            frame.pool = pool;
+            frame.capacity = capacity;
+        }
+
+        frame.query_count = 0;
+        frame.wanted_query_count = 0;
+        frame.scopes.clear();
+
+        unsafe {
+            self.device
+                .inner
+                .cmd_reset_query_pool(cmd_buffer.inner, frame.pool, 0, frame.capacity);
+        }
+
+        inner.current_frame = Some(in_flight_index);
+        inner.frame_command_buffers.clear();
+        inner.frame_command_buffers.push(cmd_buffer.inner);
+        inner.open_scopes.clear();
+
+        Ok(())
+    }
+
+    /// Lets a secondary command buffer that is executed in the current frame record scopes.
+    pub(crate) fn add_frame_command_buffer(&self, cmd_buffer: &CommandBuffer) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.current_frame.is_some() {
+            inner.frame_command_buffers.push(cmd_buffer.inner);
+        }
+    }
+
+    /// None when the timestamps are not available yet.
+    fn read_results(&self, frame: &GpuProfilerFrame) -> Result<Option<Vec<GpuScope>>> {
+        if frame.query_count == 0 {
+            return Ok(Some(vec![]));
+        }
+
+        // Value and availability of each query, scopes that were never ended have no end timestamp.
+        let mut data = vec![[0u64; 2]; frame.query_count as usize];
+        let result = unsafe {
+            self.device.inner.get_query_pool_results(
+                frame.pool,
+                0,
+                &mut data,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+            )
+        };
+        match result {
+            Ok(()) | Err(vk::Result::NOT_READY) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let to_duration = |ticks: u64| {
+            Duration::from_nanos(((ticks & self.timestamp_mask) as f64 * self.timestamp_period) as u64)
+        };
+
+        for scope in frame.scopes.iter().filter(|scope| !scope.ended) {
+            log::warn!("GPU scope {} was never ended", scope.name);
+        }
+
+        let available = |query: u32| data[query as usize][1] != 0;
+        let scopes = frame.scopes
+            .iter()
+            .filter(|scope| scope.ended && available(scope.begin_query) && available(scope.begin_query + 1));
+        let Some(frame_start) = scopes.clone().map(|scope| data[scope.begin_query as usize][0]).min() else {
+            return Ok(None);
+        };
+
+        let mut results = scopes
+            .map(|scope| {
+                let begin = data[scope.begin_query as usize][0];
+                let end = data[scope.begin_query as usize + 1][0];
+
+                GpuScope {
+                    name: scope.name.clone(),
+                    depth: scope.depth,
+                    start: to_duration(begin.wrapping_sub(frame_start)),
+                    duration: to_duration(end.wrapping_sub(begin)),
+                }
+            })
+            .collect::<Vec<_>>();
+        results.sort_by_key(|scope| (scope.start, scope.depth));
+
+        Ok(Some(results))
+    }
+
+    pub(crate) fn begin_scope(&self, cmd_buffer: &CommandBuffer, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        let Some(frame_index) = inner.current_frame else {
+            return;
+        };
+        if !inner.frame_command_buffers.contains(&cmd_buffer.inner) {
+            return;
+        }
+        let frame = &mut inner.frames[frame_index];
+        let open_scopes = inner.open_scopes.entry(cmd_buffer.inner).or_default();
+
+        frame.wanted_query_count += 2;
+        if frame.query_count + 2 > frame.capacity {
+            open_scopes.push(None);
+            return;
+        }
+
+        let begin_query = frame.query_count;
+        frame.query_count += 2;
+        frame.scopes.push(RecordedScope {
+            name: name.to_owned(),
+            depth: open_scopes.len() as u32,
+            begin_query,
+            ended: false,
+        });
+        open_scopes.push(Some(frame.scopes.len() - 1));
+
+        cmd_buffer.write_timestamp_to_pool(vk::PipelineStageFlags2::NONE, frame.pool, begin_query);
+    }
+
+    pub(crate) fn end_scope(&self, cmd_buffer: &CommandBuffer) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        let Some(frame_index) = inner.current_frame else {
+            return;
+        };
+        if !inner.frame_command_buffers.contains(&cmd_buffer.inner) {
+            return;
+        }
+        let scope = inner.open_scopes
+            .get_mut(&cmd_buffer.inner)
+            .and_then(|open_scopes| open_scopes.pop())
+            .expect("CommandBuffer::end_gpu_scope called without a matching begin_gpu_scope");
+
+        let Some(scope_index) = scope else {
+            return;
+        };
+        let frame = &mut inner.frames[frame_index];
+        let scope = &mut frame.scopes[scope_index];
+        scope.ended = true;
+
+        cmd_buffer.write_timestamp_to_pool(vk::PipelineStageFlags2::ALL_COMMANDS, frame.pool, scope.begin_query + 1);
+    }
+
+    /// Scopes of the last frame with available results, ordered by start.
+    pub fn results(&self) -> Vec<GpuScope> {
+        self.inner.lock().unwrap().results.clone()
+    }
+}
+
+impl fmt::Debug for GpuProfiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GpuProfiler")
+            .field("timestamp_period", &self.timestamp_period)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for GpuProfiler {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut().unwrap();
+        for frame in inner.frames.iter() {
+            unsafe { self.device.inner.destroy_query_pool(frame.pool, None) };
+        }
+    }
+}
@@ -23,6 +23,7 @@ pub struct Image {
     allocation: Option<Allocation>,
     pub format: vk::Format,
     pub size: UVec3,
+    pub mip_levels: u32,
     is_swapchain: bool, // if set, image should not be destroyed
 }
 
@@ -40,8 +41,9 @@ impl Image {
         memory_location: MemoryLocation,
         format: vk::Format,
         size: UVec2,
+        mip_levels: u32,
     ) -> Result<Self> {
-        trace!("Creating Image: {size} with usage flags {usage:?} at memory location {memory_location:?}");
+        trace!("Creating Image: {size} with {mip_levels} mip levels, usage flags {usage:?} at memory location {memory_location:?}");
 
         let size = UVec3::from((size, 1));
         
@@ -49,7 +51,7 @@ impl Image {
             .image_type(vk::ImageType::TYPE_2D)
             .format(format)
             .extent(uvec3_to_extend3d(size))
-            .mip_levels(1)
+            .mip_levels(mip_levels)
             .array_layers(1)
             .samples(vk::SampleCountFlags::TYPE_1)
             .tiling(vk::ImageTiling::OPTIMAL)
@@ -80,6 +82,7 @@ impl Image {
             allocation: Some(allocation),
             format,
             size,
+            mip_levels,
             is_swapchain: false,
         })
     }
@@ -130,6 +133,7 @@ impl Image {
             allocation: Some(allocation),
             format,
             size,
+            mip_levels: 1,
             is_swapchain: false,
         })
     }
@@ -151,6 +155,7 @@ impl Image {
             allocation: None,
             format,
             size,
+            mip_levels: 1,
             is_swapchain: true,
         }
     }
@@ -169,7 +174,7 @@ impl Image {
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask,
                 base_mip_level: 0,
-                level_count: 1,
+                level_count: self.mip_levels,
                 base_array_layer: 0,
                 layer_count: 1,
             });
@@ -216,6 +221,17 @@ impl Context {
         memory_location: MemoryLocation,
         format: vk::Format,
         size: UVec2,
+    ) -> Result<Image> {
+        self.create_image_complex(usage, memory_location, format, size, 1)
+    }
+
+    pub fn create_image_complex(
+        &self,
+        usage: vk::ImageUsageFlags,
+        memory_location: MemoryLocation,
+        format: vk::Format,
+        size: UVec2,
+        mip_levels: u32,
     ) -> Result<Image> {
         Image::new_2d(
             self.device.clone(),
@@ -224,6 +240,7 @@ impl Context {
             memory_location,
             format,
             size,
+            mip_levels,
         )
     }
 }
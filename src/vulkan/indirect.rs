@@ -0,0 +1,271 @@
+use std::mem::size_of;
+
+use anyhow::Result;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use gpu_allocator::MemoryLocation;
+
+use crate::vulkan::{Buffer, CommandBuffer, Context, TypedBuffer};
+
+/// Same layout as VkDrawIndirectCommand.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+pub struct DrawIndirectCommand {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub first_vertex: u32,
+    pub first_instance: u32,
+}
+
+/// Same layout as VkDrawIndexedIndirectCommand.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+pub struct DrawIndexedIndirectCommand {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    pub vertex_offset: i32,
+    pub first_instance: u32,
+}
+
+/// Same layout as VkDispatchIndirectCommand.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+pub struct DispatchIndirectCommand {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+/// Same layout as VkDrawMeshTasksIndirectCommandEXT.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
+pub struct DrawMeshTasksIndirectCommand {
+    pub group_count_x: u32,
+    pub group_count_y: u32,
+    pub group_count_z: u32,
+}
+
+impl Context {
+    /// The buffer can also be written by shaders, for example for gpu culling.
+    pub fn create_indirect_buffer<T: Pod>(&self, memory_location: MemoryLocation, len: usize) -> Result<TypedBuffer<T>> {
+        self.create_typed_buffer(
+            vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            memory_location,
+            len,
+        )
+    }
+}
+
+impl CommandBuffer {
+    /// Draws all commands of the buffer, more than one needs the multiDrawIndirect device feature.
+    pub fn draw_indirect(&self, commands: &TypedBuffer<DrawIndirectCommand>) {
+        self.draw_indirect_complex(commands.buffer(), 0, commands.len() as _, size_of::<DrawIndirectCommand>() as _);
+    }
+
+    /// Needs the multiDrawIndirect device feature for draw_count > 1.
+    pub fn draw_indirect_complex(&self, buffer: &Buffer, offset: vk::DeviceSize, draw_count: u32, stride: u32) {
+        self.check_draw_count("draw_indirect", draw_count);
+        unsafe {
+            self.device
+                .inner
+                .cmd_draw_indirect(self.inner, buffer.inner, offset, draw_count, stride)
+        };
+    }
+
+    /// Draws all commands of the buffer, more than one needs the multiDrawIndirect device feature.
+    pub fn draw_indexed_indirect(&self, commands: &TypedBuffer<DrawIndexedIndirectCommand>) {
+        self.draw_indexed_indirect_complex(commands.buffer(), 0, commands.len() as _, size_of::<DrawIndexedIndirectCommand>() as _);
+    }
+
+    /// Needs the multiDrawIndirect device feature for draw_count > 1.
+    pub fn draw_indexed_indirect_complex(&self, buffer: &Buffer, offset: vk::DeviceSize, draw_count: u32, stride: u32) {
+        self.check_draw_count("draw_indexed_indirect", draw_count);
+        unsafe {
+            self.device
+                .inner
+                .cmd_draw_indexed_indirect(self.inner, buffer.inner, offset, draw_count, stride)
+        };
+    }
+
+    /// The draw count is read from the first u32 of count_buffer. Needs the drawIndirectCount device feature, before vulkan 1.2 VK_KHR_draw_indirect_count.
+    pub fn draw_indirect_count(&self, commands: &TypedBuffer<DrawIndirectCommand>, count_buffer: &Buffer) {
+        self.draw_indirect_count_complex(
+            commands.buffer(),
+            0,
+            count_buffer,
+            0,
+            commands.len() as _,
+            size_of::<DrawIndirectCommand>() as _,
+        );
+    }
+
+    pub fn draw_indirect_count_complex(
+        &self,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+        count_buffer: &Buffer,
+        count_buffer_offset: vk::DeviceSize,
+        max_draw_count: u32,
+        stride: u32,
+    ) {
+        self.check_draw_indirect_count("draw_indirect_count");
+        unsafe {
+            // Checked above
+            #[cfg(any(vulkan_1_0, vulkan_1_1))]
+            self.device.draw_indirect_count_fn.as_ref().unwrap().cmd_draw_indirect_count(
+                self.inner,
+                buffer.inner,
+                offset,
+                count_buffer.inner,
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            );
+
+            #[cfg(any(vulkan_1_2, vulkan_1_3))]
+            self.device.inner.cmd_draw_indirect_count(
+                self.inner,
+                buffer.inner,
+                offset,
+                count_buffer.inner,
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            )
+        };
+    }
+
+    pub fn draw_indexed_indirect_count(&self, commands: &TypedBuffer<DrawIndexedIndirectCommand>, count_buffer: &Buffer) {
+        self.draw_indexed_indirect_count_complex(
+            commands.buffer(),
+            0,
+            count_buffer,
+            0,
+            commands.len() as _,
+            size_of::<DrawIndexedIndirectCommand>() as _,
+        );
+    }
+
+    pub fn draw_indexed_indirect_count_complex(
+        &self,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+        count_buffer: &Buffer,
+        count_buffer_offset: vk::DeviceSize,
+        max_draw_count: u32,
+        stride: u32,
+    ) {
+        self.check_draw_indirect_count("draw_indexed_indirect_count");
+        unsafe {
+            // Checked above
+            #[cfg(any(vulkan_1_0, vulkan_1_1))]
+            self.device.draw_indirect_count_fn.as_ref().unwrap().cmd_draw_indexed_indirect_count(
+                self.inner,
+                buffer.inner,
+                offset,
+                count_buffer.inner,
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            );
+
+            #[cfg(any(vulkan_1_2, vulkan_1_3))]
+            self.device.inner.cmd_draw_indexed_indirect_count(
+                self.inner,
+                buffer.inner,
+                offset,
+                count_buffer.inner,
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            )
+        };
+    }
+
+    pub fn dispatch_indirect(&self, command: &TypedBuffer<DispatchIndirectCommand>) {
+        self.dispatch_indirect_complex(command.buffer(), 0);
+    }
+
+    pub fn dispatch_indirect_complex(&self, buffer: &Buffer, offset: vk::DeviceSize) {
+        unsafe {
+            self.device
+                .inner
+                .cmd_dispatch_indirect(self.inner, buffer.inner, offset)
+        };
+    }
+
+    pub fn draw_mesh_tasks(&self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+        unsafe {
+            self.mesh_shader_fn("draw_mesh_tasks")
+                .cmd_draw_mesh_tasks(self.inner, group_count_x, group_count_y, group_count_z)
+        };
+    }
+
+    pub fn draw_mesh_tasks_indirect(&self, commands: &TypedBuffer<DrawMeshTasksIndirectCommand>) {
+        self.draw_mesh_tasks_indirect_complex(commands.buffer(), 0, commands.len() as _, size_of::<DrawMeshTasksIndirectCommand>() as _);
+    }
+
+    pub fn draw_mesh_tasks_indirect_complex(&self, buffer: &Buffer, offset: vk::DeviceSize, draw_count: u32, stride: u32) {
+        self.check_draw_count("draw_mesh_tasks_indirect", draw_count);
+        unsafe {
+            self.mesh_shader_fn("draw_mesh_tasks_indirect")
+                .cmd_draw_mesh_tasks_indirect(self.inner, buffer.inner, offset, draw_count, stride)
+        };
+    }
+
+    pub fn draw_mesh_tasks_indirect_count(&self, commands: &TypedBuffer<DrawMeshTasksIndirectCommand>, count_buffer: &Buffer) {
+        self.draw_mesh_tasks_indirect_count_complex(
+            commands.buffer(),
+            0,
+            count_buffer,
+            0,
+            commands.len() as _,
+            size_of::<DrawMeshTasksIndirectCommand>() as _,
+        );
+    }
+
+    pub fn draw_mesh_tasks_indirect_count_complex(
+        &self,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+        count_buffer: &Buffer,
+        count_buffer_offset: vk::DeviceSize,
+        max_draw_count: u32,
+        stride: u32,
+    ) {
+        unsafe {
+            self.mesh_shader_fn("draw_mesh_tasks_indirect_count").cmd_draw_mesh_tasks_indirect_count(
+                self.inner,
+                buffer.inner,
+                offset,
+                count_buffer.inner,
+                count_buffer_offset,
+                max_draw_count,
+                stride,
+            )
+        };
+    }
+
+    fn check_draw_count(&self, name: &str, draw_count: u32) {
+        assert!(
+            draw_count <= 1 || self.device.multi_draw_indirect,
+            "Cannot call CommandBuffer::{name} with {draw_count} draws when multiDrawIndirect is not enabled"
+        );
+    }
+
+    fn check_draw_indirect_count(&self, name: &str) {
+        assert!(
+            self.device.draw_indirect_count,
+            "Cannot call CommandBuffer::{name} when drawIndirectCount is not enabled"
+        );
+    }
+
+    fn mesh_shader_fn(&self, name: &str) -> &ash::ext::mesh_shader::Device {
+        self.device.mesh_shader_fn.as_ref().unwrap_or_else(|| {
+            panic!("Cannot call CommandBuffer::{name} when mesh shading is not enabled")
+        })
+    }
+}
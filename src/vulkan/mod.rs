@@ -7,10 +7,15 @@ mod align;
 mod buffer;
 mod command;
 mod context;
+mod deletion_queue;
 mod descriptor;
+mod descriptor_buffer;
+mod frame_ring_buffer;
+mod gpu_profiler;
 pub mod descriptor_heap;
 mod device;
 mod image;
+mod indirect;
 mod instance;
 pub mod physical_device;
 mod pipeline;
@@ -21,7 +26,11 @@ mod sampler;
 pub mod sampler_pool;
 mod surface;
 mod swapchain;
+mod staging_belt;
 mod sync;
+mod texture;
+mod thread_command_pool;
+mod typed_buffer;
 
 pub mod push_constant;
 pub mod utils;
@@ -30,16 +39,25 @@ use std::fmt::{Debug, Formatter};
 pub use buffer::*;
 pub use command::*;
 pub use context::*;
+pub use deletion_queue::*;
 pub use descriptor::*;
+pub use descriptor_buffer::*;
+pub use frame_ring_buffer::*;
+pub use gpu_profiler::*;
 pub use device::*;
 pub use image::*;
+pub use indirect::*;
 pub use pipeline::*;
 pub use query::*;
 pub use queue::*;
 pub use ray_tracing::*;
 pub use sampler::*;
 pub use swapchain::*;
+pub use staging_belt::*;
 pub use sync::*;
+pub use texture::*;
+pub use thread_command_pool::*;
+pub use typed_buffer::*;
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum Version {
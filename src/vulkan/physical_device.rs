@@ -4,7 +4,7 @@ use convert_case::{Case, Casing};
 
 use anyhow::{bail, Result};
 use ash::vk;
-use ash::vk::{Format, FormatFeatureFlags, PhysicalDevice8BitStorageFeatures, PhysicalDeviceAccelerationStructureFeaturesKHR, PhysicalDeviceFeatures2, PhysicalDeviceRayTracingPipelineFeaturesKHR, PhysicalDeviceShaderClockFeaturesKHR, PhysicalDeviceType, PhysicalDeviceVulkan11Features, PhysicalDeviceVulkan12Features, PhysicalDeviceVulkan13Features, PresentModeKHR, SurfaceFormatKHR};
+use ash::vk::{Format, FormatFeatureFlags, PhysicalDevice8BitStorageFeatures, PhysicalDeviceAccelerationStructureFeaturesKHR, PhysicalDeviceMeshShaderFeaturesEXT, PhysicalDeviceDescriptorBufferFeaturesEXT, PhysicalDeviceRayQueryFeaturesKHR, PhysicalDeviceFeatures2, PhysicalDeviceRayTracingPipelineFeaturesKHR, PhysicalDeviceShaderClockFeaturesKHR, PhysicalDeviceType, PhysicalDeviceVulkan11Features, PhysicalDeviceVulkan12Features, PhysicalDeviceVulkan13Features, PresentModeKHR, SurfaceFormatKHR};
 use log::error;
 use crate::{vulkan::queue::QueueFamily, vulkan::surface::Surface};
 use crate::vulkan::instance::Instance;
@@ -15,10 +15,13 @@ pub struct PhysicalDevice {
     pub name: String,
     pub device_type: vk::PhysicalDeviceType,
 
+    pub properties: vk::PhysicalDeviceProperties,
     pub limits: vk::PhysicalDeviceLimits,
 
     pub graphics_queue_family: QueueFamily,
     pub present_queue_family: QueueFamily,
+    pub compute_queue_family: Option<QueueFamily>,
+    pub transfer_queue_family: Option<QueueFamily>,
 
     pub wanted_extensions: HashMap<String, bool>,
 
@@ -42,12 +45,15 @@ pub struct PhysicalDeviceCapabilities {
     pub name: String,
     pub device_type: vk::PhysicalDeviceType,
 
+    pub properties: vk::PhysicalDeviceProperties,
     pub limits: vk::PhysicalDeviceLimits,
     pub limits_ok: bool,
 
     pub queues: Vec<QueueFamily>,
     pub graphics_queues: Vec<QueueFamily>,
     pub present_queues: Vec<QueueFamily>,
+    pub compute_queues: Vec<QueueFamily>,
+    pub transfer_queues: Vec<QueueFamily>,
 
     pub required_extensions: HashMap<String, bool>,
     pub required_extensions_ok: bool,
@@ -230,6 +236,11 @@ impl Instance {
         };
         log::info!(" -- Render storage image format: {:?}", render_storage_image_format);
 
+        let compute_queue_family = selected_device_capabilities.compute_queues.first().copied();
+        let transfer_queue_family = selected_device_capabilities.transfer_queues.first().copied();
+        log::info!(" -- Async compute queue: {}", compute_queue_family.map(|f| f.index.to_string()).unwrap_or("❌".to_string()));
+        log::info!(" -- Async transfer queue: {}", transfer_queue_family.map(|f| f.index.to_string()).unwrap_or("❌".to_string()));
+
         let depth_format = selected_device_capabilities.supported_depth_formats[0];
         log::info!(" -- Depth format: {:?} ", depth_format);
         
@@ -252,9 +263,12 @@ impl Instance {
             inner: selected_device_capabilities.inner,
             name: selected_device_capabilities.name.to_owned(),
             device_type,
+            properties: selected_device_capabilities.properties,
             limits: selected_device_capabilities.limits,
             graphics_queue_family: selected_device_capabilities.graphics_queues[0],
             present_queue_family: selected_device_capabilities.present_queues[0],
+            compute_queue_family,
+            transfer_queue_family,
             wanted_extensions,
             surface_format,
             render_storage_image_format,
@@ -346,6 +360,8 @@ impl PhysicalDeviceCapabilities {
         // Choose Graphics and Present Queue
         let mut graphics = vec![];
         let mut present = vec![];
+        let mut compute = vec![];
+        let mut transfer = vec![];
         for family in queue_families.iter().filter(|f| f.has_queues()) {
             if family.supports_graphics()
                 && family.supports_compute()
@@ -357,6 +373,15 @@ impl PhysicalDeviceCapabilities {
             if family.supports_present() {
                 present.push(*family);
             }
+
+            // Async queues only make sense on families distinct from the graphics family
+            if family.supports_compute() && !family.supports_graphics() {
+                compute.push(*family);
+            }
+
+            if family.supports_transfer() && !family.supports_graphics() && !family.supports_compute() {
+                transfer.push(*family);
+            }
         }
 
         // Extensions
@@ -473,7 +498,7 @@ impl PhysicalDeviceCapabilities {
         let (required_device_features_ok, required_device_features) = res_required_features.get_mask_result(&required_features);
 
         let wanted_features = PhysicalDeviceFeatures::new(wanted_device_features);
-        let mut res_wanted_features = required_features.to_owned();
+        let mut res_wanted_features = wanted_features.to_owned();
         unsafe { instance.inner.get_physical_device_features2(inner, &mut res_wanted_features.vulkan_features()) };
         let (wanted_device_features_ok, wanted_device_features) = res_wanted_features.get_mask_result(&wanted_features);
 
@@ -482,12 +507,15 @@ impl PhysicalDeviceCapabilities {
             name,
             device_type,
 
+            properties: props,
             limits,
             limits_ok,
 
             queues: queue_families,
             graphics_queues: graphics, 
             present_queues: present,
+            compute_queues: compute,
+            transfer_queues: transfer,
 
             required_extensions,
             required_extensions_ok,
@@ -521,7 +549,10 @@ pub struct PhysicalDeviceFeatures<'a> {
     pub features12: PhysicalDeviceVulkan12Features<'a>,
     pub features13: PhysicalDeviceVulkan13Features<'a>,
     pub clock_feature: PhysicalDeviceShaderClockFeaturesKHR<'a>,
-    pub storage8_features: PhysicalDevice8BitStorageFeatures<'a>
+    pub storage8_features: PhysicalDevice8BitStorageFeatures<'a>,
+    pub mesh_shader_features: PhysicalDeviceMeshShaderFeaturesEXT<'a>,
+    pub descriptor_buffer_features: PhysicalDeviceDescriptorBufferFeaturesEXT<'a>,
+    pub ray_query_features: PhysicalDeviceRayQueryFeaturesKHR<'a>,
 }
 
 #[macro_export]
@@ -699,6 +730,31 @@ impl<'a> PhysicalDeviceFeatures<'a> {
             res = res.push_next(&mut self.storage8_features);
         }
 
+        if any_used!(self, mesh_shader_features,
+    pub task_shader: Bool32,
+    pub mesh_shader: Bool32,
+    pub multiview_mesh_shader: Bool32,
+    pub primitive_fragment_shading_rate_mesh_shader: Bool32,
+    pub mesh_shader_queries: Bool32,
+        ) {
+            res = res.push_next(&mut self.mesh_shader_features);
+        }
+
+        if any_used!(self, descriptor_buffer_features,
+    pub descriptor_buffer: Bool32,
+    pub descriptor_buffer_capture_replay: Bool32,
+    pub descriptor_buffer_image_layout_ignored: Bool32,
+    pub descriptor_buffer_push_descriptors: Bool32,
+        ) {
+            res = res.push_next(&mut self.descriptor_buffer_features);
+        }
+
+        if any_used!(self, ray_query_features,
+    pub ray_query: Bool32,
+        ) {
+            res = res.push_next(&mut self.ray_query_features);
+        }
+
         res
     }
 
@@ -860,6 +916,19 @@ impl<'a> PhysicalDeviceFeatures<'a> {
     pub storage_buffer8_bit_access: Bool32,
     pub uniform_and_storage_buffer8_bit_access: Bool32,
     pub storage_push_constant8: Bool32,
+            :mesh_shader_features,
+    pub task_shader: Bool32,
+    pub mesh_shader: Bool32,
+    pub multiview_mesh_shader: Bool32,
+    pub primitive_fragment_shading_rate_mesh_shader: Bool32,
+    pub mesh_shader_queries: Bool32,
+            :descriptor_buffer_features,
+    pub descriptor_buffer: Bool32,
+    pub descriptor_buffer_capture_replay: Bool32,
+    pub descriptor_buffer_image_layout_ignored: Bool32,
+    pub descriptor_buffer_push_descriptors: Bool32,
+            :ray_query_features,
+    pub ray_query: Bool32,
 );  
         if !set.is_empty() {
             error!("Device Feature Check: {:?}, not known!", set);
@@ -1023,6 +1092,19 @@ impl<'a> PhysicalDeviceFeatures<'a> {
     pub storage_buffer8_bit_access: Bool32,
     pub uniform_and_storage_buffer8_bit_access: Bool32,
     pub storage_push_constant8: Bool32,
+            :mesh_shader_features,
+    pub task_shader: Bool32,
+    pub mesh_shader: Bool32,
+    pub multiview_mesh_shader: Bool32,
+    pub primitive_fragment_shading_rate_mesh_shader: Bool32,
+    pub mesh_shader_queries: Bool32,
+            :descriptor_buffer_features,
+    pub descriptor_buffer: Bool32,
+    pub descriptor_buffer_capture_replay: Bool32,
+    pub descriptor_buffer_image_layout_ignored: Bool32,
+    pub descriptor_buffer_push_descriptors: Bool32,
+            :ray_query_features,
+    pub ray_query: Bool32,
 )
     }
 }
@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use ash::vk;
+use log::{debug, error, info};
+
+use crate::vulkan::device::Device;
+use crate::vulkan::physical_device::PhysicalDevice;
+
+const MAGIC: &[u8; 4] = b"OFPC";
+const HEADER_SIZE: usize = 4 + 4 + 4 + 4 + vk::UUID_SIZE;
+
+/// Pipeline cache that is loaded from and saved to a file.
+/// The file is only used if it was written by the same device and driver version.
+#[derive(Debug)]
+pub struct PipelineCache {
+    device: Arc<Device>,
+    pub(crate) inner: vk::PipelineCache,
+    path: Option<PathBuf>,
+    header: Vec<u8>,
+}
+
+impl PipelineCache {
+    pub(crate) fn new(device: Arc<Device>, physical_device: &PhysicalDevice, path: Option<PathBuf>) -> Result<Self> {
+        let props = &physical_device.properties;
+        let header = [
+            MAGIC.as_slice(),
+            &props.vendor_id.to_le_bytes(),
+            &props.device_id.to_le_bytes(),
+            &props.driver_version.to_le_bytes(),
+            &props.pipeline_cache_uuid,
+        ].concat();
+        debug_assert_eq!(header.len(), HEADER_SIZE);
+
+        let initial_data = path.as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| {
+                if data.len() >= HEADER_SIZE && data[..HEADER_SIZE] == header[..] {
+                    Some(data[HEADER_SIZE..].to_vec())
+                } else {
+                    info!("Discarding pipeline cache of a different device or driver version");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        debug!("Loaded pipeline cache with {} bytes", initial_data.len());
+
+        let create_info = vk::PipelineCacheCreateInfo::default().initial_data(&initial_data);
+        let inner = unsafe { device.inner.create_pipeline_cache(&create_info, None)? };
+
+        Ok(Self {
+            device,
+            inner,
+            path,
+            header,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+
+        let data = unsafe { self.device.inner.get_pipeline_cache_data(self.inner)? };
+        // Write to a temporary file first so a crash while saving can't leave a truncated cache
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, [self.header.as_slice(), &data].concat())?;
+        fs::rename(&temp_path, path)?;
+        debug!("Saved pipeline cache with {} bytes to {path:?}", data.len());
+
+        Ok(())
+    }
+}
+
+impl Drop for PipelineCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            error!("Failed to save pipeline cache: {err}");
+        }
+
+        unsafe { self.device.inner.destroy_pipeline_cache(self.inner, None) };
+    }
+}
@@ -0,0 +1,347 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use ash::vk;
+use log::warn;
+use shaderc::{
+    CompileOptions, Compiler, EnvVersion, IncludeType, OptimizationLevel, ResolvedInclude, ShaderKind,
+    SourceLanguage, SpirvVersion, TargetEnv,
+};
+
+use crate::{Context, ShaderModule};
+
+/// Vulkan and SPIR-V version shaderc targets, matching the vulkan version the engine is built for.
+#[cfg(vulkan_1_0)]
+const TARGET_VERSIONS: (EnvVersion, SpirvVersion) = (EnvVersion::Vulkan1_0, SpirvVersion::V1_0);
+#[cfg(vulkan_1_1)]
+const TARGET_VERSIONS: (EnvVersion, SpirvVersion) = (EnvVersion::Vulkan1_1, SpirvVersion::V1_3);
+#[cfg(vulkan_1_2)]
+const TARGET_VERSIONS: (EnvVersion, SpirvVersion) = (EnvVersion::Vulkan1_2, SpirvVersion::V1_5);
+// shaderc 0.7 has no Vulkan 1.3 or SPIR-V 1.6 target, 1.2 with SPIR-V 1.5 is valid on 1.3 devices.
+#[cfg(vulkan_1_3)]
+const TARGET_VERSIONS: (EnvVersion, SpirvVersion) = (EnvVersion::Vulkan1_2, SpirvVersion::V1_5);
+
+/// Options for compiling GLSL, HLSL or Slang to SPIR-V.
+#[derive(Debug, Clone, Default)]
+pub struct ShaderCompileOptions {
+    /// Searched for all includes. Includes with quotes are looked up next to the including file first.
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: Vec<(String, Option<String>)>,
+    /// None uses "main".
+    pub entry_point: Option<String>,
+    pub optimize: bool,
+    pub debug_info: bool,
+    /// Defines DEBUG_PRINTING, for GLSL also enables GL_EXT_debug_printf.
+    pub debug_printing: bool,
+    /// Defines SCALAR_BLOCK_LAYOUT, for GLSL also enables GL_EXT_scalar_block_layout and for Slang the scalar layout.
+    /// GLSL blocks still have to opt in with layout(scalar).
+    pub scalar_block_layout: bool,
+    /// Defines SHADER_CLOCK, for GLSL also enables GL_EXT_shader_realtime_clock.
+    pub shader_clock: bool,
+}
+
+impl ShaderCompileOptions {
+    fn all_defines(&self) -> Vec<(String, Option<String>)> {
+        [
+            (self.debug_printing, "DEBUG_PRINTING"),
+            (self.scalar_block_layout, "SCALAR_BLOCK_LAYOUT"),
+            (self.shader_clock, "SHADER_CLOCK"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| (name.to_owned(), None))
+        .chain(self.defines.iter().cloned())
+        .collect()
+    }
+
+    fn glsl_extensions(&self) -> Vec<&'static str> {
+        [
+            (self.debug_printing, "GL_EXT_debug_printf"),
+            (self.scalar_block_layout, "GL_EXT_scalar_block_layout"),
+            (self.shader_clock, "GL_EXT_shader_realtime_clock"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| name)
+        .collect()
+    }
+
+    fn entry_point(&self) -> &str {
+        self.entry_point.as_deref().unwrap_or("main")
+    }
+}
+
+impl Context {
+    /// Compile options with the shader features enabled on this device.
+    pub fn shader_compile_options(&self) -> ShaderCompileOptions {
+        ShaderCompileOptions {
+            optimize: !cfg!(debug_assertions),
+            debug_info: cfg!(debug_assertions),
+            debug_printing: self.debug_printing,
+            scalar_block_layout: self.scalar_block_layout,
+            shader_clock: self.shader_clock,
+            ..Default::default()
+        }
+    }
+
+    /// Returns SPIR-V that can be used as source in the pipeline create infos.
+    pub fn compile_shader_from_source(
+        &self,
+        path: impl AsRef<Path>,
+        stage: vk::ShaderStageFlags,
+        defines: &[(&str, Option<&str>)],
+    ) -> Result<Vec<u8>> {
+        let mut options = self.shader_compile_options();
+        options.defines.extend(defines.iter().map(|(name, value)| (name.to_string(), value.map(str::to_owned))));
+
+        compile_shader(path.as_ref(), stage, &options)
+    }
+
+    /// Compiles GLSL, HLSL (.hlsl) or Slang (.slang). Slang needs slangc in the PATH.
+    pub fn create_shader_module_from_source(
+        &self,
+        path: impl AsRef<Path>,
+        stage: vk::ShaderStageFlags,
+        defines: &[(&str, Option<&str>)],
+    ) -> Result<ShaderModule> {
+        let spirv = self.compile_shader_from_source(path, stage, defines)?;
+        self.create_shader_module(&spirv)
+    }
+}
+
+/// Compiles GLSL, HLSL (.hlsl) or Slang (.slang) to SPIR-V. Slang needs slangc in the PATH.
+/// Errors point to the file and line, also inside of includes.
+pub fn compile_shader(path: &Path, stage: vk::ShaderStageFlags, options: &ShaderCompileOptions) -> Result<Vec<u8>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("slang") => compile_slang(path, stage, options),
+        Some("hlsl") => compile_shaderc(path, stage, SourceLanguage::HLSL, options),
+        _ => compile_shaderc(path, stage, SourceLanguage::GLSL, options),
+    }
+}
+
+/// For build scripts. Compiles every shader in src_dir with a stage extension (like .vert or .comp.hlsl)
+/// to out_dir/<file name>.spv, so release builds don't need a shader compiler at runtime.
+pub fn compile_shader_dir(src_dir: impl AsRef<Path>, out_dir: impl AsRef<Path>, options: &ShaderCompileOptions) -> Result<()> {
+    let src_dir = src_dir.as_ref();
+    let out_dir = out_dir.as_ref();
+    println!("cargo:rerun-if-changed={}", src_dir.display());
+
+    let mut options = options.to_owned();
+    options.include_dirs.push(src_dir.to_owned());
+
+    fs::create_dir_all(out_dir)?;
+    for entry in fs::read_dir(src_dir)? {
+        let path = entry?.path();
+        let Some(stage) = shader_stage_from_path(&path) else {
+            continue;
+        };
+
+        let spirv = compile_shader(&path, stage, &options)?;
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        fs::write(out_dir.join(format!("{file_name}.spv")), spirv)?;
+    }
+
+    Ok(())
+}
+
+/// Stage from the glslc file extensions. For HLSL and Slang the extension before, like shader.frag.hlsl.
+pub fn shader_stage_from_path(path: &Path) -> Option<vk::ShaderStageFlags> {
+    let mut extension = path.extension()?.to_str()?;
+    if matches!(extension, "glsl" | "hlsl" | "slang") {
+        extension = Path::new(path.file_stem()?).extension()?.to_str()?;
+    }
+
+    Some(match extension {
+        "vert" => vk::ShaderStageFlags::VERTEX,
+        "frag" => vk::ShaderStageFlags::FRAGMENT,
+        "comp" => vk::ShaderStageFlags::COMPUTE,
+        "geom" => vk::ShaderStageFlags::GEOMETRY,
+        "tesc" => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        "tese" => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        "task" => vk::ShaderStageFlags::TASK_EXT,
+        "mesh" => vk::ShaderStageFlags::MESH_EXT,
+        "rgen" => vk::ShaderStageFlags::RAYGEN_KHR,
+        "rmiss" => vk::ShaderStageFlags::MISS_KHR,
+        "rchit" => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        "rahit" => vk::ShaderStageFlags::ANY_HIT_KHR,
+        "rint" => vk::ShaderStageFlags::INTERSECTION_KHR,
+        "rcall" => vk::ShaderStageFlags::CALLABLE_KHR,
+        _ => return None,
+    })
+}
+
+fn compile_shaderc(
+    path: &Path,
+    stage: vk::ShaderStageFlags,
+    language: SourceLanguage,
+    options: &ShaderCompileOptions,
+) -> Result<Vec<u8>> {
+    let mut source = fs::read_to_string(path).with_context(|| format!("Failed to read shader {path:?}"))?;
+    if language == SourceLanguage::GLSL {
+        source = inject_glsl_extensions(&source, &options.glsl_extensions());
+    }
+
+    let kind = match stage {
+        vk::ShaderStageFlags::VERTEX => ShaderKind::Vertex,
+        vk::ShaderStageFlags::FRAGMENT => ShaderKind::Fragment,
+        vk::ShaderStageFlags::COMPUTE => ShaderKind::Compute,
+        vk::ShaderStageFlags::GEOMETRY => ShaderKind::Geometry,
+        vk::ShaderStageFlags::TESSELLATION_CONTROL => ShaderKind::TessControl,
+        vk::ShaderStageFlags::TESSELLATION_EVALUATION => ShaderKind::TessEvaluation,
+        vk::ShaderStageFlags::TASK_EXT => ShaderKind::Task,
+        vk::ShaderStageFlags::MESH_EXT => ShaderKind::Mesh,
+        vk::ShaderStageFlags::RAYGEN_KHR => ShaderKind::RayGeneration,
+        vk::ShaderStageFlags::MISS_KHR => ShaderKind::Miss,
+        vk::ShaderStageFlags::CLOSEST_HIT_KHR => ShaderKind::ClosestHit,
+        vk::ShaderStageFlags::ANY_HIT_KHR => ShaderKind::AnyHit,
+        vk::ShaderStageFlags::INTERSECTION_KHR => ShaderKind::Intersection,
+        vk::ShaderStageFlags::CALLABLE_KHR => ShaderKind::Callable,
+        _ => bail!("Cannot compile shader {path:?} for stage {stage:?}"),
+    };
+
+    let mut compiler = Compiler::new().context("Failed to create shader compiler")?;
+    let mut compile_options = CompileOptions::new().context("Failed to create shader compile options")?;
+    let (env_version, spirv_version) = TARGET_VERSIONS;
+    compile_options.set_target_env(TargetEnv::Vulkan, env_version as u32);
+    compile_options.set_target_spirv(spirv_version);
+    compile_options.set_source_language(language);
+    for (name, value) in options.all_defines() {
+        compile_options.add_macro_definition(&name, value.as_deref());
+    }
+    if options.optimize {
+        compile_options.set_optimization_level(OptimizationLevel::Performance);
+    }
+    if options.debug_info {
+        compile_options.set_generate_debug_info();
+    }
+
+    let include_dirs = options.include_dirs.to_owned();
+    compile_options.set_include_callback(move |requested, include_type, requesting, _| {
+        resolve_include(requested, include_type, Path::new(requesting), &include_dirs)
+    });
+
+    let artifact = compiler
+        .compile_into_spirv(&source, kind, &path.to_string_lossy(), options.entry_point(), Some(&compile_options))
+        .map_err(|e| anyhow!("Failed to compile shader {path:?}: {e}"))?;
+
+    if artifact.get_num_warnings() > 0 {
+        warn!("{}", artifact.get_warning_messages());
+    }
+
+    Ok(artifact.as_binary_u8().to_vec())
+}
+
+/// Enables the extensions right after the #version line and restores the line numbers,
+/// so errors still point to the right line.
+fn inject_glsl_extensions(source: &str, extensions: &[&str]) -> String {
+    if extensions.is_empty() {
+        return source.to_owned();
+    }
+    let directives = extensions.iter().map(|name| format!("#extension {name} : enable\n")).collect::<String>();
+
+    let Some(version_line) = source.lines().position(|line| line.trim_start().starts_with("#version")) else {
+        return format!("{directives}#line 1\n{source}");
+    };
+
+    let mut result = String::with_capacity(source.len() + directives.len() + 16);
+    for (i, line) in source.split_inclusive('\n').enumerate() {
+        result.push_str(line);
+        if i == version_line {
+            if !line.ends_with('\n') {
+                result.push('\n');
+            }
+            result.push_str(&directives);
+            result.push_str(&format!("#line {}\n", version_line + 2));
+        }
+    }
+
+    result
+}
+
+fn resolve_include(
+    requested: &str,
+    include_type: IncludeType,
+    requesting: &Path,
+    include_dirs: &[PathBuf],
+) -> Result<ResolvedInclude, String> {
+    let relative = requesting
+        .parent()
+        .filter(|_| include_type == IncludeType::Relative)
+        .map(|dir| dir.join(requested));
+
+    for candidate in relative.into_iter().chain(include_dirs.iter().map(|dir| dir.join(requested))) {
+        if let Ok(content) = fs::read_to_string(&candidate) {
+            return Ok(ResolvedInclude {
+                resolved_name: candidate.to_string_lossy().into_owned(),
+                content,
+            });
+        }
+    }
+
+    Err(format!("Cannot find include {requested:?}"))
+}
+
+fn compile_slang(path: &Path, stage: vk::ShaderStageFlags, options: &ShaderCompileOptions) -> Result<Vec<u8>> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let stage_name = match stage {
+        vk::ShaderStageFlags::VERTEX => "vertex",
+        vk::ShaderStageFlags::FRAGMENT => "fragment",
+        vk::ShaderStageFlags::COMPUTE => "compute",
+        vk::ShaderStageFlags::GEOMETRY => "geometry",
+        vk::ShaderStageFlags::TESSELLATION_CONTROL => "hull",
+        vk::ShaderStageFlags::TESSELLATION_EVALUATION => "domain",
+        vk::ShaderStageFlags::TASK_EXT => "amplification",
+        vk::ShaderStageFlags::MESH_EXT => "mesh",
+        vk::ShaderStageFlags::RAYGEN_KHR => "raygeneration",
+        vk::ShaderStageFlags::MISS_KHR => "miss",
+        vk::ShaderStageFlags::CLOSEST_HIT_KHR => "closesthit",
+        vk::ShaderStageFlags::ANY_HIT_KHR => "anyhit",
+        vk::ShaderStageFlags::INTERSECTION_KHR => "intersection",
+        vk::ShaderStageFlags::CALLABLE_KHR => "callable",
+        _ => bail!("Cannot compile shader {path:?} for stage {stage:?}"),
+    };
+
+    let out_path = std::env::temp_dir().join(format!(
+        "octa_force_{}_{}.spv",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut command = Command::new("slangc");
+    command
+        .arg(path)
+        .args(["-target", "spirv", "-stage", stage_name, "-entry", options.entry_point()])
+        .args([if options.optimize { "-O2" } else { "-O0" }])
+        .arg("-o")
+        .arg(&out_path);
+    for dir in options.include_dirs.iter() {
+        command.arg("-I").arg(dir);
+    }
+    for (name, value) in options.all_defines() {
+        match value {
+            Some(value) => command.arg(format!("-D{name}={value}")),
+            None => command.arg(format!("-D{name}")),
+        };
+    }
+    if options.scalar_block_layout {
+        command.arg("-fvk-use-scalar-layout");
+    }
+    if options.debug_info {
+        command.arg("-g");
+    }
+
+    let output = command.output().context("Failed to run slangc, is it in the PATH?");
+    let spirv = match output {
+        Ok(output) if output.status.success() => fs::read(&out_path).map_err(Into::into),
+        Ok(output) => Err(anyhow!("Failed to compile shader {path:?}:\n{}", String::from_utf8_lossy(&output.stderr))),
+        Err(err) => Err(err),
+    };
+    // slangc can leave a partial output behind on errors
+    let _ = fs::remove_file(&out_path);
+
+    spirv
+}
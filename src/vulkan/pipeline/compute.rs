@@ -1,9 +1,10 @@
-use std::{ffi::CString, sync::Arc};
+use std::sync::Arc;
 
 use anyhow::Result;
 use ash::vk;
 
-use crate::{vulkan::device::Device, Context, PipelineLayout, ShaderModule};
+use crate::vulkan::pipeline::entry_point_name;
+use crate::{vulkan::device::Device, Context, PipelineCache, PipelineLayout, ShaderModule, SpecializationConstants};
 
 #[derive(Debug)]
 pub struct ComputePipeline {
@@ -11,33 +12,53 @@ pub struct ComputePipeline {
     pub(crate) inner: vk::Pipeline,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct ComputePipelineCreateInfo<'a> {
     pub shader_source: &'a [u8],
+    /// None uses "main".
+    pub entry_point: Option<&'a str>,
+    pub specialization: Option<&'a SpecializationConstants>,
+}
+
+impl<'a> ComputePipelineCreateInfo<'a> {
+    /// Uses "main" without specialization constants.
+    pub fn new(shader_source: &'a [u8]) -> Self {
+        Self {
+            shader_source,
+            entry_point: None,
+            specialization: None,
+        }
+    }
 }
 
 impl ComputePipeline {
     pub(crate) fn new(
         device: Arc<Device>,
+        cache: &PipelineCache,
         layout: &PipelineLayout,
         create_info: ComputePipelineCreateInfo,
     ) -> Result<Self> {
-        let entry_point_name = CString::new("main").unwrap();
+        let entry_point_name = entry_point_name(create_info.entry_point)?;
+        let specialization_info = create_info.specialization.map(|s| s.info());
         let shader_module = ShaderModule::from_bytes(device.clone(), create_info.shader_source)?;
-        let shader_stage_info = vk::PipelineShaderStageCreateInfo::default()
+        let mut shader_stage_info = vk::PipelineShaderStageCreateInfo::default()
             .stage(vk::ShaderStageFlags::COMPUTE)
             .module(shader_module.inner)
             .name(&entry_point_name);
+        if let Some(specialization_info) = &specialization_info {
+            shader_stage_info = shader_stage_info.specialization_info(specialization_info);
+        }
 
         let pipeline_info = vk::ComputePipelineCreateInfo::default()
             .stage(shader_stage_info)
-            .layout(layout.inner);
+            .layout(layout.inner)
+            .flags(layout.pipeline_create_flags());
 
         let inner = unsafe {
             device
                 .inner
                 .create_compute_pipelines(
-                    vk::PipelineCache::null(),
+                    cache.inner,
                     std::slice::from_ref(&pipeline_info),
                     None,
                 )
@@ -54,7 +75,7 @@ impl Context {
         layout: &PipelineLayout,
         create_info: ComputePipelineCreateInfo,
     ) -> Result<ComputePipeline> {
-        ComputePipeline::new(self.device.clone(), layout, create_info)
+        ComputePipeline::new(self.device.clone(), &self.pipeline_cache, layout, create_info)
     }
 }
 
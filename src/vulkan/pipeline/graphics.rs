@@ -1,9 +1,10 @@
-use std::{ffi::CString, sync::Arc};
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk::{self};
 
-use crate::{vulkan::device::Device, Context, PipelineLayout, ShaderModule};
+use crate::vulkan::pipeline::entry_point_name;
+use crate::{vulkan::device::Device, Context, PipelineCache, SpecializationConstants, PipelineLayout, ShaderModule};
 
 #[derive(Debug)]
 pub struct GraphicsPipeline {
@@ -16,10 +17,154 @@ pub struct GraphicsPipelineCreateInfo<'a> {
     pub shaders: &'a [GraphicsShaderCreateInfo<'a>],
     pub primitive_topology: vk::PrimitiveTopology,
     pub extent: Option<vk::Extent2D>,
+    /// Format of the single color attachment, ignored when color_attachment_formats is set.
     pub color_attachment_format: vk::Format,
+    /// None writes the color attachment without blending.
     pub color_attachment_blend: Option<vk::PipelineColorBlendAttachmentState>,
+    /// One format per color attachment for MRT.
+    pub color_attachment_formats: &'a [vk::Format],
+    /// One blend state per entry of color_attachment_formats. None writes all attachments without blending.
+    pub color_attachment_blends: Option<&'a [vk::PipelineColorBlendAttachmentState]>,
     pub depth_attachment_format: vk::Format,
     pub dynamic_states: Option<&'a [vk::DynamicState]>,
+    pub rasterization: RasterizationState,
+    pub depth_stencil: DepthStencilState,
+    pub multisample: MultisampleState,
+}
+
+impl Default for GraphicsPipelineCreateInfo<'_> {
+    fn default() -> Self {
+        Self {
+            shaders: &[],
+            primitive_topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            extent: None,
+            color_attachment_format: vk::Format::UNDEFINED,
+            color_attachment_blend: None,
+            color_attachment_formats: &[],
+            color_attachment_blends: None,
+            depth_attachment_format: vk::Format::UNDEFINED,
+            dynamic_states: None,
+            rasterization: Default::default(),
+            depth_stencil: Default::default(),
+            multisample: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RasterizationState {
+    pub polygon_mode: vk::PolygonMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub line_width: f32,
+    pub depth_clamp: bool,
+    pub depth_bias: Option<DepthBias>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    pub clamp: f32,
+    pub slope_factor: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DepthStencilState {
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp,
+    /// Front and back stencil op state. Needs a depth attachment format with stencil.
+    pub stencil: Option<(vk::StencilOpState, vk::StencilOpState)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MultisampleState {
+    pub samples: vk::SampleCountFlags,
+    pub min_sample_shading: Option<f32>,
+    pub alpha_to_coverage: bool,
+}
+
+impl Default for RasterizationState {
+    fn default() -> Self {
+        Self {
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            line_width: 1.0,
+            depth_clamp: false,
+            depth_bias: None,
+        }
+    }
+}
+
+impl RasterizationState {
+    pub fn wireframe() -> Self {
+        Self {
+            polygon_mode: vk::PolygonMode::LINE,
+            cull_mode: vk::CullModeFlags::NONE,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for DepthStencilState {
+    fn default() -> Self {
+        Self {
+            depth_test: true,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS,
+            stencil: None,
+        }
+    }
+}
+
+impl DepthStencilState {
+    /// Depth cleared to 0.0 and near plane mapped to 1.0.
+    pub fn reverse_z() -> Self {
+        Self {
+            depth_compare_op: vk::CompareOp::GREATER,
+            ..Default::default()
+        }
+    }
+
+    /// Depth test against the opaque geometry without writing, for transparent passes.
+    pub fn read_only() -> Self {
+        Self {
+            depth_write: false,
+            ..Default::default()
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            depth_test: false,
+            depth_write: false,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for MultisampleState {
+    fn default() -> Self {
+        Self {
+            samples: vk::SampleCountFlags::TYPE_1,
+            min_sample_shading: None,
+            alpha_to_coverage: false,
+        }
+    }
+}
+
+pub fn alpha_blend_attachment() -> vk::PipelineColorBlendAttachmentState {
+    vk::PipelineColorBlendAttachmentState {
+        blend_enable: vk::TRUE,
+        src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+        dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+        color_blend_op: vk::BlendOp::ADD,
+        src_alpha_blend_factor: vk::BlendFactor::ONE,
+        dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+        alpha_blend_op: vk::BlendOp::ADD,
+        color_write_mask: vk::ColorComponentFlags::RGBA,
+    }
 }
 
 pub trait Vertex {
@@ -27,42 +172,79 @@ pub trait Vertex {
     fn attributes() -> Vec<vk::VertexInputAttributeDescription>;
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct GraphicsShaderCreateInfo<'a> {
     pub source: &'a [u8],
     pub stage: vk::ShaderStageFlags,
+    /// None uses "main".
+    pub entry_point: Option<&'a str>,
+    pub specialization: Option<&'a SpecializationConstants>,
+}
+
+impl<'a> GraphicsShaderCreateInfo<'a> {
+    /// Uses "main" without specialization constants.
+    pub fn new(source: &'a [u8], stage: vk::ShaderStageFlags) -> Self {
+        Self {
+            source,
+            stage,
+            entry_point: None,
+            specialization: None,
+        }
+    }
 }
 
 impl GraphicsPipeline {
     pub(crate) fn new<V: Vertex>(
         device: Arc<Device>,
+        cache: &PipelineCache,
         layout: &PipelineLayout,
         create_info: GraphicsPipelineCreateInfo,
+    ) -> Result<Self> {
+        Self::new_complex(device, cache, layout, create_info, Some((V::bindings(), V::attributes())))
+    }
+
+    /// Without vertex input the pipeline uses task and mesh shaders instead of the vertex stage.
+    pub(crate) fn new_complex(
+        device: Arc<Device>,
+        cache: &PipelineCache,
+        layout: &PipelineLayout,
+        create_info: GraphicsPipelineCreateInfo,
+        vertex_input: Option<(Vec<vk::VertexInputBindingDescription>, Vec<vk::VertexInputAttributeDescription>)>,
     ) -> Result<Self> {
         // shaders
         let mut shader_modules = vec![];
         let mut shader_stages_infos = vec![];
 
-        let entry_point_name = CString::new("main").unwrap();
+        let entry_point_names = create_info.shaders
+            .iter()
+            .map(|shader| entry_point_name(shader.entry_point))
+            .collect::<Result<Vec<_>>>()?;
+        let specialization_infos = create_info.shaders
+            .iter()
+            .map(|shader| shader.specialization.map(|s| s.info()))
+            .collect::<Vec<_>>();
 
-        for shader in create_info.shaders.iter() {
+        for (i, shader) in create_info.shaders.iter().enumerate() {
             let module = ShaderModule::from_bytes(device.clone(), shader.source)?;
 
-            let stage = vk::PipelineShaderStageCreateInfo::default()
+            let mut stage = vk::PipelineShaderStageCreateInfo::default()
                 .stage(shader.stage)
                 .module(module.inner)
-                .name(&entry_point_name);
+                .name(&entry_point_names[i]);
+            if let Some(specialization_info) = &specialization_infos[i] {
+                stage = stage.specialization_info(specialization_info);
+            }
 
             shader_modules.push(module);
             shader_stages_infos.push(stage);
         }
 
         // vertex
-        let vertex_bindings = V::bindings();
-        let vertex_attributes = V::attributes();
-        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default()
-            .vertex_binding_descriptions(&vertex_bindings)
-            .vertex_attribute_descriptions(&vertex_attributes);
+        let vertex_input_info = vertex_input.as_ref().map(|(bindings, attributes)| {
+            vk::PipelineVertexInputStateCreateInfo::default()
+                .vertex_binding_descriptions(bindings)
+                .vertex_attribute_descriptions(attributes)
+        });
 
         let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::default()
             .topology(create_info.primitive_topology)
@@ -99,36 +281,59 @@ impl GraphicsPipeline {
             .scissor_count(1);
 
         // raster
+        let rasterization = create_info.rasterization;
+        let depth_bias = rasterization.depth_bias.unwrap_or_default();
         let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::default()
-            .depth_clamp_enable(false)
+            .depth_clamp_enable(rasterization.depth_clamp)
             .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .depth_bias_enable(false)
-            .depth_bias_constant_factor(0.0)
-            .depth_bias_clamp(0.0)
-            .depth_bias_slope_factor(0.0);
+            .polygon_mode(rasterization.polygon_mode)
+            .line_width(rasterization.line_width)
+            .cull_mode(rasterization.cull_mode)
+            .front_face(rasterization.front_face)
+            .depth_bias_enable(rasterization.depth_bias.is_some())
+            .depth_bias_constant_factor(depth_bias.constant_factor)
+            .depth_bias_clamp(depth_bias.clamp)
+            .depth_bias_slope_factor(depth_bias.slope_factor);
 
         // msaa
+        let multisample = create_info.multisample;
         let multisampling_info = vk::PipelineMultisampleStateCreateInfo::default()
-            .sample_shading_enable(false)
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1)
-            .min_sample_shading(1.0)
-            .alpha_to_coverage_enable(false)
+            .sample_shading_enable(multisample.min_sample_shading.is_some())
+            .rasterization_samples(multisample.samples)
+            .min_sample_shading(multisample.min_sample_shading.unwrap_or(1.0))
+            .alpha_to_coverage_enable(multisample.alpha_to_coverage)
             .alpha_to_one_enable(false);
 
         // blending
-        let color_blend_attachment =
-            create_info
-                .color_attachment_blend
-                .unwrap_or(vk::PipelineColorBlendAttachmentState {
+        let single_color_attachment_format = [create_info.color_attachment_format];
+        let (color_attachment_formats, color_attachment_blends) = if !create_info.color_attachment_formats.is_empty() {
+            (create_info.color_attachment_formats, create_info.color_attachment_blends.map(|b| b.to_vec()))
+        } else if create_info.color_attachment_format != vk::Format::UNDEFINED {
+            (&single_color_attachment_format[..], create_info.color_attachment_blend.map(|b| vec![b]))
+        } else {
+            (&[][..], None)
+        };
+
+        let color_blend_attachments = match color_attachment_blends {
+            Some(blends) => {
+                if blends.len() != color_attachment_formats.len() {
+                    bail!(
+                        "Got {} color attachment blends for {} color attachments",
+                        blends.len(),
+                        color_attachment_formats.len()
+                    );
+                }
+                blends
+            }
+            None => vec![
+                vk::PipelineColorBlendAttachmentState {
                     color_write_mask: vk::ColorComponentFlags::RGBA,
                     ..Default::default()
-                });
+                };
+                color_attachment_formats.len()
+            ],
+        };
 
-        let color_blend_attachments = [color_blend_attachment];
         let color_blending_info = vk::PipelineColorBlendStateCreateInfo::default()
             .logic_op_enable(false)
             .logic_op(vk::LogicOp::COPY)
@@ -139,41 +344,55 @@ impl GraphicsPipeline {
         let dynamic_state_info = vk::PipelineDynamicStateCreateInfo::default()
             .dynamic_states(create_info.dynamic_states.unwrap_or(&[]));
 
-        // dynamic rendering
-        let color_attachment_formats = [create_info.color_attachment_format];
-        let mut rendering_info = vk::PipelineRenderingCreateInfo::default()
-            .color_attachment_formats(&color_attachment_formats)
-            .depth_attachment_format(create_info.depth_attachment_format);
-
         // Depth
-        let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::default()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS)
+        let depth_stencil = create_info.depth_stencil;
+        let mut depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(depth_stencil.depth_test)
+            .depth_write_enable(depth_stencil.depth_write)
+            .depth_compare_op(depth_stencil.depth_compare_op)
             .depth_bounds_test_enable(false)
             .min_depth_bounds(0.0)
             .max_depth_bounds(f32::MAX)
-            .stencil_test_enable(false);
+            .stencil_test_enable(depth_stencil.stencil.is_some());
+        if let Some((front, back)) = depth_stencil.stencil {
+            depth_stencil_info = depth_stencil_info.front(front).back(back);
+        }
+
+        // dynamic rendering
+        let stencil_attachment_format = if depth_stencil.stencil.is_some() {
+            create_info.depth_attachment_format
+        } else {
+            vk::Format::UNDEFINED
+        };
+        let mut rendering_info = vk::PipelineRenderingCreateInfo::default()
+            .color_attachment_formats(color_attachment_formats)
+            .depth_attachment_format(create_info.depth_attachment_format)
+            .stencil_attachment_format(stencil_attachment_format);
 
         // Create Pipeline
-        let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+        let mut pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .stages(&shader_stages_infos)
-            .vertex_input_state(&vertex_input_info)
-            .input_assembly_state(&input_assembly_info)
             .viewport_state(&viewport_info)
             .rasterization_state(&rasterizer_info)
             .multisample_state(&multisampling_info)
             .color_blend_state(&color_blending_info)
             .dynamic_state(&dynamic_state_info)
             .layout(layout.inner)
+            .flags(layout.pipeline_create_flags())
             .depth_stencil_state(&depth_stencil_info)
             .push_next(&mut rendering_info);
 
+        if let Some(vertex_input_info) = vertex_input_info.as_ref() {
+            pipeline_info = pipeline_info
+                .vertex_input_state(vertex_input_info)
+                .input_assembly_state(&input_assembly_info);
+        }
+
         let inner = unsafe {
             device
                 .inner
                 .create_graphics_pipelines(
-                    vk::PipelineCache::null(),
+                    cache.inner,
                     std::slice::from_ref(&pipeline_info),
                     None,
                 )
@@ -190,7 +409,20 @@ impl Context {
         layout: &PipelineLayout,
         create_info: GraphicsPipelineCreateInfo,
     ) -> Result<GraphicsPipeline> {
-        GraphicsPipeline::new::<V>(self.device.clone(), layout, create_info)
+        GraphicsPipeline::new::<V>(self.device.clone(), &self.pipeline_cache, layout, create_info)
+    }
+
+    /// Needs mesh_shading in the EngineConfig. The shaders have to contain a MESH_EXT and optionally a TASK_EXT stage.
+    pub fn create_mesh_pipeline(
+        &self,
+        layout: &PipelineLayout,
+        create_info: GraphicsPipelineCreateInfo,
+    ) -> Result<GraphicsPipeline> {
+        if !self.mesh_shading {
+            bail!("Mesh shading is not enabled on this device");
+        }
+
+        GraphicsPipeline::new_complex(self.device.clone(), &self.pipeline_cache, layout, create_info, None)
     }
 }
 
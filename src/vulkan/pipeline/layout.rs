@@ -9,6 +9,7 @@ use crate::{vulkan::device::Device, Context, DescriptorSetLayout};
 pub struct PipelineLayout {
     device: Arc<Device>,
     pub(crate) inner: vk::PipelineLayout,
+    descriptor_buffer: bool,
 }
 
 impl PipelineLayout {
@@ -31,7 +32,18 @@ impl PipelineLayout {
                 .create_pipeline_layout(&pipe_layout_info, None)?
         };
 
-        Ok(Self { device, inner })
+        let descriptor_buffer = descriptor_set_layouts.iter().any(|l| l.descriptor_buffer);
+
+        Ok(Self { device, inner, descriptor_buffer })
+    }
+
+    /// Pipelines have to be created with DESCRIPTOR_BUFFER_EXT when their set layouts are for descriptor buffers.
+    pub(crate) fn pipeline_create_flags(&self) -> vk::PipelineCreateFlags {
+        if self.descriptor_buffer {
+            vk::PipelineCreateFlags::DESCRIPTOR_BUFFER_EXT
+        } else {
+            vk::PipelineCreateFlags::empty()
+        }
     }
 }
 
@@ -1,9 +1,19 @@
+mod cache;
+#[cfg(feature = "shader-compiler")]
+mod compiler;
 mod compute;
 mod graphics;
 mod layout;
+mod reflection;
 mod shader;
+mod specialization;
 
+pub use cache::*;
+#[cfg(feature = "shader-compiler")]
+pub use compiler::*;
 pub use compute::*;
 pub use graphics::*;
 pub use layout::*;
+pub use reflection::*;
 pub use shader::*;
+pub use specialization::*;
@@ -0,0 +1,826 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use ash::vk;
+use spirv::{Decoration, Dim, ExecutionModel, Op, StorageClass};
+
+use crate::vulkan::utils::read_shader_from_bytes;
+use crate::{Context, DescriptorSetLayout, PipelineLayout};
+
+/// Descriptor bindings and push constants a shader uses, read from its SPIR-V.
+#[derive(Debug, Clone, Default)]
+pub struct ShaderReflection {
+    pub stages: vk::ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constant_range: Option<vk::PushConstantRange>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReflectedBinding {
+    pub name: String,
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    /// None for runtime sized arrays.
+    pub count: Option<u32>,
+    pub stage_flags: vk::ShaderStageFlags,
+}
+
+/// Pipeline layout created from shader reflection.
+/// The set layouts are indexed by set number, unused sets get an empty layout.
+#[derive(Debug)]
+pub struct ReflectedPipelineLayout {
+    pub pipeline_layout: PipelineLayout,
+    pub descriptor_set_layouts: Vec<DescriptorSetLayout>,
+    pub reflection: ShaderReflection,
+}
+
+impl ShaderReflection {
+    pub fn from_bytes(source: &[u8]) -> Result<Self> {
+        Self::from_words(&read_shader_from_bytes(source)?)
+    }
+
+    pub fn from_words(words: &[u32]) -> Result<Self> {
+        SpirvModule::parse(words)?.reflect()
+    }
+
+    /// Merges the reflections of all stages of a pipeline.
+    /// Bindings used by multiple stages must have the same type and count.
+    pub fn merge(reflections: &[ShaderReflection]) -> Result<Self> {
+        let mut merged = ShaderReflection::default();
+
+        for reflection in reflections {
+            merged.stages |= reflection.stages;
+
+            for binding in reflection.bindings.iter() {
+                let existing = merged.bindings
+                    .iter_mut()
+                    .find(|b| b.set == binding.set && b.binding == binding.binding);
+
+                match existing {
+                    Some(existing) => {
+                        if existing.descriptor_type != binding.descriptor_type || existing.count != binding.count {
+                            bail!(
+                                "Set {} binding {} is {} {:?} x{:?} in one stage and {} {:?} x{:?} in another",
+                                binding.set, binding.binding,
+                                existing.name, existing.descriptor_type, existing.count,
+                                binding.name, binding.descriptor_type, binding.count,
+                            );
+                        }
+                        existing.stage_flags |= binding.stage_flags;
+                    }
+                    None => merged.bindings.push(binding.to_owned()),
+                }
+            }
+
+            merged.push_constant_range = union_push_constant_ranges(merged.push_constant_range, reflection.push_constant_range);
+        }
+
+        merged.bindings.sort_by_key(|b| (b.set, b.binding));
+        Ok(merged)
+    }
+
+    /// Number of set layouts the pipeline layout needs.
+    pub fn set_count(&self) -> u32 {
+        self.bindings.iter().map(|b| b.set + 1).max().unwrap_or(0)
+    }
+
+    pub fn set_layout_bindings(&self, set: u32) -> Result<Vec<vk::DescriptorSetLayoutBinding<'static>>> {
+        self.bindings
+            .iter()
+            .filter(|b| b.set == set)
+            .map(|b| {
+                let Some(count) = b.count else {
+                    bail!(
+                        "{} (set {} binding {}) is a runtime sized array, create its set layout by hand",
+                        b.name, b.set, b.binding
+                    );
+                };
+
+                Ok(vk::DescriptorSetLayoutBinding::default()
+                    .binding(b.binding)
+                    .descriptor_type(b.descriptor_type)
+                    .descriptor_count(count)
+                    .stage_flags(b.stage_flags))
+            })
+            .collect()
+    }
+
+    /// Checks that a hand written layout matches what the shaders use.
+    /// set_layouts is indexed by set number. All mismatches are reported in one error.
+    pub fn validate(
+        &self,
+        set_layouts: &[&[vk::DescriptorSetLayoutBinding]],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Result<()> {
+        let mut mismatches = vec![];
+
+        for b in self.bindings.iter() {
+            let Some(layout) = set_layouts
+                .get(b.set as usize)
+                .and_then(|set| set.iter().find(|l| l.binding == b.binding)) else {
+                mismatches.push(format!("{} (set {} binding {}) is missing in the layout", b.name, b.set, b.binding));
+                continue;
+            };
+
+            if !descriptor_types_compatible(b.descriptor_type, layout.descriptor_type) {
+                mismatches.push(format!(
+                    "{} (set {} binding {}) is {:?} in the shader but {:?} in the layout",
+                    b.name, b.set, b.binding, b.descriptor_type, layout.descriptor_type
+                ));
+            }
+
+            if layout.descriptor_count < b.count.unwrap_or(1) {
+                mismatches.push(format!(
+                    "{} (set {} binding {}) needs {} descriptors but the layout has {}",
+                    b.name, b.set, b.binding, b.count.unwrap_or(1), layout.descriptor_count
+                ));
+            }
+
+            if !layout.stage_flags.contains(b.stage_flags) {
+                mismatches.push(format!(
+                    "{} (set {} binding {}) is used in {:?} but the layout only has {:?}",
+                    b.name, b.set, b.binding, b.stage_flags, layout.stage_flags
+                ));
+            }
+        }
+
+        if let Some(range) = self.push_constant_range {
+            let covered = push_constant_ranges.iter().any(|r| {
+                r.offset <= range.offset
+                    && r.offset + r.size >= range.offset + range.size
+                    && r.stage_flags.contains(range.stage_flags)
+            });
+
+            if !covered {
+                mismatches.push(format!(
+                    "Push constants at offset {} with size {} used in {:?} are not covered by the layout",
+                    range.offset, range.size, range.stage_flags
+                ));
+            }
+        }
+
+        if !mismatches.is_empty() {
+            bail!("Pipeline layout does not match the shaders:\n{}", mismatches.join("\n"));
+        }
+
+        Ok(())
+    }
+}
+
+fn union_push_constant_ranges(a: Option<vk::PushConstantRange>, b: Option<vk::PushConstantRange>) -> Option<vk::PushConstantRange> {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let offset = a.offset.min(b.offset);
+            let end = (a.offset + a.size).max(b.offset + b.size);
+            Some(vk::PushConstantRange {
+                stage_flags: a.stage_flags | b.stage_flags,
+                offset,
+                size: end - offset,
+            })
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+fn descriptor_types_compatible(shader: vk::DescriptorType, layout: vk::DescriptorType) -> bool {
+    shader == layout
+        || (shader == vk::DescriptorType::UNIFORM_BUFFER && layout == vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        || (shader == vk::DescriptorType::STORAGE_BUFFER && layout == vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
+}
+
+impl Context {
+    /// Creates the descriptor set layouts and the pipeline layout from the SPIR-V of all stages of a pipeline.
+    pub fn create_reflected_pipeline_layout(&self, shader_sources: &[&[u8]]) -> Result<ReflectedPipelineLayout> {
+        let reflections = shader_sources
+            .iter()
+            .map(|source| ShaderReflection::from_bytes(source))
+            .collect::<Result<Vec<_>>>()?;
+        let reflection = ShaderReflection::merge(&reflections)?;
+
+        let descriptor_set_layouts = (0..reflection.set_count())
+            .map(|set| self.create_descriptor_set_layout(&reflection.set_layout_bindings(set)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        let pipeline_layout = self.create_pipeline_layout(
+            &descriptor_set_layouts.iter().collect::<Vec<_>>(),
+            reflection.push_constant_range.as_slice(),
+        )?;
+
+        Ok(ReflectedPipelineLayout {
+            pipeline_layout,
+            descriptor_set_layouts,
+            reflection,
+        })
+    }
+}
+
+type Decorations<'a> = Vec<(Decoration, &'a [u32])>;
+
+struct EntryPoint<'a> {
+    model: ExecutionModel,
+    function: u32,
+    /// Global variables the entry point uses, only complete since SPIR-V 1.4.
+    interface: &'a [u32],
+}
+
+#[derive(Default)]
+struct Function {
+    /// Global variables referenced by the function itself.
+    variables: Vec<u32>,
+    calls: Vec<u32>,
+}
+
+struct SpirvModule<'a> {
+    version: u32,
+    entry_points: Vec<EntryPoint<'a>>,
+    names: HashMap<u32, String>,
+    decorations: HashMap<u32, Decorations<'a>>,
+    member_decorations: HashMap<(u32, u32), Decorations<'a>>,
+    /// Types and constants by result id. Operands start after the result id.
+    types: HashMap<u32, (Op, &'a [u32])>,
+    /// Descriptor and push constant variables as (id, pointer type, storage class).
+    variables: Vec<(u32, u32, StorageClass)>,
+    functions: HashMap<u32, Function>,
+}
+
+impl<'a> SpirvModule<'a> {
+    fn parse(words: &'a [u32]) -> Result<Self> {
+        if words.len() < 5 || words[0] != spirv::MAGIC_NUMBER {
+            bail!("Not a SPIR-V module");
+        }
+
+        let mut module = SpirvModule {
+            version: words[1],
+            entry_points: vec![],
+            names: HashMap::new(),
+            decorations: HashMap::new(),
+            member_decorations: HashMap::new(),
+            types: HashMap::new(),
+            variables: vec![],
+            functions: HashMap::new(),
+        };
+        let mut current_function = None;
+
+        let mut rest = &words[5..];
+        while let Some(&first) = rest.first() {
+            let word_count = (first >> 16) as usize;
+            if word_count == 0 || word_count > rest.len() {
+                bail!("Malformed SPIR-V instruction");
+            }
+            let operands = &rest[1..word_count];
+            rest = &rest[word_count..];
+
+            let Some(op) = Op::from_u32(first & 0xffff) else {
+                continue;
+            };
+
+            // Globals are declared before the functions, so all descriptor variables are known here.
+            if let Some(function) = current_function {
+                let function = module.functions.entry(function).or_default();
+                match op {
+                    Op::FunctionEnd => current_function = None,
+                    Op::FunctionCall if operands.len() >= 3 => function.calls.push(operands[2]),
+                    _ => function.variables.extend(
+                        operands.iter().filter(|id| module.variables.iter().any(|(v, _, _)| v == *id)),
+                    ),
+                }
+                continue;
+            }
+
+            match op {
+                Op::EntryPoint if operands.len() >= 2 => {
+                    if let Some(model) = ExecutionModel::from_u32(operands[0]) {
+                        let name_words = operands[2..]
+                            .iter()
+                            .position(|w| w.to_le_bytes().contains(&0))
+                            .map_or(operands.len() - 2, |i| i + 1);
+
+                        module.entry_points.push(EntryPoint {
+                            model,
+                            function: operands[1],
+                            interface: &operands[2 + name_words..],
+                        });
+                    }
+                }
+                Op::Name if !operands.is_empty() => {
+                    module.names.insert(operands[0], read_string(&operands[1..]));
+                }
+                Op::Decorate if operands.len() >= 2 => {
+                    if let Some(decoration) = Decoration::from_u32(operands[1]) {
+                        module.decorations.entry(operands[0]).or_default().push((decoration, &operands[2..]));
+                    }
+                }
+                Op::MemberDecorate if operands.len() >= 3 => {
+                    if let Some(decoration) = Decoration::from_u32(operands[2]) {
+                        module.member_decorations
+                            .entry((operands[0], operands[1]))
+                            .or_default()
+                            .push((decoration, &operands[3..]));
+                    }
+                }
+                Op::Variable if operands.len() >= 3 => {
+                    let storage_class = StorageClass::from_u32(operands[2]);
+                    if let Some(storage_class @ (StorageClass::Uniform
+                        | StorageClass::UniformConstant
+                        | StorageClass::StorageBuffer
+                        | StorageClass::PushConstant)) = storage_class
+                    {
+                        module.variables.push((operands[1], operands[0], storage_class));
+                    }
+                }
+                Op::Constant | Op::SpecConstant if operands.len() >= 3 => {
+                    module.types.insert(operands[1], (op, &operands[2..]));
+                }
+                Op::TypeBool | Op::TypeInt | Op::TypeFloat | Op::TypeVector | Op::TypeMatrix | Op::TypeImage
+                | Op::TypeSampler | Op::TypeSampledImage | Op::TypeArray | Op::TypeRuntimeArray | Op::TypeStruct
+                | Op::TypePointer | Op::TypeAccelerationStructureKHR if !operands.is_empty() => {
+                    module.types.insert(operands[0], (op, &operands[1..]));
+                }
+                Op::Function if operands.len() >= 2 => current_function = Some(operands[1]),
+                _ => {}
+            }
+        }
+
+        Ok(module)
+    }
+
+    fn reflect(&self) -> Result<ShaderReflection> {
+        let stages = self.entry_points
+            .iter()
+            .fold(vk::ShaderStageFlags::empty(), |stages, entry| stages | stage_flags(entry.model));
+        let used_variables = self.entry_points
+            .iter()
+            .map(|entry| (stage_flags(entry.model), self.used_variables(entry)))
+            .collect::<Vec<_>>();
+
+        let mut bindings = vec![];
+        let mut push_constant_range: Option<vk::PushConstantRange> = None;
+
+        for &(id, pointer_type, storage_class) in self.variables.iter() {
+            // Variables no entry point uses keep all stages of the module.
+            let variable_stages = used_variables
+                .iter()
+                .filter(|(_, used)| used.contains(&id))
+                .fold(vk::ShaderStageFlags::empty(), |stages, (stage, _)| stages | *stage);
+            let variable_stages = if variable_stages.is_empty() { stages } else { variable_stages };
+
+            let Some(type_id) = self.types.get(&pointer_type).and_then(|(_, pointer)| pointer.get(1).copied()) else {
+                continue;
+            };
+
+            if storage_class == StorageClass::PushConstant {
+                let Some((offset, end)) = self.struct_range(type_id) else {
+                    log::warn!("Skipping push constants of an unsupported type in shader reflection");
+                    continue;
+                };
+
+                // Each entry point can have its own push constant block
+                push_constant_range = union_push_constant_ranges(push_constant_range, Some(vk::PushConstantRange {
+                    stage_flags: variable_stages,
+                    offset,
+                    size: end - offset,
+                }));
+                continue;
+            }
+
+            let (Some(set), Some(binding)) = (
+                self.decoration(id, Decoration::DescriptorSet),
+                self.decoration(id, Decoration::Binding),
+            ) else {
+                continue;
+            };
+
+            let Some((descriptor_type, count)) = self.descriptor_type(storage_class, type_id) else {
+                log::warn!("Skipping set {set} binding {binding} of an unsupported type in shader reflection");
+                continue;
+            };
+
+            let name = self.names.get(&id)
+                .filter(|n| !n.is_empty())
+                .or_else(|| self.names.get(&self.element_type(type_id)))
+                .cloned()
+                .unwrap_or_else(|| format!("set_{set}_binding_{binding}"));
+
+            bindings.push(ReflectedBinding {
+                name,
+                set,
+                binding,
+                descriptor_type,
+                count,
+                stage_flags: variable_stages,
+            });
+        }
+
+        bindings.sort_by_key(|b| (b.set, b.binding));
+
+        Ok(ShaderReflection {
+            stages,
+            bindings,
+            push_constant_range,
+        })
+    }
+
+    /// Descriptor and push constant variables an entry point statically uses.
+    fn used_variables(&self, entry: &EntryPoint) -> Vec<u32> {
+        // Since SPIR-V 1.4 the interface lists all global variables of the entry point.
+        if self.version >= 0x10400 {
+            return entry.interface.to_vec();
+        }
+
+        let mut used = vec![];
+        let mut visited = vec![];
+        let mut stack = vec![entry.function];
+        while let Some(id) = stack.pop() {
+            if visited.contains(&id) {
+                continue;
+            }
+            visited.push(id);
+
+            if let Some(function) = self.functions.get(&id) {
+                used.extend_from_slice(&function.variables);
+                stack.extend_from_slice(&function.calls);
+            }
+        }
+
+        used
+    }
+
+    fn get_type(&self, id: u32) -> Option<(Op, &'a [u32])> {
+        self.types.get(&id).copied()
+    }
+
+    fn decoration(&self, id: u32, decoration: Decoration) -> Option<u32> {
+        self.decorations.get(&id)?
+            .iter()
+            .find(|(d, _)| *d == decoration)
+            .and_then(|(_, operands)| operands.first().copied())
+    }
+
+    fn has_decoration(&self, id: u32, decoration: Decoration) -> bool {
+        self.decorations.get(&id).is_some_and(|d| d.iter().any(|(d, _)| *d == decoration))
+    }
+
+    fn member_decoration(&self, id: u32, member: u32, decoration: Decoration) -> Option<u32> {
+        self.member_decorations.get(&(id, member))?
+            .iter()
+            .find(|(d, _)| *d == decoration)
+            .and_then(|(_, operands)| operands.first().copied())
+    }
+
+    fn has_member_decoration(&self, id: u32, member: u32, decoration: Decoration) -> bool {
+        self.member_decorations.get(&(id, member)).is_some_and(|d| d.iter().any(|(d, _)| *d == decoration))
+    }
+
+    /// None for lengths computed with OpSpecConstantOp or composites.
+    fn constant(&self, id: u32) -> Option<u32> {
+        match self.get_type(id)? {
+            // Specialization constants use their default value.
+            (Op::Constant | Op::SpecConstant, [value, ..]) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The type inside of (runtime) arrays.
+    fn element_type(&self, id: u32) -> u32 {
+        match self.types.get(&id) {
+            Some((Op::TypeArray | Op::TypeRuntimeArray, [element, ..])) => self.element_type(*element),
+            _ => id,
+        }
+    }
+
+    fn descriptor_type(&self, storage_class: StorageClass, id: u32) -> Option<(vk::DescriptorType, Option<u32>)> {
+        let count = match self.get_type(id)? {
+            (Op::TypeArray, [_, length]) => Some(self.constant(*length)?),
+            (Op::TypeRuntimeArray, _) => None,
+            _ => Some(1),
+        };
+        let element = self.element_type(id);
+
+        let descriptor_type = match (storage_class, self.get_type(element)?) {
+            (StorageClass::UniformConstant, (Op::TypeSampler, _)) => vk::DescriptorType::SAMPLER,
+            (StorageClass::UniformConstant, (Op::TypeSampledImage, _)) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (StorageClass::UniformConstant, (Op::TypeAccelerationStructureKHR, _)) => {
+                vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+            }
+            (StorageClass::UniformConstant, (Op::TypeImage, [_, dim, _, _, _, sampled, ..])) => {
+                let storage = *sampled == 2;
+                match Dim::from_u32(*dim) {
+                    Some(Dim::DimBuffer) if storage => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                    Some(Dim::DimBuffer) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    Some(Dim::DimSubpassData) => vk::DescriptorType::INPUT_ATTACHMENT,
+                    _ if storage => vk::DescriptorType::STORAGE_IMAGE,
+                    _ => vk::DescriptorType::SAMPLED_IMAGE,
+                }
+            }
+            (StorageClass::Uniform, _) if self.has_decoration(element, Decoration::BufferBlock) => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            (StorageClass::Uniform, _) => vk::DescriptorType::UNIFORM_BUFFER,
+            (StorageClass::StorageBuffer, _) => vk::DescriptorType::STORAGE_BUFFER,
+            _ => return None,
+        };
+
+        Some((descriptor_type, count))
+    }
+
+    /// Start and end byte of the members of a struct.
+    fn struct_range(&self, id: u32) -> Option<(u32, u32)> {
+        let (Op::TypeStruct, members) = self.get_type(id)? else {
+            return None;
+        };
+
+        let mut start = u32::MAX;
+        let mut end = 0;
+        for (i, &member) in members.iter().enumerate() {
+            let offset = self.member_decoration(id, i as u32, Decoration::Offset).unwrap_or(0);
+            start = start.min(offset);
+            end = end.max(offset + self.member_size(id, i as u32, member)?);
+        }
+
+        Some((start.min(end), end))
+    }
+
+    fn member_size(&self, struct_id: u32, member: u32, id: u32) -> Option<u32> {
+        if let (Op::TypeMatrix, [column_type, columns]) = self.get_type(id)?
+            && let Some(stride) = self.member_decoration(struct_id, member, Decoration::MatrixStride)
+        {
+            let (_, column) = self.get_type(*column_type)?;
+            let vectors = if self.has_member_decoration(struct_id, member, Decoration::RowMajor) {
+                column.get(1).copied().unwrap_or(*columns)
+            } else {
+                *columns
+            };
+            return Some(stride * vectors);
+        }
+
+        self.type_size(id)
+    }
+
+    fn type_size(&self, id: u32) -> Option<u32> {
+        Some(match self.get_type(id)? {
+            (Op::TypeBool, _) => 4,
+            (Op::TypeInt | Op::TypeFloat, [width, ..]) => width / 8,
+            (Op::TypeVector | Op::TypeMatrix, [component, count]) => self.type_size(*component)? * count,
+            (Op::TypeArray, [element, length]) => {
+                let stride = match self.decoration(id, Decoration::ArrayStride) {
+                    Some(stride) => stride,
+                    None => self.type_size(*element)?,
+                };
+                stride * self.constant(*length)?
+            }
+            (Op::TypeStruct, _) => self.struct_range(id)?.1,
+            (Op::TypePointer, _) => 8,
+            _ => return None,
+        })
+    }
+}
+
+fn read_string(words: &[u32]) -> String {
+    let bytes = words.iter().flat_map(|w| w.to_le_bytes()).take_while(|&b| b != 0).collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn stage_flags(model: ExecutionModel) -> vk::ShaderStageFlags {
+    match model {
+        ExecutionModel::Vertex => vk::ShaderStageFlags::VERTEX,
+        ExecutionModel::TessellationControl => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        ExecutionModel::TessellationEvaluation => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        ExecutionModel::Geometry => vk::ShaderStageFlags::GEOMETRY,
+        ExecutionModel::Fragment => vk::ShaderStageFlags::FRAGMENT,
+        ExecutionModel::GLCompute => vk::ShaderStageFlags::COMPUTE,
+        ExecutionModel::TaskEXT | ExecutionModel::TaskNV => vk::ShaderStageFlags::TASK_EXT,
+        ExecutionModel::MeshEXT | ExecutionModel::MeshNV => vk::ShaderStageFlags::MESH_EXT,
+        ExecutionModel::RayGenerationNV => vk::ShaderStageFlags::RAYGEN_KHR,
+        ExecutionModel::IntersectionNV => vk::ShaderStageFlags::INTERSECTION_KHR,
+        ExecutionModel::AnyHitNV => vk::ShaderStageFlags::ANY_HIT_KHR,
+        ExecutionModel::ClosestHitNV => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        ExecutionModel::MissNV => vk::ShaderStageFlags::MISS_KHR,
+        ExecutionModel::CallableNV => vk::ShaderStageFlags::CALLABLE_KHR,
+        ExecutionModel::Kernel => vk::ShaderStageFlags::empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+    use spirv::{Capability, Decoration, Dim, ExecutionModel, ImageFormat, Op, StorageClass};
+
+    use super::ShaderReflection;
+
+    const VERSION_1_0: u32 = 0x10000;
+    const VERSION_1_4: u32 = 0x10400;
+
+    /// Minimal SPIR-V assembler for the fixtures.
+    struct Assembler(Vec<u32>);
+
+    impl Assembler {
+        fn new(version: u32) -> Self {
+            Self(vec![spirv::MAGIC_NUMBER, version, 0, 100, 0])
+        }
+
+        fn op(&mut self, op: Op, operands: &[u32]) -> &mut Self {
+            self.0.push(((operands.len() as u32 + 1) << 16) | op as u32);
+            self.0.extend_from_slice(operands);
+            self
+        }
+
+        fn entry_point(&mut self, model: ExecutionModel, function: u32, name: &str, interface: &[u32]) -> &mut Self {
+            let mut bytes = name.as_bytes().to_vec();
+            bytes.resize(bytes.len() / 4 * 4 + 4, 0);
+
+            let mut operands = vec![model as u32, function];
+            operands.extend(bytes.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())));
+            operands.extend_from_slice(interface);
+            self.op(Op::EntryPoint, &operands)
+        }
+
+        fn binding(&mut self, id: u32, set: u32, binding: u32) -> &mut Self {
+            self.op(Op::Decorate, &[id, Decoration::DescriptorSet as u32, set])
+                .op(Op::Decorate, &[id, Decoration::Binding as u32, binding])
+        }
+
+        /// Function that loads each of the variables and calls the functions.
+        fn function(&mut self, id: u32, loads: &[(u32, u32)], calls: &[u32]) -> &mut Self {
+            self.op(Op::Function, &[1, id, 0, 2]).op(Op::Label, &[id + 1000]);
+            for (i, &(ty, variable)) in loads.iter().enumerate() {
+                self.op(Op::Load, &[ty, id + 2000 + i as u32, variable]);
+            }
+            for (i, &call) in calls.iter().enumerate() {
+                self.op(Op::FunctionCall, &[1, id + 3000 + i as u32, call]);
+            }
+            self.op(Op::Return, &[]).op(Op::FunctionEnd, &[])
+        }
+    }
+
+    /// Fragment shader with a sampler array, a UBO and push constants.
+    /// Also has a ray query and a workgroup array sized by OpSpecConstantOp, which are not reflected.
+    fn fragment_shader() -> Vec<u32> {
+        let mut a = Assembler::new(VERSION_1_0);
+        a.op(Op::Capability, &[Capability::Shader as u32])
+            .op(Op::Capability, &[Capability::RayQueryKHR as u32])
+            .entry_point(ExecutionModel::Fragment, 3, "main", &[])
+            .op(Op::Name, &[20, u32::from_le_bytes(*b"ubo\0")])
+            .binding(10, 0, 1)
+            .binding(20, 1, 0)
+            .op(Op::Decorate, &[21, Decoration::Block as u32])
+            .op(Op::Decorate, &[30, Decoration::Block as u32])
+            .op(Op::MemberDecorate, &[30, 0, Decoration::Offset as u32, 16])
+            .op(Op::TypeVoid, &[1])
+            .op(Op::TypeFunction, &[2, 1])
+            .op(Op::TypeFloat, &[4, 32])
+            .op(Op::TypeVector, &[5, 4, 4])
+            .op(Op::TypeImage, &[6, 4, Dim::Dim2D as u32, 0, 0, 0, 1, ImageFormat::Unknown as u32])
+            .op(Op::TypeSampledImage, &[7, 6])
+            .op(Op::TypeInt, &[8, 32, 0])
+            .op(Op::Constant, &[8, 9, 4])
+            .op(Op::TypeArray, &[11, 7, 9])
+            .op(Op::TypePointer, &[12, StorageClass::UniformConstant as u32, 11])
+            .op(Op::Variable, &[12, 10, StorageClass::UniformConstant as u32])
+            .op(Op::TypeStruct, &[21, 5])
+            .op(Op::TypePointer, &[22, StorageClass::Uniform as u32, 21])
+            .op(Op::Variable, &[22, 20, StorageClass::Uniform as u32])
+            .op(Op::TypeStruct, &[30, 5])
+            .op(Op::TypePointer, &[31, StorageClass::PushConstant as u32, 30])
+            .op(Op::Variable, &[31, 32, StorageClass::PushConstant as u32])
+            .op(Op::SpecConstant, &[8, 40, 64])
+            .op(Op::SpecConstantOp, &[8, 41, Op::IMul as u32, 40, 40])
+            .op(Op::TypeArray, &[42, 4, 41])
+            .op(Op::TypePointer, &[43, StorageClass::Workgroup as u32, 42])
+            .op(Op::Variable, &[43, 44, StorageClass::Workgroup as u32])
+            .op(Op::TypeRayQueryKHR, &[50])
+            .op(Op::TypePointer, &[51, StorageClass::Private as u32, 50])
+            .op(Op::Variable, &[51, 52, StorageClass::Private as u32])
+            .function(3, &[(11, 10), (21, 20), (30, 32)], &[]);
+        a.0
+    }
+
+    /// Vertex and fragment entry points each using their own UBO, the fragment one through a called function.
+    fn two_entry_points(version: u32) -> Vec<u32> {
+        let mut a = Assembler::new(version);
+        a.op(Op::Capability, &[Capability::Shader as u32])
+            .entry_point(ExecutionModel::Vertex, 3, "vs", &[20])
+            .entry_point(ExecutionModel::Fragment, 4, "fs", &[25])
+            .binding(20, 0, 0)
+            .binding(25, 0, 1)
+            .op(Op::Decorate, &[7, Decoration::Block as u32])
+            .op(Op::TypeVoid, &[1])
+            .op(Op::TypeFunction, &[2, 1])
+            .op(Op::TypeFloat, &[6, 32])
+            .op(Op::TypeStruct, &[7, 6])
+            .op(Op::TypePointer, &[8, StorageClass::Uniform as u32, 7])
+            .op(Op::Variable, &[8, 20, StorageClass::Uniform as u32])
+            .op(Op::Variable, &[8, 25, StorageClass::Uniform as u32]);
+
+        // Since 1.4 the stages come from the interface lists
+        if version < VERSION_1_4 {
+            a.function(3, &[(7, 20)], &[])
+                .function(5, &[(7, 25)], &[])
+                .function(4, &[], &[5]);
+        }
+        a.0
+    }
+
+    #[test]
+    fn reflects_descriptors_and_push_constants() {
+        let reflection = ShaderReflection::from_words(&fragment_shader()).unwrap();
+
+        assert_eq!(reflection.stages, vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(reflection.bindings.len(), 2);
+
+        let samplers = &reflection.bindings[0];
+        assert_eq!((samplers.set, samplers.binding), (0, 1));
+        assert_eq!(samplers.descriptor_type, vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
+        assert_eq!(samplers.count, Some(4));
+        assert_eq!(samplers.stage_flags, vk::ShaderStageFlags::FRAGMENT);
+
+        let ubo = &reflection.bindings[1];
+        assert_eq!(ubo.name, "ubo");
+        assert_eq!((ubo.set, ubo.binding), (1, 0));
+        assert_eq!(ubo.descriptor_type, vk::DescriptorType::UNIFORM_BUFFER);
+        assert_eq!(ubo.count, Some(1));
+
+        let push_constants = reflection.push_constant_range.unwrap();
+        assert_eq!((push_constants.offset, push_constants.size), (16, 16));
+        assert_eq!(push_constants.stage_flags, vk::ShaderStageFlags::FRAGMENT);
+    }
+
+    #[test]
+    fn tracks_stages_per_entry_point() {
+        for version in [VERSION_1_0, VERSION_1_4] {
+            let reflection = ShaderReflection::from_words(&two_entry_points(version)).unwrap();
+
+            assert_eq!(reflection.stages, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
+            assert_eq!(reflection.bindings[0].stage_flags, vk::ShaderStageFlags::VERTEX);
+            assert_eq!(reflection.bindings[1].stage_flags, vk::ShaderStageFlags::FRAGMENT);
+        }
+    }
+
+    #[test]
+    fn runtime_arrays_have_no_count() {
+        let mut a = Assembler::new(VERSION_1_4);
+        a.entry_point(ExecutionModel::GLCompute, 3, "main", &[10])
+            .binding(10, 0, 0)
+            .op(Op::Decorate, &[6, Decoration::Block as u32])
+            .op(Op::TypeVoid, &[1])
+            .op(Op::TypeFunction, &[2, 1])
+            .op(Op::TypeFloat, &[4, 32])
+            .op(Op::TypeRuntimeArray, &[5, 4])
+            .op(Op::TypeStruct, &[6, 5])
+            .op(Op::TypeRuntimeArray, &[7, 6])
+            .op(Op::TypePointer, &[8, StorageClass::StorageBuffer as u32, 7])
+            .op(Op::Variable, &[8, 10, StorageClass::StorageBuffer as u32]);
+
+        let reflection = ShaderReflection::from_words(&a.0).unwrap();
+        let binding = &reflection.bindings[0];
+        assert_eq!(binding.descriptor_type, vk::DescriptorType::STORAGE_BUFFER);
+        assert_eq!(binding.count, None);
+        assert!(reflection.set_layout_bindings(0).is_err());
+    }
+
+    #[test]
+    fn unions_push_constants_of_entry_points() {
+        let mut a = Assembler::new(VERSION_1_4);
+        a.op(Op::Capability, &[Capability::Shader as u32])
+            .entry_point(ExecutionModel::Vertex, 3, "vs", &[10])
+            .entry_point(ExecutionModel::Fragment, 4, "fs", &[20])
+            .op(Op::Decorate, &[7, Decoration::Block as u32])
+            .op(Op::MemberDecorate, &[7, 0, Decoration::Offset as u32, 0])
+            .op(Op::Decorate, &[8, Decoration::Block as u32])
+            .op(Op::MemberDecorate, &[8, 0, Decoration::Offset as u32, 32])
+            .op(Op::TypeVoid, &[1])
+            .op(Op::TypeFunction, &[2, 1])
+            .op(Op::TypeFloat, &[5, 32])
+            .op(Op::TypeVector, &[6, 5, 4])
+            .op(Op::TypeStruct, &[7, 6])
+            .op(Op::TypeStruct, &[8, 6])
+            .op(Op::TypePointer, &[11, StorageClass::PushConstant as u32, 7])
+            .op(Op::TypePointer, &[21, StorageClass::PushConstant as u32, 8])
+            .op(Op::Variable, &[11, 10, StorageClass::PushConstant as u32])
+            .op(Op::Variable, &[21, 20, StorageClass::PushConstant as u32]);
+
+        let reflection = ShaderReflection::from_words(&a.0).unwrap();
+        let push_constants = reflection.push_constant_range.unwrap();
+        assert_eq!((push_constants.offset, push_constants.size), (0, 48));
+        assert_eq!(push_constants.stage_flags, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
+    }
+
+    #[test]
+    fn merge_rejects_conflicting_bindings() {
+        let fragment = ShaderReflection::from_words(&fragment_shader()).unwrap();
+        let mut other = fragment.clone();
+        other.bindings[0].descriptor_type = vk::DescriptorType::SAMPLED_IMAGE;
+
+        assert!(ShaderReflection::merge(&[fragment.clone(), fragment.clone()]).is_ok());
+        assert!(ShaderReflection::merge(&[fragment, other]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        assert!(ShaderReflection::from_words(&[1, 2, 3]).is_err());
+
+        let mut words = fragment_shader();
+        words.push(10 << 16);
+        assert!(ShaderReflection::from_words(&words).is_err());
+    }
+}
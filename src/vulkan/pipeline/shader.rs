@@ -3,22 +3,27 @@ use std::sync::Arc;
 use anyhow::Result;
 use ash::vk;
 
-use crate::{vulkan::device::Device, Context};
+use crate::{vulkan::device::Device, Context, ShaderReflection};
 use crate::vulkan::utils::read_shader_from_bytes;
 
 pub struct ShaderModule {
     device: Arc<Device>,
     pub(crate) inner: vk::ShaderModule,
+    /// None when the SPIR-V could not be reflected, the module can still be used.
+    pub reflection: Option<ShaderReflection>,
 }
 
 impl ShaderModule {
     pub(crate) fn from_bytes(device: Arc<Device>, source: &[u8]) -> Result<Self> {
         let source = read_shader_from_bytes(source)?;
+        let reflection = ShaderReflection::from_words(&source)
+            .inspect_err(|err| log::warn!("Shader reflection failed: {err:#}"))
+            .ok();
 
         let create_info = vk::ShaderModuleCreateInfo::default().code(&source);
         let inner = unsafe { device.inner.create_shader_module(&create_info, None)? };
 
-        Ok(Self { device, inner })
+        Ok(Self { device, inner, reflection })
     }
 }
 
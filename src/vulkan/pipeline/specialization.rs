@@ -0,0 +1,47 @@
+use std::ffi::CString;
+use std::mem::size_of;
+
+use anyhow::{Context as _, Result};
+use ash::vk;
+use bytemuck::Pod;
+
+/// Values for the specialization constants of one shader stage, matched by constant_id.
+#[derive(Debug, Clone, Default)]
+pub struct SpecializationConstants {
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>,
+}
+
+impl SpecializationConstants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// T has to match the type of the constant in the shader, for example u32 for workgroup sizes.
+    pub fn with<T: Pod>(mut self, constant_id: u32, value: T) -> Self {
+        self.entries.push(vk::SpecializationMapEntry {
+            constant_id,
+            offset: self.data.len() as _,
+            size: size_of::<T>(),
+        });
+        self.data.extend_from_slice(bytemuck::bytes_of(&value));
+        self
+    }
+
+    /// Bool constants are 32 bit in SPIR-V.
+    pub fn with_bool(self, constant_id: u32, value: bool) -> Self {
+        self.with(constant_id, value as vk::Bool32)
+    }
+
+    pub(crate) fn info(&self) -> vk::SpecializationInfo<'_> {
+        vk::SpecializationInfo::default()
+            .map_entries(&self.entries)
+            .data(&self.data)
+    }
+}
+
+/// None uses "main".
+pub(crate) fn entry_point_name(entry_point: Option<&str>) -> Result<CString> {
+    let name = entry_point.unwrap_or("main");
+    CString::new(name).with_context(|| format!("Invalid entry point name {name:?}"))
+}
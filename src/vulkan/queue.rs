@@ -1,5 +1,5 @@
 use anyhow::Result;
-use crate::{CommandBuffer, Fence, Semaphore};
+use crate::{CommandBuffer, Fence, Semaphore, TimelineSemaphore};
 use ash::vk;
 
 #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
@@ -38,6 +38,10 @@ impl QueueFamily {
         self.inner.queue_flags.contains(vk::QueueFlags::GRAPHICS)
     }
 
+    pub fn supports_transfer(&self) -> bool {
+        self.inner.queue_flags.intersects(vk::QueueFlags::TRANSFER | vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+    }
+
     pub fn supports_present(&self) -> bool {
         self.supports_present
     }
@@ -90,47 +94,68 @@ impl Queue {
         signal_semaphore: Option<SemaphoreSubmitInfo>,
         fence: &Fence,
     ) -> Result<()> {
-        let wait_semaphore_submit_info = wait_semaphore.map(|s| {
-            vk::SemaphoreSubmitInfo::default()
-                .semaphore(s.semaphore.inner)
-                .stage_mask(s.stage_mask)
-        });
-
-        let signal_semaphore_submit_info = signal_semaphore.map(|s| {
-            vk::SemaphoreSubmitInfo::default()
-                .semaphore(s.semaphore.inner)
-                .stage_mask(s.stage_mask)
-        });
-
-        let cmd_buffer_submit_info =
-            vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer.inner);
-
-        let submit_info = vk::SubmitInfo2::default()
-            .command_buffer_infos(std::slice::from_ref(&cmd_buffer_submit_info));
-
-        let submit_info = match wait_semaphore_submit_info.as_ref() {
-            Some(info) => submit_info.wait_semaphore_infos(std::slice::from_ref(info)),
-            None => submit_info,
-        };
+        self.submit_batch(&[SubmitBatch {
+            command_buffers: vec![command_buffer],
+            wait_semaphores: wait_semaphore.into_iter().collect(),
+            signal_semaphores: signal_semaphore.into_iter().collect(),
+            ..Default::default()
+        }], Some(fence))
+    }
 
-        let submit_info = match signal_semaphore_submit_info.as_ref() {
-            Some(info) => submit_info.signal_semaphore_infos(std::slice::from_ref(info)),
-            None => submit_info,
-        };
+    /// Submits all batches with one call. Batches are started in order, but can overlap.
+    pub fn submit_batch(&self, batches: &[SubmitBatch], fence: Option<&Fence>) -> Result<()> {
+        let infos = batches.iter()
+            .map(|batch| {
+                let binary_info = |s: &SemaphoreSubmitInfo| {
+                    vk::SemaphoreSubmitInfo::default()
+                        .semaphore(s.semaphore.inner)
+                        .stage_mask(s.stage_mask)
+                };
+                let timeline_info = |s: &TimelineSemaphoreSubmitInfo| {
+                    vk::SemaphoreSubmitInfo::default()
+                        .semaphore(s.semaphore.inner)
+                        .value(s.value)
+                        .stage_mask(s.stage_mask)
+                };
+
+                let wait_infos = batch.wait_semaphores.iter().map(binary_info)
+                    .chain(batch.wait_timeline_semaphores.iter().map(timeline_info))
+                    .collect::<Vec<_>>();
+                let signal_infos = batch.signal_semaphores.iter().map(binary_info)
+                    .chain(batch.signal_timeline_semaphores.iter().map(timeline_info))
+                    .collect::<Vec<_>>();
+                let cmd_buffer_infos = batch.command_buffers.iter()
+                    .map(|c| vk::CommandBufferSubmitInfo::default().command_buffer(c.inner))
+                    .collect::<Vec<_>>();
+
+                (wait_infos, signal_infos, cmd_buffer_infos)
+            })
+            .collect::<Vec<_>>();
+
+        let submit_infos = infos.iter()
+            .map(|(wait_infos, signal_infos, cmd_buffer_infos)| {
+                vk::SubmitInfo2::default()
+                    .wait_semaphore_infos(wait_infos)
+                    .signal_semaphore_infos(signal_infos)
+                    .command_buffer_infos(cmd_buffer_infos)
+            })
+            .collect::<Vec<_>>();
+
+        let fence = fence.map(|f| f.inner).unwrap_or(vk::Fence::null());
 
         unsafe {
             #[cfg(any(vulkan_1_0, vulkan_1_1, vulkan_1_2))]
             self.synchronization2.queue_submit2(
                 self.inner,
-                std::slice::from_ref(&submit_info),
-                fence.inner
+                &submit_infos,
+                fence
             )?;
 
             #[cfg(vulkan_1_3)]
             self.device.inner.queue_submit2(
                 self.inner,
-                std::slice::from_ref(&submit_info),
-                fence.inner,
+                &submit_infos,
+                fence,
             )?
         };
 
@@ -138,6 +163,21 @@ impl Queue {
     }
 }
 
+#[derive(Default)]
+pub struct SubmitBatch<'a> {
+    pub command_buffers: Vec<&'a CommandBuffer>,
+    pub wait_semaphores: Vec<SemaphoreSubmitInfo<'a>>,
+    pub signal_semaphores: Vec<SemaphoreSubmitInfo<'a>>,
+    pub wait_timeline_semaphores: Vec<TimelineSemaphoreSubmitInfo<'a>>,
+    pub signal_timeline_semaphores: Vec<TimelineSemaphoreSubmitInfo<'a>>,
+}
+
+pub struct TimelineSemaphoreSubmitInfo<'a> {
+    pub semaphore: &'a TimelineSemaphore,
+    pub value: u64,
+    pub stage_mask: vk::PipelineStageFlags2,
+}
+
 pub struct SemaphoreSubmitInfo<'a> {
     pub semaphore: &'a Semaphore,
     pub stage_mask: vk::PipelineStageFlags2,
@@ -1,16 +1,32 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk;
 use gpu_allocator::MemoryLocation;
 
-use crate::{Buffer, Context, RayTracingContext};
+use crate::{vulkan::device::Device, Buffer, CommandBuffer, Context, RayTracingContext};
 
 pub struct AccelerationStructure {
     ray_tracing: Arc<RayTracingContext>,
     pub(crate) inner: vk::AccelerationStructureKHR,
     _buffer: Buffer,
     pub address: u64,
+    pub level: vk::AccelerationStructureTypeKHR,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+    pub size: vk::DeviceSize,
+    pub build_scratch_size: vk::DeviceSize,
+    pub update_scratch_size: vk::DeviceSize,
+}
+
+/// One build or refit recorded with CommandBuffer::build_acceleration_structures_batch.
+#[derive(Clone, Copy)]
+pub struct AccelerationStructureBuild<'a> {
+    pub acceleration_structure: &'a AccelerationStructure,
+    pub geometry: &'a [vk::AccelerationStructureGeometryKHR<'a>],
+    pub ranges: &'a [vk::AccelerationStructureBuildRangeInfoKHR],
+    /// Refits the previous build in place instead of rebuilding it, needs ALLOW_UPDATE.
+    /// Geometry count, types and primitive counts have to stay the same.
+    pub update: bool,
 }
 
 impl AccelerationStructure {
@@ -21,10 +37,43 @@ impl AccelerationStructure {
         as_geometry: &[vk::AccelerationStructureGeometryKHR],
         as_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
         max_primitive_counts: &[u32],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<Self> {
+        let acceleration_structure = Self::new_unbuilt(
+            context,
+            ray_tracing,
+            level,
+            as_geometry,
+            max_primitive_counts,
+            flags,
+        )?;
+
+        let build = AccelerationStructureBuild {
+            acceleration_structure: &acceleration_structure,
+            geometry: as_geometry,
+            ranges: as_ranges,
+            update: false,
+        };
+        let scratch_buffer = context.create_acceleration_structure_scratch_buffer(&[build])?;
+
+        context.execute_one_time_commands(|cmd_buffer| {
+            cmd_buffer.build_acceleration_structures_batch(&[build], &scratch_buffer);
+        })?;
+
+        Ok(acceleration_structure)
+    }
+
+    pub(crate) fn new_unbuilt(
+        context: &Context,
+        ray_tracing: Arc<RayTracingContext>,
+        level: vk::AccelerationStructureTypeKHR,
+        as_geometry: &[vk::AccelerationStructureGeometryKHR],
+        max_primitive_counts: &[u32],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
     ) -> Result<Self> {
         let build_geo_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
             .ty(level)
-            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .flags(flags)
             .geometries(as_geometry);
 
         let mut build_size = vk::AccelerationStructureBuildSizesInfoKHR::default();
@@ -39,16 +88,36 @@ impl AccelerationStructure {
                 )
         };
 
+        Self::new_with_size(
+            context,
+            ray_tracing,
+            level,
+            flags,
+            build_size.acceleration_structure_size,
+            build_size.build_scratch_size,
+            build_size.update_scratch_size,
+        )
+    }
+
+    fn new_with_size(
+        context: &Context,
+        ray_tracing: Arc<RayTracingContext>,
+        level: vk::AccelerationStructureTypeKHR,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+        size: vk::DeviceSize,
+        build_scratch_size: vk::DeviceSize,
+        update_scratch_size: vk::DeviceSize,
+    ) -> Result<Self> {
         let buffer = context.create_buffer(
             vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
                 | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
             MemoryLocation::GpuOnly,
-            build_size.acceleration_structure_size,
+            size,
         )?;
 
         let create_info = vk::AccelerationStructureCreateInfoKHR::default()
             .buffer(buffer.inner)
-            .size(build_size.acceleration_structure_size)
+            .size(size)
             .ty(level);
         let inner = unsafe {
             ray_tracing
@@ -56,27 +125,6 @@ impl AccelerationStructure {
                 .create_acceleration_structure(&create_info, None)?
         };
 
-        let scratch_buffer = context.create_buffer(
-            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
-            MemoryLocation::GpuOnly,
-            build_size.build_scratch_size,
-        )?;
-        let scratch_buffer_address = scratch_buffer.get_device_address();
-
-        let build_geo_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
-            .ty(level)
-            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
-            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-            .geometries(as_geometry)
-            .dst_acceleration_structure(inner)
-            .scratch_data(vk::DeviceOrHostAddressKHR {
-                device_address: scratch_buffer_address,
-            });
-
-        context.execute_one_time_commands(|cmd_buffer| {
-            cmd_buffer.build_acceleration_structures(&build_geo_info, as_ranges);
-        })?;
-
         let address_info =
             vk::AccelerationStructureDeviceAddressInfoKHR::default().acceleration_structure(inner);
         let address = unsafe {
@@ -90,16 +138,58 @@ impl AccelerationStructure {
             inner,
             _buffer: buffer,
             address,
+            level,
+            flags,
+            size,
+            build_scratch_size,
+            update_scratch_size,
         })
     }
 }
 
+/// Offsets of the builds in one scratch buffer and the size it needs.
+/// The size includes one alignment of padding, because the buffer address itself might not be aligned.
+fn scratch_offsets(builds: &[AccelerationStructureBuild], alignment: vk::DeviceSize) -> (Vec<vk::DeviceSize>, vk::DeviceSize) {
+    let mut size = 0;
+    let offsets = builds
+        .iter()
+        .map(|build| {
+            let offset = size;
+            let scratch_size = if build.update {
+                build.acceleration_structure.update_scratch_size
+            } else {
+                build.acceleration_structure.build_scratch_size
+            };
+            size = (size + scratch_size).next_multiple_of(alignment);
+            offset
+        })
+        .collect();
+
+    (offsets, size + alignment)
+}
+
 impl Context {
     pub fn create_bottom_level_acceleration_structure(
         &self,
         as_geometry: &[vk::AccelerationStructureGeometryKHR],
         as_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
         max_primitive_counts: &[u32],
+    ) -> Result<AccelerationStructure> {
+        self.create_bottom_level_acceleration_structure_complex(
+            as_geometry,
+            as_ranges,
+            max_primitive_counts,
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+        )
+    }
+
+    /// Builds right away and waits for it, use create_acceleration_structure to record the build yourself.
+    pub fn create_bottom_level_acceleration_structure_complex(
+        &self,
+        as_geometry: &[vk::AccelerationStructureGeometryKHR],
+        as_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
+        max_primitive_counts: &[u32],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
     ) -> Result<AccelerationStructure> {
         let ray_tracing = self.ray_tracing.clone().expect(
             "Cannot call Context::create_bottom_level_acceleration_structure when ray tracing is not enabled",
@@ -112,6 +202,7 @@ impl Context {
             as_geometry,
             as_ranges,
             max_primitive_counts,
+            flags,
         )
     }
 
@@ -120,6 +211,22 @@ impl Context {
         as_geometry: &[vk::AccelerationStructureGeometryKHR],
         as_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
         max_primitive_counts: &[u32],
+    ) -> Result<AccelerationStructure> {
+        self.create_top_level_acceleration_structure_complex(
+            as_geometry,
+            as_ranges,
+            max_primitive_counts,
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+        )
+    }
+
+    /// Builds right away and waits for it, use create_acceleration_structure to record the build yourself.
+    pub fn create_top_level_acceleration_structure_complex(
+        &self,
+        as_geometry: &[vk::AccelerationStructureGeometryKHR],
+        as_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
+        max_primitive_counts: &[u32],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
     ) -> Result<AccelerationStructure> {
         let ray_tracing = self.ray_tracing.clone().expect(
             "Cannot call Context::create_top_level_acceleration_structure when ray tracing is not enabled",
@@ -132,8 +239,222 @@ impl Context {
             as_geometry,
             as_ranges,
             max_primitive_counts,
+            flags,
+        )
+    }
+
+    /// Allocates the acceleration structure without building it.
+    /// Record the build with CommandBuffer::build_acceleration_structures_batch.
+    /// Later builds and refits can use up to max_primitive_counts primitives per geometry.
+    pub fn create_acceleration_structure(
+        &self,
+        level: vk::AccelerationStructureTypeKHR,
+        as_geometry: &[vk::AccelerationStructureGeometryKHR],
+        max_primitive_counts: &[u32],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<AccelerationStructure> {
+        let ray_tracing = self.ray_tracing.clone().expect(
+            "Cannot call Context::create_acceleration_structure when ray tracing is not enabled",
+        );
+
+        AccelerationStructure::new_unbuilt(
+            self,
+            ray_tracing,
+            level,
+            as_geometry,
+            max_primitive_counts,
+            flags,
         )
     }
+
+    /// Scratch buffer big enough for all builds at once. Can be reused for other batches that fit.
+    pub fn create_acceleration_structure_scratch_buffer(
+        &self,
+        builds: &[AccelerationStructureBuild],
+    ) -> Result<Buffer> {
+        let ray_tracing = self.ray_tracing.as_ref().expect(
+            "Cannot call Context::create_acceleration_structure_scratch_buffer when ray tracing is not enabled",
+        );
+        let alignment = ray_tracing
+            .acceleration_structure_properties
+            .min_acceleration_structure_scratch_offset_alignment as vk::DeviceSize;
+        let (_, size) = scratch_offsets(builds, alignment);
+
+        self.create_buffer(
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::GpuOnly,
+            size,
+        )
+    }
+
+    pub fn create_acceleration_structure_query_pool(&self, count: u32) -> Result<AccelerationStructureQueryPool> {
+        AccelerationStructureQueryPool::new(self.device.clone(), count)
+    }
+
+    /// Allocates the destination for CommandBuffer::copy_acceleration_structure with COMPACT mode.
+    pub fn create_compacted_acceleration_structure(
+        &self,
+        src: &AccelerationStructure,
+        compacted_size: vk::DeviceSize,
+    ) -> Result<AccelerationStructure> {
+        AccelerationStructure::new_with_size(
+            self,
+            src.ray_tracing.clone(),
+            src.level,
+            src.flags,
+            compacted_size,
+            src.build_scratch_size,
+            src.update_scratch_size,
+        )
+    }
+
+    /// Queries the compacted sizes and copies the structures into new smaller ones, waiting for both steps.
+    /// The structures have to be built with ALLOW_COMPACTION and the builds have to be finished.
+    pub fn compact_acceleration_structures(
+        &self,
+        structures: &[&AccelerationStructure],
+    ) -> Result<Vec<AccelerationStructure>> {
+        if structures.is_empty() {
+            return Ok(vec![]);
+        }
+
+        if let Some(s) = structures
+            .iter()
+            .find(|s| !s.flags.contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION))
+        {
+            bail!("Acceleration structure was not built with ALLOW_COMPACTION, flags: {:?}", s.flags);
+        }
+
+        let query_pool = self.create_acceleration_structure_query_pool(structures.len() as _)?;
+        self.execute_one_time_commands(|cmd_buffer| {
+            cmd_buffer.write_acceleration_structures_compacted_size(structures, &query_pool);
+        })?;
+        let compacted_sizes = query_pool.wait_for_results()?;
+
+        let compacted = structures
+            .iter()
+            .zip(compacted_sizes)
+            .map(|(s, size)| self.create_compacted_acceleration_structure(s, size))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.execute_one_time_commands(|cmd_buffer| {
+            for (src, dst) in structures.iter().zip(compacted.iter()) {
+                cmd_buffer.copy_acceleration_structure(src, dst, vk::CopyAccelerationStructureModeKHR::COMPACT);
+            }
+        })?;
+
+        Ok(compacted)
+    }
+}
+
+impl CommandBuffer {
+    /// Records all builds and refits with one vkCmdBuildAccelerationStructuresKHR, sharing the scratch buffer.
+    /// The builds must not depend on each other, put a barrier between the BLAS and the TLAS batch.
+    pub fn build_acceleration_structures_batch(
+        &self,
+        builds: &[AccelerationStructureBuild],
+        scratch_buffer: &Buffer,
+    ) {
+        let ray_tracing = self.ray_tracing.as_ref().expect(
+            "Cannot call CommandBuffer::build_acceleration_structures_batch when ray tracing is not enabled",
+        );
+        let alignment = ray_tracing
+            .acceleration_structure_properties
+            .min_acceleration_structure_scratch_offset_alignment as vk::DeviceSize;
+        let (offsets, size) = scratch_offsets(builds, alignment);
+        assert!(
+            scratch_buffer.size >= size,
+            "Scratch buffer of size {} is too small for the batch, it needs {size}",
+            scratch_buffer.size
+        );
+        let scratch_address = scratch_buffer.get_device_address().next_multiple_of(alignment);
+
+        let build_infos = builds
+            .iter()
+            .zip(offsets)
+            .map(|(build, offset)| {
+                let acceleration_structure = build.acceleration_structure;
+                let mut info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+                    .ty(acceleration_structure.level)
+                    .flags(acceleration_structure.flags)
+                    .geometries(build.geometry)
+                    .dst_acceleration_structure(acceleration_structure.inner)
+                    .scratch_data(vk::DeviceOrHostAddressKHR {
+                        device_address: scratch_address + offset,
+                    });
+
+                if build.update {
+                    assert!(
+                        acceleration_structure.flags.contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE),
+                        "Acceleration structure refits need ALLOW_UPDATE"
+                    );
+                    info = info
+                        .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+                        .src_acceleration_structure(acceleration_structure.inner);
+                } else {
+                    info = info.mode(vk::BuildAccelerationStructureModeKHR::BUILD);
+                }
+                info
+            })
+            .collect::<Vec<_>>();
+        let ranges = builds.iter().map(|build| build.ranges).collect::<Vec<_>>();
+
+        unsafe {
+            ray_tracing
+                .acceleration_structure_fn
+                .cmd_build_acceleration_structures(self.inner, &build_infos, &ranges)
+        };
+    }
+
+    /// Resets the query pool and writes the compacted size of structure i into query i.
+    /// Needs a barrier after the builds of the structures when they are recorded in the same command buffer.
+    pub fn write_acceleration_structures_compacted_size(
+        &self,
+        structures: &[&AccelerationStructure],
+        query_pool: &AccelerationStructureQueryPool,
+    ) {
+        let ray_tracing = self.ray_tracing.as_ref().expect(
+            "Cannot call CommandBuffer::write_acceleration_structures_compacted_size when ray tracing is not enabled",
+        );
+        assert!(structures.len() as u32 <= query_pool.count, "Query pool is too small");
+
+        let structures = structures.iter().map(|s| s.inner).collect::<Vec<_>>();
+        unsafe {
+            self.device
+                .inner
+                .cmd_reset_query_pool(self.inner, query_pool.inner, 0, query_pool.count);
+            ray_tracing
+                .acceleration_structure_fn
+                .cmd_write_acceleration_structures_properties(
+                    self.inner,
+                    &structures,
+                    vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                    query_pool.inner,
+                    0,
+                )
+        };
+    }
+
+    pub fn copy_acceleration_structure(
+        &self,
+        src: &AccelerationStructure,
+        dst: &AccelerationStructure,
+        mode: vk::CopyAccelerationStructureModeKHR,
+    ) {
+        let ray_tracing = self.ray_tracing.as_ref().expect(
+            "Cannot call CommandBuffer::copy_acceleration_structure when ray tracing is not enabled",
+        );
+
+        let copy_info = vk::CopyAccelerationStructureInfoKHR::default()
+            .src(src.inner)
+            .dst(dst.inner)
+            .mode(mode);
+        unsafe {
+            ray_tracing
+                .acceleration_structure_fn
+                .cmd_copy_acceleration_structure(self.inner, &copy_info)
+        };
+    }
 }
 
 impl Drop for AccelerationStructure {
@@ -145,3 +466,46 @@ impl Drop for AccelerationStructure {
         }
     }
 }
+
+/// Query pool for the compacted sizes of acceleration structures.
+#[derive(Debug)]
+pub struct AccelerationStructureQueryPool {
+    device: Arc<Device>,
+    pub(crate) inner: vk::QueryPool,
+    pub count: u32,
+}
+
+impl AccelerationStructureQueryPool {
+    pub(crate) fn new(device: Arc<Device>, count: u32) -> Result<Self> {
+        let create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+            .query_count(count);
+
+        let inner = unsafe { device.inner.create_query_pool(&create_info, None)? };
+
+        Ok(Self { device, inner, count })
+    }
+
+    pub fn wait_for_results(&self) -> Result<Vec<u64>> {
+        let mut data = vec![0u64; self.count as usize];
+
+        unsafe {
+            self.device.inner.get_query_pool_results(
+                self.inner,
+                0,
+                &mut data,
+                vk::QueryResultFlags::WAIT | vk::QueryResultFlags::TYPE_64,
+            )?;
+        }
+
+        Ok(data)
+    }
+}
+
+impl Drop for AccelerationStructureQueryPool {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.inner.destroy_query_pool(self.inner, None);
+        }
+    }
+}
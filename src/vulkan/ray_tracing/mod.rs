@@ -1,12 +1,14 @@
 mod acceleration_structure;
 mod pipeline;
 mod shader_binding_table;
+mod tlas_builder;
 
 use std::fmt;
 
 pub use acceleration_structure::*;
 pub use pipeline::*;
 pub use shader_binding_table::*;
+pub use tlas_builder::*;
 
 use ash::{
     khr::{acceleration_structure as ash_accel_structure, ray_tracing_pipeline},
@@ -15,30 +17,48 @@ use ash::{
 
 use crate::vulkan::{device::Device, instance::Instance, physical_device::PhysicalDevice};
 
+/// Acceleration structures, with the ray tracing pipeline and ray queries when they are enabled.
 pub struct RayTracingContext {
-    pub pipeline_properties: PhysicalDeviceRayTracingPipelineProperties,
-    pub pipeline_fn: ray_tracing_pipeline::Device,
+    /// None when only ray queries are enabled.
+    pub pipeline: Option<RayTracingPipelineContext>,
     pub acceleration_structure_properties: PhysicalDeviceAccelerationStructureProperties,
     pub acceleration_structure_fn: ash_accel_structure::Device,
+    /// VK_KHR_ray_query, for tracing from any shader stage without a shader binding table.
+    pub ray_query: bool,
+}
+
+pub struct RayTracingPipelineContext {
+    pub properties: PhysicalDeviceRayTracingPipelineProperties,
+    pub pipeline_fn: ray_tracing_pipeline::Device,
 }
 
 unsafe impl Send for RayTracingContext {}
 unsafe impl Sync for RayTracingContext {}
 
 impl RayTracingContext {
-    pub(crate) fn new(instance: &Instance, pdevice: &PhysicalDevice, device: &Device) -> Self {
-        // get rt pipeline properties
-        let mut pipeline_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
-        let mut pproperties2 =
-            vk::PhysicalDeviceProperties2::default().push_next(&mut pipeline_properties);
-        unsafe {
-            instance
-                .inner
-                .get_physical_device_properties2(pdevice.inner, &mut pproperties2)
-        };
-        let pipeline_properties = pipeline_properties.into();
-
-        let pipeline_fn = ray_tracing_pipeline::Device::new(&instance.inner, &device.inner);
+    pub(crate) fn new(
+        instance: &Instance,
+        pdevice: &PhysicalDevice,
+        device: &Device,
+        ray_tracing_pipeline: bool,
+        ray_query: bool,
+    ) -> Self {
+        let pipeline = ray_tracing_pipeline.then(|| {
+            // get rt pipeline properties
+            let mut pipeline_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+            let mut pproperties2 =
+                vk::PhysicalDeviceProperties2::default().push_next(&mut pipeline_properties);
+            unsafe {
+                instance
+                    .inner
+                    .get_physical_device_properties2(pdevice.inner, &mut pproperties2)
+            };
+
+            RayTracingPipelineContext {
+                properties: pipeline_properties.into(),
+                pipeline_fn: ray_tracing_pipeline::Device::new(&instance.inner, &device.inner),
+            }
+        });
 
         // get rt acceleration structure properties
         let mut acceleration_structure_properties =
@@ -56,12 +76,18 @@ impl RayTracingContext {
             ash_accel_structure::Device::new(&instance.inner, &device.inner);
 
         Self {
-            pipeline_properties,
-            pipeline_fn,
+            pipeline,
             acceleration_structure_properties,
             acceleration_structure_fn,
+            ray_query,
         }
     }
+
+    pub(crate) fn pipeline(&self, name: &str) -> &RayTracingPipelineContext {
+        self.pipeline.as_ref().unwrap_or_else(|| {
+            panic!("Cannot call {name} when the ray tracing pipeline is not enabled")
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -130,10 +156,19 @@ impl From<vk::PhysicalDeviceAccelerationStructurePropertiesKHR<'_>>
 impl fmt::Debug for RayTracingContext {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("RayTracingContext")
-            .field("pipeline_properties", &self.pipeline_properties)
-            .field("pipeline_fn", &())
+            .field("pipeline", &self.pipeline)
             .field("acceleration_structure_properties", &self.acceleration_structure_properties)
             .field("acceleration_structure_fn", &())
+            .field("ray_query", &self.ray_query)
+            .finish()
+    }
+}
+
+impl fmt::Debug for RayTracingPipelineContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RayTracingPipelineContext")
+            .field("properties", &self.properties)
+            .field("pipeline_fn", &())
             .finish()
     }
 }
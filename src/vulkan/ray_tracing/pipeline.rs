@@ -1,36 +1,93 @@
-use std::{ffi::CString, sync::Arc};
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk;
 
 use crate::{vulkan::device::Device, Context};
 
-use crate::{PipelineLayout, RayTracingContext, ShaderModule};
+use crate::vulkan::pipeline::entry_point_name;
+use crate::{PipelineCache, PipelineLayout, RayTracingPipelineContext, ShaderModule, SpecializationConstants};
 
 #[derive(Debug, Clone, Copy)]
 pub struct RayTracingPipelineCreateInfo<'a> {
     pub shaders: &'a [RayTracingShaderCreateInfo<'a>],
+    /// The shader binding table lists the groups of each kind in this order,
+    /// so the index of a miss or hit group among its kind is the missIndex or sbtRecordOffset.
+    pub groups: &'a [RayTracingShaderGroup],
     pub max_ray_recursion_depth: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct RayTracingShaderCreateInfo<'a> {
     pub source: &'a [u8],
     pub stage: vk::ShaderStageFlags,
-    pub group: RayTracingShaderGroup,
+    /// None uses "main".
+    pub entry_point: Option<&'a str>,
+    pub specialization: Option<&'a SpecializationConstants>,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl<'a> RayTracingShaderCreateInfo<'a> {
+    /// Uses "main" without specialization constants.
+    pub fn new(source: &'a [u8], stage: vk::ShaderStageFlags) -> Self {
+        Self {
+            source,
+            stage,
+            entry_point: None,
+            specialization: None,
+        }
+    }
+}
+
+/// Shaders are indices into RayTracingPipelineCreateInfo::shaders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RayTracingShaderGroup {
+    RayGen(u32),
+    Miss(u32),
+    Callable(u32),
+    TrianglesHit {
+        closest_hit: Option<u32>,
+        any_hit: Option<u32>,
+    },
+    /// Hit group for AABB geometry.
+    ProceduralHit {
+        intersection: u32,
+        closest_hit: Option<u32>,
+        any_hit: Option<u32>,
+    },
+}
+
+impl RayTracingShaderGroup {
+    pub fn closest_hit(shader: u32) -> Self {
+        Self::TrianglesHit {
+            closest_hit: Some(shader),
+            any_hit: None,
+        }
+    }
+
+    pub(crate) fn kind(&self) -> ShaderGroupKind {
+        match self {
+            Self::RayGen(_) => ShaderGroupKind::RayGen,
+            Self::Miss(_) => ShaderGroupKind::Miss,
+            Self::Callable(_) => ShaderGroupKind::Callable,
+            Self::TrianglesHit { .. } | Self::ProceduralHit { .. } => ShaderGroupKind::Hit,
+        }
+    }
+}
+
+/// The regions of the shader binding table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShaderGroupKind {
     RayGen,
     Miss,
-    ClosestHit,
+    Hit,
+    Callable,
 }
 
 pub struct RayTracingPipeline {
     device: Arc<Device>,
     pub(crate) inner: vk::Pipeline,
     pub(crate) shader_group_info: RayTracingShaderGroupInfo,
+    pub(crate) groups: Vec<RayTracingShaderGroup>,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -39,72 +96,120 @@ pub struct RayTracingShaderGroupInfo {
     pub raygen_shader_count: u32,
     pub miss_shader_count: u32,
     pub hit_shader_count: u32,
+    pub callable_shader_count: u32,
 }
 
 impl RayTracingPipeline {
     pub(crate) fn new(
         device: Arc<Device>,
-        ray_tracing: &RayTracingContext,
+        cache: &PipelineCache,
+        ray_tracing: &RayTracingPipelineContext,
         layout: &PipelineLayout,
         create_info: RayTracingPipelineCreateInfo,
     ) -> Result<Self> {
         let mut shader_group_info = RayTracingShaderGroupInfo {
-            group_count: create_info.shaders.len() as _,
+            group_count: create_info.groups.len() as _,
             ..Default::default()
         };
 
+        let entry_point_names = create_info.shaders
+            .iter()
+            .map(|shader| entry_point_name(shader.entry_point))
+            .collect::<Result<Vec<_>>>()?;
+        let specialization_infos = create_info.shaders
+            .iter()
+            .map(|shader| shader.specialization.map(|s| s.info()))
+            .collect::<Vec<_>>();
+
         let mut modules = vec![];
         let mut stages = vec![];
-        let mut groups = vec![];
-
-        let entry_point_name = CString::new("main").unwrap();
-
         for (shader_index, shader) in create_info.shaders.iter().enumerate() {
             let module = ShaderModule::from_bytes(device.clone(), shader.source)?;
 
-            let stage = vk::PipelineShaderStageCreateInfo::default()
+            let mut stage = vk::PipelineShaderStageCreateInfo::default()
                 .stage(shader.stage)
                 .module(module.inner)
-                .name(&entry_point_name);
+                .name(&entry_point_names[shader_index]);
+            if let Some(specialization_info) = &specialization_infos[shader_index] {
+                stage = stage.specialization_info(specialization_info);
+            }
 
-            match shader.group {
-                RayTracingShaderGroup::RayGen => shader_group_info.raygen_shader_count += 1,
-                RayTracingShaderGroup::Miss => shader_group_info.miss_shader_count += 1,
-                RayTracingShaderGroup::ClosestHit => shader_group_info.hit_shader_count += 1,
-            };
+            modules.push(module);
+            stages.push(stage);
+        }
 
-            let mut group = vk::RayTracingShaderGroupCreateInfoKHR::default()
-                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
-                .general_shader(vk::SHADER_UNUSED_KHR)
-                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
-                .any_hit_shader(vk::SHADER_UNUSED_KHR)
-                .intersection_shader(vk::SHADER_UNUSED_KHR);
-            group = match shader.group {
-                RayTracingShaderGroup::RayGen | RayTracingShaderGroup::Miss => {
-                    group.general_shader(shader_index as _)
-                }
-                RayTracingShaderGroup::ClosestHit => group
-                    .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
-                    .closest_hit_shader(shader_index as _),
+        let shader = |index: Option<u32>, stage: vk::ShaderStageFlags| -> Result<u32> {
+            let Some(index) = index else {
+                return Ok(vk::SHADER_UNUSED_KHR);
+            };
+            let Some(shader) = create_info.shaders.get(index as usize) else {
+                bail!("Shader group references shader {index}, but there are only {} shaders", create_info.shaders.len());
             };
+            if shader.stage != stage {
+                bail!("Shader {index} is used as {stage:?} in a shader group, but has stage {:?}", shader.stage);
+            }
+            Ok(index)
+        };
 
-            modules.push(module);
-            stages.push(stage);
-            groups.push(group);
+        let groups = create_info.groups
+            .iter()
+            .map(|group| {
+                let info = vk::RayTracingShaderGroupCreateInfoKHR::default()
+                    .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                    .general_shader(vk::SHADER_UNUSED_KHR)
+                    .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .intersection_shader(vk::SHADER_UNUSED_KHR);
+
+                let info = match *group {
+                    RayTracingShaderGroup::RayGen(index) => {
+                        shader_group_info.raygen_shader_count += 1;
+                        info.general_shader(shader(Some(index), vk::ShaderStageFlags::RAYGEN_KHR)?)
+                    }
+                    RayTracingShaderGroup::Miss(index) => {
+                        shader_group_info.miss_shader_count += 1;
+                        info.general_shader(shader(Some(index), vk::ShaderStageFlags::MISS_KHR)?)
+                    }
+                    RayTracingShaderGroup::Callable(index) => {
+                        shader_group_info.callable_shader_count += 1;
+                        info.general_shader(shader(Some(index), vk::ShaderStageFlags::CALLABLE_KHR)?)
+                    }
+                    RayTracingShaderGroup::TrianglesHit { closest_hit, any_hit } => {
+                        shader_group_info.hit_shader_count += 1;
+                        info.ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+                            .closest_hit_shader(shader(closest_hit, vk::ShaderStageFlags::CLOSEST_HIT_KHR)?)
+                            .any_hit_shader(shader(any_hit, vk::ShaderStageFlags::ANY_HIT_KHR)?)
+                    }
+                    RayTracingShaderGroup::ProceduralHit { intersection, closest_hit, any_hit } => {
+                        shader_group_info.hit_shader_count += 1;
+                        info.ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
+                            .intersection_shader(shader(Some(intersection), vk::ShaderStageFlags::INTERSECTION_KHR)?)
+                            .closest_hit_shader(shader(closest_hit, vk::ShaderStageFlags::CLOSEST_HIT_KHR)?)
+                            .any_hit_shader(shader(any_hit, vk::ShaderStageFlags::ANY_HIT_KHR)?)
+                    }
+                };
+                Ok(info)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let max_recursion_depth = ray_tracing.properties.max_ray_recursion_depth;
+        if create_info.max_ray_recursion_depth > max_recursion_depth {
+            bail!("Max ray recursion depth {} is higher than the device limit {max_recursion_depth}", create_info.max_ray_recursion_depth);
         }
 
         let pipe_info = vk::RayTracingPipelineCreateInfoKHR::default()
             .layout(layout.inner)
+            .flags(layout.pipeline_create_flags())
             .stages(&stages)
             .groups(&groups)
-            .max_pipeline_ray_recursion_depth(2);
+            .max_pipeline_ray_recursion_depth(create_info.max_ray_recursion_depth);
 
         let inner = unsafe {
             ray_tracing
                 .pipeline_fn
                 .create_ray_tracing_pipelines(
                     vk::DeferredOperationKHR::null(),
-                    vk::PipelineCache::null(),
+                    cache.inner,
                     std::slice::from_ref(&pipe_info),
                     None,
                 )
@@ -115,6 +220,7 @@ impl RayTracingPipeline {
             device,
             inner,
             shader_group_info,
+            groups: create_info.groups.to_vec(),
         })
     }
 }
@@ -127,9 +233,9 @@ impl Context {
     ) -> Result<RayTracingPipeline> {
         let ray_tracing = self.ray_tracing.as_ref().expect(
             "Cannot call Context::create_ray_tracing_pipeline when ray tracing is not enabled",
-        );
+        ).pipeline("Context::create_ray_tracing_pipeline");
 
-        RayTracingPipeline::new(self.device.clone(), ray_tracing, layout, create_info)
+        RayTracingPipeline::new(self.device.clone(), &self.pipeline_cache, ray_tracing, layout, create_info)
     }
 }
 
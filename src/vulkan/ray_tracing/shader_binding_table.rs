@@ -1,33 +1,41 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk;
 use gpu_allocator::MemoryLocation;
 
-use crate::{vulkan::utils::compute_aligned_size, Buffer, Context, RayTracingContext, RayTracingPipeline};
+use crate::{vulkan::utils::compute_aligned_size, Buffer, Context, RayTracingPipelineContext, RayTracingPipeline};
+use crate::vulkan::ray_tracing::pipeline::ShaderGroupKind;
 
 pub struct ShaderBindingTable {
-    _buffer: Buffer,
+    buffer: Buffer,
+    handle_size: u32,
+    /// Offset into the buffer and size of the shader record data of each group, in pipeline group order.
+    records: Vec<(usize, usize)>,
     pub(crate) raygen_region: vk::StridedDeviceAddressRegionKHR,
+    raygen_count: u32,
     pub(crate) miss_region: vk::StridedDeviceAddressRegionKHR,
     pub(crate) hit_region: vk::StridedDeviceAddressRegionKHR,
+    pub(crate) callable_region: vk::StridedDeviceAddressRegionKHR,
 }
 
 impl ShaderBindingTable {
     pub(crate) fn new(
         context: &Context,
-        ray_tracing: &RayTracingContext,
+        ray_tracing: &RayTracingPipelineContext,
         pipeline: &RayTracingPipeline,
+        group_data: &[&[u8]],
     ) -> Result<Self> {
         let desc = pipeline.shader_group_info;
+        if group_data.len() > desc.group_count as usize {
+            bail!("Shader record data for {} groups, but the pipeline has {} groups", group_data.len(), desc.group_count);
+        }
 
         // Handle size & aligment
-        let handle_size = ray_tracing.pipeline_properties.shader_group_handle_size;
+        let handle_size = ray_tracing.properties.shader_group_handle_size;
         let handle_alignment = ray_tracing
-            .pipeline_properties
+            .properties
             .shader_group_handle_alignment;
-        let aligned_handle_size = compute_aligned_size(handle_size, handle_alignment);
-        let handle_pad = aligned_handle_size - handle_size;
-
-        let group_alignment = ray_tracing.pipeline_properties.shader_group_base_alignment;
+        let group_alignment = ray_tracing.properties.shader_group_base_alignment;
+        let max_stride = ray_tracing.properties.max_shader_group_stride;
 
         // Get Handles
         let data_size = desc.group_count * handle_size;
@@ -42,100 +50,156 @@ impl ShaderBindingTable {
                 )?
         };
 
-        // Region sizes
-        let raygen_region_size = compute_aligned_size(
-            desc.raygen_shader_count * aligned_handle_size,
-            group_alignment,
-        );
-
-        let miss_region_size = compute_aligned_size(
-            desc.miss_shader_count * aligned_handle_size,
-            group_alignment,
-        );
-        let hit_region_size =
-            compute_aligned_size(desc.hit_shader_count * aligned_handle_size, group_alignment);
-
-        // Create sbt data
-        let buffer_size = raygen_region_size + miss_region_size + hit_region_size;
-        let mut stb_data = Vec::<u8>::with_capacity(buffer_size as _);
-        let groups_shader_count = [
-            desc.raygen_shader_count,
-            desc.miss_shader_count,
-            desc.hit_shader_count,
+        // Regions in the order raygen, miss, hit, callable, each starting at the group base alignment.
+        // A record is the handle followed by the shader record data, the stride fits the biggest record of the region.
+        let kinds = [
+            ShaderGroupKind::RayGen,
+            ShaderGroupKind::Miss,
+            ShaderGroupKind::Hit,
+            ShaderGroupKind::Callable,
         ];
+        let mut records = vec![(0, 0); desc.group_count as usize];
+        let mut regions = [(0u32, 0u32, 0u32); 4];
+        let mut region_offset = 0;
+        for (region, kind) in regions.iter_mut().zip(kinds) {
+            let groups = pipeline.groups
+                .iter()
+                .enumerate()
+                .filter(|(_, group)| group.kind() == kind)
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+
+            let max_data_size = groups
+                .iter()
+                .map(|&index| group_data.get(index).map_or(0, |data| data.len()))
+                .max()
+                .unwrap_or(0) as u32;
+            // trace_rays_complex offsets the raygen region by whole records, each has to start at the group base alignment
+            let record_alignment = if kind == ShaderGroupKind::RayGen { group_alignment } else { handle_alignment };
+            let stride = compute_aligned_size(handle_size + max_data_size, record_alignment);
+            if stride > max_stride {
+                bail!("{kind:?} shader records need a stride of {stride}, but the device limit is {max_stride}");
+            }
 
-        let mut offset = 0;
-        // for each groups
-        for group_shader_count in groups_shader_count {
-            let group_size = group_shader_count * aligned_handle_size;
-            let aligned_group_size = compute_aligned_size(group_size, group_alignment);
-            let group_pad = aligned_group_size - group_size;
-
-            // for each handle
-            for _ in 0..group_shader_count {
-                //copy handle
-                for _ in 0..handle_size as usize {
-                    stb_data.push(handles[offset]);
-                    offset += 1;
-                }
-
-                // pad handle to alignment
-                for _ in 0..handle_pad {
-                    stb_data.push(0);
-                }
+            for (i, &index) in groups.iter().enumerate() {
+                let record_offset = region_offset + i as u32 * stride;
+                records[index] = (record_offset as usize, (stride - handle_size) as usize);
             }
 
-            // pad group to alignment
-            for _ in 0..group_pad {
-                stb_data.push(0);
+            let size = compute_aligned_size(groups.len() as u32 * stride, group_alignment);
+            *region = (region_offset, size, stride);
+            region_offset += size;
+        }
+
+        // Create sbt data
+        let mut stb_data = vec![0u8; region_offset as usize];
+        for (index, &(offset, _)) in records.iter().enumerate() {
+            let handle = &handles[index * handle_size as usize..(index + 1) * handle_size as usize];
+            stb_data[offset..offset + handle.len()].copy_from_slice(handle);
+
+            if let Some(data) = group_data.get(index) {
+                let data_offset = offset + handle_size as usize;
+                stb_data[data_offset..data_offset + data.len()].copy_from_slice(data);
             }
         }
 
-        // Create buffer
+        // Create buffer, with room to move the start to the group base alignment
         let buffer_usage = vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR
             | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
         let memory_location = MemoryLocation::CpuToGpu;
 
-        let buffer = context.create_buffer(buffer_usage, memory_location, buffer_size as _)?;
-
-        buffer.copy_data_to_buffer(&stb_data);
+        let buffer_size = region_offset.max(1) + group_alignment;
+        let mut buffer = context.create_buffer(buffer_usage, memory_location, buffer_size as _)?;
 
-        let address = buffer.get_device_address();
+        let buffer_address = buffer.get_device_address();
+        let address = buffer_address.next_multiple_of(group_alignment as u64);
+        let start = (address - buffer_address) as usize;
+        buffer.copy_data_to_buffer_without_aligment(&stb_data, start);
+        for (offset, _) in records.iter_mut() {
+            *offset += start;
+        }
 
         // see https://nvpro-samples.github.io/vk_raytracing_tutorial_KHR/Images/sbt_0.png
-        let raygen_region = vk::StridedDeviceAddressRegionKHR::default()
-            .device_address(address)
-            .size(raygen_region_size as _)
-            .stride(raygen_region_size as _);
+        let region = |(offset, size, stride): (u32, u32, u32)| {
+            if size == 0 {
+                return vk::StridedDeviceAddressRegionKHR::default();
+            }
 
-        let miss_region = vk::StridedDeviceAddressRegionKHR::default()
-            .device_address(address + raygen_region.size)
-            .size(miss_region_size as _)
-            .stride(aligned_handle_size as _);
+            vk::StridedDeviceAddressRegionKHR::default()
+                .device_address(address + offset as u64)
+                .size(size as _)
+                .stride(stride as _)
+        };
 
-        let hit_region = vk::StridedDeviceAddressRegionKHR::default()
-            .device_address(address + raygen_region.size + miss_region.size)
-            .size(hit_region_size as _)
-            .stride(aligned_handle_size as _);
+        // The raygen region is a single record, trace_rays_complex picks one by index
+        let mut raygen_region = region(regions[0]);
+        raygen_region.size = raygen_region.stride;
+        let raygen_count = pipeline.groups
+            .iter()
+            .filter(|group| group.kind() == ShaderGroupKind::RayGen)
+            .count() as u32;
 
         Ok(Self {
-            _buffer: buffer,
+            buffer,
+            handle_size,
+            records,
             raygen_region,
-            miss_region,
-            hit_region,
+            raygen_count,
+            miss_region: region(regions[1]),
+            hit_region: region(regions[2]),
+            callable_region: region(regions[3]),
         })
     }
+
+    /// Overwrites the shader record data of a group, index in pipeline group order.
+    /// The table must not be in use by a frame in flight.
+    pub fn write_record_data(&mut self, group_index: usize, data: &[u8]) -> Result<()> {
+        let Some(&(offset, max_size)) = self.records.get(group_index) else {
+            bail!("Group {group_index} out of range, the pipeline has {} groups", self.records.len());
+        };
+        if data.len() > max_size {
+            bail!("Shader record data of {} bytes does not fit into the record of group {group_index}, max is {max_size}", data.len());
+        }
+
+        self.buffer.copy_data_to_buffer_without_aligment(data, offset + self.handle_size as usize);
+        Ok(())
+    }
+
+    pub fn raygen_count(&self) -> u32 {
+        self.raygen_count
+    }
+
+    pub(crate) fn raygen_region(&self, raygen_index: u32) -> vk::StridedDeviceAddressRegionKHR {
+        assert!(
+            raygen_index < self.raygen_count,
+            "Raygen index {raygen_index} out of range, the pipeline has {} raygen groups",
+            self.raygen_count
+        );
+        let mut region = self.raygen_region;
+        region.device_address += raygen_index as u64 * region.stride;
+        region
+    }
 }
 
 impl Context {
     pub fn create_shader_binding_table(
         &self,
         pipeline: &RayTracingPipeline,
+    ) -> Result<ShaderBindingTable> {
+        self.create_shader_binding_table_complex(pipeline, &[])
+    }
+
+    /// group_data is the shader record data of each group in pipeline group order,
+    /// read in shaders with shaderRecordEXT. Groups without an entry get no data.
+    pub fn create_shader_binding_table_complex(
+        &self,
+        pipeline: &RayTracingPipeline,
+        group_data: &[&[u8]],
     ) -> Result<ShaderBindingTable> {
         let ray_tracing = self.ray_tracing.as_ref().expect(
             "Cannot call Context::create_shader_binding_table when ray tracing is not enabled",
-        );
+        ).pipeline("Context::create_shader_binding_table");
 
-        ShaderBindingTable::new(self, ray_tracing, pipeline)
+        ShaderBindingTable::new(self, ray_tracing, pipeline, group_data)
     }
 }
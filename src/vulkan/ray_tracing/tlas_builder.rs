@@ -0,0 +1,277 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use ash::vk;
+use glam::Affine3A;
+use gpu_allocator::MemoryLocation;
+
+use crate::in_flight_frames::InFlightFrames;
+use crate::vulkan::{
+    AccelerationStructure, AccelerationStructureBuild, Buffer, CommandBuffer, Context, DeletionQueue, MemoryBarrier,
+};
+
+/// One instance of a BLAS in the TLAS.
+/// The builder keeps the BLAS alive until no frame in flight can trace against it anymore.
+#[derive(Clone)]
+pub struct TlasInstance {
+    pub blas: Arc<AccelerationStructure>,
+    /// Use Affine3A::from_mat4 for a Mat4.
+    pub transform: Affine3A,
+    /// gl_InstanceCustomIndexEXT, only the lower 24 bits are used.
+    pub instance_id: u32,
+    /// Compared against the cull mask of traceRayEXT.
+    pub mask: u8,
+    /// Offset of the hit group records of the instance in the shader binding table, 24 bits.
+    pub hit_group: u32,
+    pub flags: vk::GeometryInstanceFlagsKHR,
+}
+
+impl TlasInstance {
+    pub fn new(blas: Arc<AccelerationStructure>, transform: Affine3A) -> Self {
+        Self {
+            blas,
+            transform,
+            instance_id: 0,
+            mask: 0xFF,
+            hit_group: 0,
+            flags: vk::GeometryInstanceFlagsKHR::empty(),
+        }
+    }
+}
+
+/// Keeps the instances and one instance buffer and TLAS per frame in flight, and rebuilds or refits
+/// the TLAS of the current frame in flight every frame. A frame only reuses its own TLAS, after its fence signaled.
+pub struct TlasBuilder {
+    instances: Vec<vk::AccelerationStructureInstanceKHR>,
+    blases: Vec<Arc<AccelerationStructure>>,
+    frames: Vec<TlasFrame>,
+    flags: vk::BuildAccelerationStructureFlagsKHR,
+    deletion_queue: DeletionQueue,
+}
+
+struct TlasFrame {
+    instance_buffer: Buffer,
+    scratch_buffer: Buffer,
+    tlas: AccelerationStructure,
+    capacity: u32,
+    /// Instance count of the last build, None when the next build can not be a refit.
+    built_count: Option<u32>,
+    /// BLASes referenced by the last build.
+    blases: Vec<Arc<AccelerationStructure>>,
+}
+
+impl TlasBuilder {
+    pub(crate) fn new(
+        context: &Context,
+        capacity: u32,
+        in_flight_count: usize,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<Self> {
+        let capacity = capacity.max(1);
+        let frames = (0..in_flight_count)
+            .map(|_| TlasFrame::new(context, capacity, flags))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            instances: vec![],
+            blases: vec![],
+            frames,
+            flags,
+            deletion_queue: context.deletion_queue.clone(),
+        })
+    }
+
+    /// TLAS of the in flight frame, descriptors have to point to the TLAS of the frame they are used in.
+    pub fn tlas(&self, in_flight_index: usize) -> &AccelerationStructure {
+        &self.frames[in_flight_index].tlas
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.instances.clear();
+        self.blases.clear();
+    }
+
+    /// Returns the index for set_instance and set_transform.
+    pub fn push(&mut self, instance: TlasInstance) -> usize {
+        self.instances.push(instance_to_vk(&instance));
+        self.blases.push(instance.blas);
+        self.instances.len() - 1
+    }
+
+    pub fn set_instance(&mut self, index: usize, instance: TlasInstance) {
+        self.instances[index] = instance_to_vk(&instance);
+        self.blases[index] = instance.blas;
+    }
+
+    pub fn set_transform(&mut self, index: usize, transform: Affine3A) {
+        self.instances[index].transform = transform_to_vk(&transform);
+    }
+
+    /// Writes the instances and records a refit of the TLAS of the current in flight frame when only the instances
+    /// changed since its last build, otherwise a full build. Adds a barrier so following commands can trace against the TLAS.
+    /// Returns true when the TLAS had to be recreated to fit the instances, descriptors pointing to it have to be rewritten.
+    pub fn build(&mut self, context: &Context, cmd_buffer: &CommandBuffer, in_flight_frames: &InFlightFrames) -> Result<bool> {
+        let in_flight_index = in_flight_frames.in_flight_index;
+        if in_flight_index >= self.frames.len() {
+            bail!(
+                "TLAS builder has {} frames, but the in flight index is {in_flight_index}.",
+                self.frames.len()
+            );
+        }
+        let frame = &mut self.frames[in_flight_index];
+
+        let recreated = self.instances.len() as u32 > frame.capacity;
+        if recreated {
+            let capacity = (self.instances.len() as u32).next_power_of_two();
+            let new_frame = TlasFrame::new(context, capacity, self.flags)?;
+
+            // Other submits could still reference the old TLAS
+            let old_frame = std::mem::replace(frame, new_frame);
+            self.deletion_queue.push(old_frame.instance_buffer);
+            self.deletion_queue.push(old_frame.scratch_buffer);
+            self.deletion_queue.push(old_frame.tlas);
+            self.deletion_queue.push(old_frame.blases);
+        }
+
+        if !self.instances.is_empty() {
+            frame.instance_buffer.copy_data_to_buffer(&self.instances);
+        }
+        // The last build of this frame is done, so its BLASes can be released.
+        frame.blases.clone_from(&self.blases);
+
+        let count = self.instances.len() as u32;
+        let update = frame.built_count == Some(count)
+            && self.flags.contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE);
+
+        let geometry = instance_geometry(&frame.instance_buffer);
+        let range = vk::AccelerationStructureBuildRangeInfoKHR::default().primitive_count(count);
+        cmd_buffer.build_acceleration_structures_batch(
+            &[AccelerationStructureBuild {
+                acceleration_structure: &frame.tlas,
+                geometry: std::slice::from_ref(&geometry),
+                ranges: std::slice::from_ref(&range),
+                update,
+            }],
+            &frame.scratch_buffer,
+        );
+        cmd_buffer.pipeline_memory_barriers(&[MemoryBarrier {
+            src_access_mask: vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR,
+            dst_access_mask: vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR,
+            src_stage_mask: vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR,
+            dst_stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+        }]);
+
+        frame.built_count = Some(count);
+        Ok(recreated)
+    }
+
+    /// The next build of every frame is a full build, refits lose trace performance when instances move a lot.
+    pub fn force_rebuild(&mut self) {
+        for frame in self.frames.iter_mut() {
+            frame.built_count = None;
+        }
+    }
+}
+
+impl TlasFrame {
+    fn new(context: &Context, capacity: u32, flags: vk::BuildAccelerationStructureFlagsKHR) -> Result<Self> {
+        let instance_buffer = context.create_buffer(
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::CpuToGpu,
+            (size_of::<vk::AccelerationStructureInstanceKHR>() * capacity as usize) as _,
+        )?;
+
+        let geometry = instance_geometry(&instance_buffer);
+        let tlas = context.create_acceleration_structure(
+            vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+            std::slice::from_ref(&geometry),
+            &[capacity],
+            flags,
+        )?;
+
+        let scratch_buffer = context.create_acceleration_structure_scratch_buffer(&[AccelerationStructureBuild {
+            acceleration_structure: &tlas,
+            geometry: std::slice::from_ref(&geometry),
+            ranges: &[],
+            update: tlas.update_scratch_size > tlas.build_scratch_size,
+        }])?;
+
+        Ok(Self {
+            instance_buffer,
+            scratch_buffer,
+            tlas,
+            capacity,
+            built_count: None,
+            blases: vec![],
+        })
+    }
+}
+
+impl Context {
+    /// Grows when more than capacity instances are pushed.
+    pub fn create_tlas_builder(&self, capacity: u32, in_flight_frames: &InFlightFrames) -> Result<TlasBuilder> {
+        self.create_tlas_builder_complex(
+            capacity,
+            in_flight_frames,
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+        )
+    }
+
+    pub fn create_tlas_builder_complex(
+        &self,
+        capacity: u32,
+        in_flight_frames: &InFlightFrames,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<TlasBuilder> {
+        TlasBuilder::new(self, capacity, in_flight_frames.num_frames_in_flight, flags)
+    }
+}
+
+fn instance_geometry(instance_buffer: &Buffer) -> vk::AccelerationStructureGeometryKHR<'static> {
+    vk::AccelerationStructureGeometryKHR::default()
+        .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+        .geometry(vk::AccelerationStructureGeometryDataKHR {
+            instances: vk::AccelerationStructureGeometryInstancesDataKHR::default()
+                .array_of_pointers(false)
+                .data(vk::DeviceOrHostAddressConstKHR {
+                    device_address: instance_buffer.get_device_address(),
+                }),
+        })
+}
+
+/// 3x4 row major.
+fn transform_to_vk(transform: &Affine3A) -> vk::TransformMatrixKHR {
+    let m = transform.matrix3;
+    let t = transform.translation;
+    vk::TransformMatrixKHR {
+        matrix: [
+            m.x_axis.x, m.y_axis.x, m.z_axis.x, t.x,
+            m.x_axis.y, m.y_axis.y, m.z_axis.y, t.y,
+            m.x_axis.z, m.y_axis.z, m.z_axis.z, t.z,
+        ],
+    }
+}
+
+fn instance_to_vk(instance: &TlasInstance) -> vk::AccelerationStructureInstanceKHR {
+    vk::AccelerationStructureInstanceKHR {
+        transform: transform_to_vk(&instance.transform),
+        instance_custom_index_and_mask: vk::Packed24_8::new(instance.instance_id & 0xFF_FFFF, instance.mask),
+        instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+            instance.hit_group & 0xFF_FFFF,
+            instance.flags.as_raw() as u8,
+        ),
+        acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+            device_handle: instance.blas.address,
+        },
+    }
+}
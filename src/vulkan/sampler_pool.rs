@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt::{self}, rc::Rc, sync::Arc};
+use std::{collections::HashMap, fmt::{self}, sync::{Arc, Mutex}};
 
 use ash::vk::{self, DescriptorPoolSize};
 use egui::emath::OrderedFloat;
@@ -8,11 +8,12 @@ use crate::OctaResult;
 
 use super::{Context, DescriptorPool, DescriptorSet, DescriptorSetLayout, Device};
 
+/// Can be shared between threads, the descriptor pool and the sampler cache are behind locks.
 #[derive(Debug)]
 pub struct SamplerPool {
     device: Arc<Device>,
-    pool: Rc<DescriptorPool>,
-    samplers: HashMap<SamplerConfig, vk::Sampler>
+    pool: Arc<Mutex<DescriptorPool>>,
+    samplers: Mutex<HashMap<SamplerConfig, vk::Sampler>>
 }
 
 #[derive(Eq, Hash, PartialEq, PartialOrd, Ord)]
@@ -39,7 +40,7 @@ struct SamplerConfig {
 pub struct SamplerSetHandle {
     pub set: DescriptorSet,
     pub layout: DescriptorSetLayout,
-    pool: Rc<DescriptorPool>,
+    pool: Arc<Mutex<DescriptorPool>>,
 }
 
 impl SamplerPool {
@@ -58,12 +59,12 @@ impl SamplerPool {
 
         Ok(Self {
             device: device.clone(),
-            pool: Rc::new(pool),
+            pool: Arc::new(Mutex::new(pool)),
             samplers: Default::default()
         })
     }
 
-    pub(crate) fn get_sampler(&mut self, info: &vk::SamplerCreateInfo) -> OctaResult<vk::Sampler> {
+    pub(crate) fn get_sampler(&self, info: &vk::SamplerCreateInfo) -> OctaResult<vk::Sampler> {
         
         let config =SamplerConfig { 
             flags: info.flags, 
@@ -84,17 +85,18 @@ impl SamplerPool {
             unnormalized_coordinates: info.unnormalized_coordinates == 1, 
         };
 
-        if let Some(sampler) = self.samplers.get(&config) {
+        let mut samplers = self.samplers.lock().unwrap();
+        if let Some(sampler) = samplers.get(&config) {
             Ok(sampler.clone())
         } else {
             let inner = unsafe { self.device.inner.create_sampler(info, None)? };
-            self.samplers.insert(config, inner.clone());
+            samplers.insert(config, inner.clone());
 
             Ok(inner)
         }
     }
 
-    pub fn get_set(&mut self, infos: &[vk::SamplerCreateInfo]) -> OctaResult<SamplerSetHandle> {
+    pub fn get_set(&self, infos: &[vk::SamplerCreateInfo]) -> OctaResult<SamplerSetHandle> {
       
         let samplers = infos.iter()
             .map(|info| {
@@ -122,7 +124,7 @@ impl SamplerPool {
             vk::DescriptorSetLayoutCreateFlags::empty(),
             &[])?;
 
-        let set = self.pool.allocate_set(&layout)?;
+        let set = self.pool.lock().unwrap().allocate_set(&layout)?;
 
         Ok(SamplerSetHandle { 
             set, 
@@ -140,7 +142,7 @@ impl Context {
 
 impl Drop for SamplerPool {
     fn drop(&mut self) {
-        for (_, sampler) in self.samplers.iter() {
+        for (_, sampler) in self.samplers.get_mut().unwrap().iter() {
             unsafe {
                 self.device.inner.destroy_sampler(*sampler, None);
             }
@@ -150,7 +152,7 @@ impl Drop for SamplerPool {
 
 impl Drop for SamplerSetHandle {
     fn drop(&mut self) {
-        let res = self.pool.free_set(&self.set);
+        let res = self.pool.lock().unwrap().free_set(&self.set);
         if res.is_err() {
             error!("Failed to free Descriptor Set: {}", res.unwrap_err());
         }
@@ -0,0 +1,314 @@
+use std::mem::size_of_val;
+
+use anyhow::{bail, Result};
+use ash::vk;
+use glam::UVec2;
+use gpu_allocator::MemoryLocation;
+use log::trace;
+
+use crate::vulkan::utils::{buffer_image_copy_alignment, format_texel_block, uvec3_to_extend3d};
+use crate::vulkan::{Buffer, CommandBuffer, Context, Image, MemoryBarrier};
+
+/// Ring allocated staging memory for uploads that are recorded into the frame command buffer.
+/// Memory written in one frame is only reused after the fence of that in flight frame signaled,
+/// so uploads never have to wait for the device.
+#[derive(Debug)]
+pub struct StagingBelt {
+    buffer: Buffer,
+    head: vk::DeviceSize,
+    in_use: vk::DeviceSize,
+    pending_size: vk::DeviceSize,
+    frame_sizes: Vec<vk::DeviceSize>,
+    buffer_copies: Vec<(vk::Buffer, vk::BufferCopy)>,
+    image_copies: Vec<StagedImageCopy>,
+}
+
+#[derive(Debug)]
+struct StagedImageCopy {
+    image: vk::Image,
+    region: vk::BufferImageCopy,
+    old_layout: vk::ImageLayout,
+    final_layout: vk::ImageLayout,
+}
+
+impl StagingBelt {
+    pub(crate) fn new(context: &Context, size: vk::DeviceSize, in_flight_count: usize) -> Result<Self> {
+        let buffer = context.create_buffer(
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            size,
+        )?;
+
+        Ok(Self {
+            buffer,
+            head: 0,
+            in_use: 0,
+            pending_size: 0,
+            frame_sizes: vec![0; in_flight_count],
+            buffer_copies: vec![],
+            image_copies: vec![],
+        })
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.buffer.size
+    }
+
+    pub fn free_size(&self) -> vk::DeviceSize {
+        self.buffer.size - self.in_use
+    }
+
+    fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Result<vk::DeviceSize> {
+        let alignment = alignment.max(1);
+        let mut offset = self.head.next_multiple_of(alignment);
+        if offset + size > self.buffer.size {
+            // Skip the rest of the buffer and wrap around
+            offset = 0;
+        }
+
+        let needed = if offset >= self.head {
+            offset + size - self.head
+        } else {
+            self.buffer.size - self.head + size
+        };
+
+        if self.in_use + needed > self.buffer.size {
+            bail!(
+                "Staging belt of size {} is full. Tried to allocate {size} bytes with {} bytes free.",
+                self.buffer.size,
+                self.free_size()
+            );
+        }
+
+        self.head = (offset + size) % self.buffer.size;
+        self.in_use += needed;
+        self.pending_size += needed;
+
+        Ok(offset)
+    }
+
+    fn write<T: Copy>(&mut self, data: &[T], alignment: vk::DeviceSize) -> Result<vk::DeviceSize> {
+        let size = size_of_val(data) as vk::DeviceSize;
+        let offset = self.allocate(size, alignment)?;
+        self.buffer.copy_data_to_buffer_without_aligment(data, offset as _);
+
+        Ok(offset)
+    }
+
+    /// The copy is recorded into the next frame command buffer, so dst has to stay alive until that frame finished.
+    pub fn upload_to_buffer<T: Copy>(&mut self, data: &[T], dst: &Buffer, dst_offset: vk::DeviceSize) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let src_offset = self.write(data, 4)?;
+        self.buffer_copies.push((dst.inner, vk::BufferCopy {
+            src_offset,
+            dst_offset,
+            size: size_of_val(data) as _,
+        }));
+
+        Ok(())
+    }
+
+    /// The image has to be in old_layout when the frame starts and will be in final_layout after the copy.
+    /// UNDEFINED discards the old content. Several uploads to the same image in one frame are recorded together,
+    /// the old_layout of the first and the final_layout of the last upload are used.
+    pub fn upload_to_image<T: Copy>(
+        &mut self,
+        data: &[T],
+        dst: &Image,
+        old_layout: vk::ImageLayout,
+        final_layout: vk::ImageLayout,
+    ) -> Result<()> {
+        let Some((block_extent, block_bytes)) = format_texel_block(dst.format) else {
+            bail!("Can not upload to images with format {:?}", dst.format);
+        };
+        let blocks = (dst.size.truncate() + block_extent - 1) / block_extent;
+        let image_bytes = blocks.element_product() as usize * dst.size.z as usize * block_bytes as usize;
+        if size_of_val(data) != image_bytes {
+            bail!("Upload of {} bytes does not match the {image_bytes} bytes of the image", size_of_val(data));
+        }
+
+        let buffer_offset = self.write(data, buffer_image_copy_alignment(block_bytes) as _)?;
+        let region = vk::BufferImageCopy::default()
+            .buffer_offset(buffer_offset)
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(uvec3_to_extend3d(dst.size));
+
+        self.image_copies.push(StagedImageCopy {
+            image: dst.inner,
+            region,
+            old_layout,
+            final_layout,
+        });
+
+        Ok(())
+    }
+
+    pub fn create_gpu_only_buffer_from_data<T: Copy>(
+        &mut self,
+        context: &Context,
+        usage: vk::BufferUsageFlags,
+        data: &[T],
+    ) -> Result<Buffer> {
+        if data.is_empty() {
+            bail!("Can not create a gpu only buffer from empty data.");
+        }
+        let buffer = context.create_buffer(
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuOnly,
+            size_of_val(data) as _,
+        )?;
+        self.upload_to_buffer(data, &buffer, 0)?;
+
+        Ok(buffer)
+    }
+
+    pub fn create_texture_image_from_data<T: Copy>(
+        &mut self,
+        context: &Context,
+        format: vk::Format,
+        image_size: UVec2,
+        data: &[T],
+    ) -> Result<Image> {
+        let image = context.create_image(
+            vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            MemoryLocation::GpuOnly,
+            format,
+            image_size,
+        )?;
+        self.upload_to_image(data, &image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL)?;
+
+        Ok(image)
+    }
+
+    /// Records all pending copies into the command buffer of the given in flight frame.
+    pub(crate) fn flush(&mut self, command_buffer: &CommandBuffer, in_flight_index: usize) {
+        self.frame_sizes[in_flight_index] += self.pending_size;
+        self.pending_size = 0;
+
+        if self.buffer_copies.is_empty() && self.image_copies.is_empty() {
+            return;
+        }
+        trace!("Flushing staging belt: {} buffer and {} image copies", self.buffer_copies.len(), self.image_copies.len());
+
+        // Group copies to the same buffer into one command
+        self.buffer_copies.sort_by_key(|(dst, _)| *dst);
+        let buffers = self.buffer_copies.chunk_by(|(a, _), (b, _)| a == b).collect::<Vec<_>>();
+
+        // The destination buffers can still be read or written by commands of earlier frames
+        let barriers = buffers.iter()
+            .map(|chunk| {
+                vk::BufferMemoryBarrier2::default()
+                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                    .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .buffer(chunk[0].0)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+            })
+            .collect::<Vec<_>>();
+        if !barriers.is_empty() {
+            command_buffer.raw_buffer_barriers(&barriers);
+        }
+
+        for chunk in buffers.iter() {
+            let regions = chunk.iter().map(|(_, region)| *region).collect::<Vec<_>>();
+            unsafe {
+                command_buffer.device.inner.cmd_copy_buffer(
+                    command_buffer.inner,
+                    self.buffer.inner,
+                    chunk[0].0,
+                    &regions,
+                )
+            };
+        }
+
+        // One transition per image, so later uploads to the same image don't discard earlier ones.
+        // The stable sort keeps the upload order inside each image.
+        self.image_copies.sort_by_key(|copy| copy.image);
+        let images = self.image_copies.chunk_by(|a, b| a.image == b.image).collect::<Vec<_>>();
+
+        let image_barrier = |image, old_layout, new_layout, src_access_mask, src_stage_mask, dst_access_mask, dst_stage_mask| {
+            vk::ImageMemoryBarrier2::default()
+                .src_stage_mask(src_stage_mask)
+                .src_access_mask(src_access_mask)
+                .old_layout(old_layout)
+                .dst_stage_mask(dst_stage_mask)
+                .dst_access_mask(dst_access_mask)
+                .new_layout(new_layout)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+        };
+
+        if !images.is_empty() {
+            let barriers = images.iter()
+                .map(|copies| image_barrier(
+                    copies[0].image,
+                    copies[0].old_layout,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE,
+                    vk::PipelineStageFlags2::ALL_COMMANDS,
+                    vk::AccessFlags2::TRANSFER_WRITE,
+                    vk::PipelineStageFlags2::TRANSFER,
+                ))
+                .collect::<Vec<_>>();
+            command_buffer.raw_image_barriers(&barriers);
+
+            for copies in images.iter() {
+                let regions = copies.iter().map(|copy| copy.region).collect::<Vec<_>>();
+                unsafe {
+                    command_buffer.device.inner.cmd_copy_buffer_to_image(
+                        command_buffer.inner,
+                        self.buffer.inner,
+                        copies[0].image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &regions,
+                    )
+                };
+            }
+
+            let barriers = images.iter()
+                .map(|copies| image_barrier(
+                    copies[0].image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    copies[copies.len() - 1].final_layout,
+                    vk::AccessFlags2::TRANSFER_WRITE,
+                    vk::PipelineStageFlags2::TRANSFER,
+                    vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE,
+                    vk::PipelineStageFlags2::ALL_COMMANDS,
+                ))
+                .collect::<Vec<_>>();
+            command_buffer.raw_image_barriers(&barriers);
+        }
+
+        command_buffer.pipeline_memory_barriers(&[MemoryBarrier {
+            src_access_mask: vk::AccessFlags2::TRANSFER_WRITE,
+            dst_access_mask: vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE,
+            src_stage_mask: vk::PipelineStageFlags2::TRANSFER,
+            dst_stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+        }]);
+
+        self.buffer_copies.clear();
+        self.image_copies.clear();
+    }
+
+    /// Frees the memory used by the in flight frame. Has to be called after its fence signaled.
+    pub(crate) fn retire_frame(&mut self, in_flight_index: usize) {
+        self.in_use -= self.frame_sizes[in_flight_index];
+        self.frame_sizes[in_flight_index] = 0;
+    }
+}
@@ -1,4 +1,6 @@
 use anyhow::Result;
+#[cfg(any(vulkan_1_0, vulkan_1_1))]
+use anyhow::bail;
 use ash::vk;
 use std::sync::Arc;
 
@@ -33,6 +35,86 @@ impl Drop for Semaphore {
     }
 }
 
+/// Needs the timelineSemaphore device feature, before vulkan 1.2 also VK_KHR_timeline_semaphore.
+#[derive(Debug)]
+pub struct TimelineSemaphore {
+    device: Arc<Device>,
+    pub(crate) inner: vk::Semaphore,
+}
+
+impl TimelineSemaphore {
+    pub(crate) fn new(device: Arc<Device>, initial_value: u64) -> Result<Self> {
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        if device.timeline_semaphore_fn.is_none() {
+            bail!("Cannot create a timeline semaphore when VK_KHR_timeline_semaphore is not enabled");
+        }
+
+        let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(initial_value);
+        let semaphore_info = vk::SemaphoreCreateInfo::default().push_next(&mut type_info);
+        let inner = unsafe { device.inner.create_semaphore(&semaphore_info, None)? };
+
+        Ok(Self { device, inner })
+    }
+
+    pub fn value(&self) -> Result<u64> {
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        let value = unsafe { self.timeline_semaphore_fn().get_semaphore_counter_value(self.inner)? };
+        #[cfg(any(vulkan_1_2, vulkan_1_3))]
+        let value = unsafe { self.device.inner.get_semaphore_counter_value(self.inner)? };
+
+        Ok(value)
+    }
+
+    pub fn wait(&self, value: u64, timeout: Option<u64>) -> Result<()> {
+        let timeout = timeout.unwrap_or(u64::MAX);
+        let wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(std::slice::from_ref(&self.inner))
+            .values(std::slice::from_ref(&value));
+
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        unsafe { self.timeline_semaphore_fn().wait_semaphores(&wait_info, timeout)? };
+        #[cfg(any(vulkan_1_2, vulkan_1_3))]
+        unsafe { self.device.inner.wait_semaphores(&wait_info, timeout)? };
+
+        Ok(())
+    }
+
+    pub fn signal(&self, value: u64) -> Result<()> {
+        let signal_info = vk::SemaphoreSignalInfo::default()
+            .semaphore(self.inner)
+            .value(value);
+
+        #[cfg(any(vulkan_1_0, vulkan_1_1))]
+        unsafe { self.timeline_semaphore_fn().signal_semaphore(&signal_info)? };
+        #[cfg(any(vulkan_1_2, vulkan_1_3))]
+        unsafe { self.device.inner.signal_semaphore(&signal_info)? };
+
+        Ok(())
+    }
+
+    #[cfg(any(vulkan_1_0, vulkan_1_1))]
+    fn timeline_semaphore_fn(&self) -> &ash::khr::timeline_semaphore::Device {
+        // Checked in new
+        self.device.timeline_semaphore_fn.as_ref().unwrap()
+    }
+}
+
+impl Context {
+    pub fn create_timeline_semaphore(&self, initial_value: u64) -> Result<TimelineSemaphore> {
+        TimelineSemaphore::new(self.device.clone(), initial_value)
+    }
+}
+
+impl Drop for TimelineSemaphore {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.inner.destroy_semaphore(self.inner, None);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Fence {
     device: Arc<Device>,
@@ -0,0 +1,704 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use ash::vk;
+use glam::{uvec2, UVec2};
+use gpu_allocator::MemoryLocation;
+use image::DynamicImage;
+use log::{trace, warn};
+
+use crate::vulkan::descriptor_heap::{ImageDescriptorHandle, ImageDescriptorHeap};
+use crate::vulkan::utils::{buffer_image_copy_alignment, compute_mip_levels, format_texel_block};
+use crate::vulkan::{Context, Image, ImageBarrier, ImageView};
+
+const KTX2_MAGIC: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+#[derive(Debug, Clone, Copy)]
+pub struct TextureLoadOptions {
+    /// Interpret 8 bit color data as sRGB. Formats stored in KTX2 and DDS files are used as they are.
+    pub srgb: bool,
+    /// Generate the full mip chain if the file does not contain mip levels.
+    pub generate_mips: bool,
+    pub usage: vk::ImageUsageFlags,
+}
+
+impl Default for TextureLoadOptions {
+    fn default() -> Self {
+        Self {
+            srgb: true,
+            generate_mips: true,
+            usage: vk::ImageUsageFlags::SAMPLED,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Texture {
+    pub handle: Option<ImageDescriptorHandle>,
+    pub view: ImageView,
+    pub image: Image,
+}
+
+#[derive(Debug)]
+struct TextureData {
+    format: vk::Format,
+    size: UVec2,
+    data: Vec<u8>,
+    // Offset into data for every mip level stored in the file
+    level_offsets: Vec<usize>,
+}
+
+impl Context {
+    pub fn load_texture<P: AsRef<Path>>(&self, path: P) -> Result<Texture> {
+        self.load_texture_complex(path, TextureLoadOptions::default(), None)
+    }
+
+    pub fn load_texture_complex<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: TextureLoadOptions,
+        heap: Option<&ImageDescriptorHeap>,
+    ) -> Result<Texture> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read texture {path:?}"))?;
+
+        self.create_texture_from_bytes(&bytes, options, heap)
+            .with_context(|| format!("Failed to load texture {path:?}"))
+    }
+
+    pub fn create_texture_from_bytes(
+        &self,
+        bytes: &[u8],
+        options: TextureLoadOptions,
+        heap: Option<&ImageDescriptorHeap>,
+    ) -> Result<Texture> {
+        let texture_data = if bytes.starts_with(&KTX2_MAGIC) {
+            read_ktx2(bytes)?
+        } else if bytes.starts_with(&DDS_MAGIC) {
+            read_dds(bytes)?
+        } else {
+            let image = image::load_from_memory(bytes)?;
+            read_dynamic_image(image, options.srgb)
+        };
+
+        self.create_texture_from_texture_data(texture_data, options, heap)
+    }
+
+    pub fn format_supports_features(&self, format: vk::Format, features: vk::FormatFeatureFlags) -> bool {
+        let properties = unsafe {
+            self.instance
+                .inner
+                .get_physical_device_format_properties(self.physical_device.inner, format)
+        };
+
+        properties.optimal_tiling_features.contains(features)
+    }
+
+    fn create_texture_from_texture_data(
+        &self,
+        texture_data: TextureData,
+        options: TextureLoadOptions,
+        heap: Option<&ImageDescriptorHeap>,
+    ) -> Result<Texture> {
+        let texture_data = if self.format_supports_features(texture_data.format, vk::FormatFeatureFlags::SAMPLED_IMAGE) {
+            texture_data
+        } else if let Some(decoded) = decode_bc_to_rgba8(&texture_data) {
+            warn!(
+                "Texture format {:?} is not supported by {}. Decoding it to {:?}.",
+                texture_data.format,
+                self.physical_device.name,
+                decoded.format
+            );
+            decoded
+        } else {
+            bail!("Texture format {:?} is not supported by {}", texture_data.format, self.physical_device.name);
+        };
+        let TextureData { format, size, data, level_offsets } = texture_data;
+
+        let mut generate_mips = options.generate_mips && level_offsets.len() == 1 && size.max_element() > 1;
+        if generate_mips && !self.format_supports_features(
+            format,
+            vk::FormatFeatureFlags::BLIT_SRC
+                | vk::FormatFeatureFlags::BLIT_DST
+                | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        ) {
+            warn!("Texture format {format:?} does not support linear blits. Skipping mip generation.");
+            generate_mips = false;
+        }
+
+        let mip_levels = if generate_mips {
+            compute_mip_levels(size)
+        } else {
+            level_offsets.len() as u32
+        };
+        trace!("Creating Texture {size} with format {format:?} and {mip_levels} mip levels");
+
+        let mut staging_buffer = self.create_buffer(
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            data.len() as _,
+        )?;
+        staging_buffer.copy_data_to_buffer_without_aligment(&data, 0);
+
+        let mut usage = options.usage | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+        if generate_mips {
+            usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
+
+        let image = self.create_image_complex(
+            usage,
+            MemoryLocation::GpuOnly,
+            format,
+            size,
+            mip_levels,
+        )?;
+
+        let view = image.create_image_view(false)?;
+
+        let regions = level_offsets.iter()
+            .enumerate()
+            .map(|(level, offset)| {
+                let level_size = (size >> level as u32).max(UVec2::ONE);
+
+                vk::BufferImageCopy::default()
+                    .buffer_offset(*offset as _)
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: level as _,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(vk::Extent3D { width: level_size.x, height: level_size.y, depth: 1 })
+            })
+            .collect::<Vec<_>>();
+
+        // Same layouts the descriptor heap expects
+        let final_layout = if usage.contains(vk::ImageUsageFlags::STORAGE) {
+            vk::ImageLayout::GENERAL
+        } else {
+            vk::ImageLayout::READ_ONLY_OPTIMAL
+        };
+
+        self.execute_one_time_commands(|cmd_buffer| {
+            cmd_buffer.pipeline_image_barriers(&[ImageBarrier {
+                image: &image,
+                old_layout: vk::ImageLayout::UNDEFINED,
+                new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                src_access_mask: vk::AccessFlags2::NONE,
+                dst_access_mask: vk::AccessFlags2::TRANSFER_WRITE,
+                src_stage_mask: vk::PipelineStageFlags2::NONE,
+                dst_stage_mask: vk::PipelineStageFlags2::TRANSFER,
+            }]);
+
+            cmd_buffer.copy_buffer_to_image_regions(&staging_buffer, &image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &regions);
+
+            if generate_mips {
+                cmd_buffer.generate_mipmaps(&image, final_layout, vk::PipelineStageFlags2::ALL_COMMANDS);
+            } else {
+                cmd_buffer.pipeline_image_barriers(&[ImageBarrier {
+                    image: &image,
+                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_layout: final_layout,
+                    src_access_mask: vk::AccessFlags2::TRANSFER_WRITE,
+                    dst_access_mask: vk::AccessFlags2::SHADER_READ,
+                    src_stage_mask: vk::PipelineStageFlags2::TRANSFER,
+                    dst_stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+                }]);
+            }
+        })?;
+
+        let handle = heap
+            .map(|heap| heap.create_image_handle(&view, usage))
+            .transpose()?;
+
+        Ok(Texture { handle, view, image })
+    }
+}
+
+fn read_dynamic_image(image: DynamicImage, srgb: bool) -> TextureData {
+    let size = uvec2(image.width(), image.height());
+
+    let (format, data) = match image {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            let data = image.into_rgba32f()
+                .into_raw()
+                .into_iter()
+                .flat_map(|v| v.to_ne_bytes())
+                .collect();
+
+            (vk::Format::R32G32B32A32_SFLOAT, data)
+        }
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => {
+            let data = image.into_rgba16()
+                .into_raw()
+                .into_iter()
+                .flat_map(|v| v.to_ne_bytes())
+                .collect();
+
+            (vk::Format::R16G16B16A16_UNORM, data)
+        }
+        _ => {
+            let format = if srgb { vk::Format::R8G8B8A8_SRGB } else { vk::Format::R8G8B8A8_UNORM };
+            (format, image.into_rgba8().into_raw())
+        }
+    };
+
+    TextureData {
+        format,
+        size,
+        data,
+        level_offsets: vec![0],
+    }
+}
+
+fn read_ktx2(bytes: &[u8]) -> Result<TextureData> {
+    let reader = ktx2::Reader::new(bytes)
+        .map_err(|e| anyhow::anyhow!("Invalid KTX2 file: {e}"))?;
+    let header = reader.header();
+
+    if let Some(scheme) = header.supercompression_scheme {
+        bail!("KTX2 supercompression {scheme:?} is not supported");
+    }
+
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+        bail!("Only 2D KTX2 textures are supported");
+    }
+
+    let Some(format) = header.format else {
+        bail!("KTX2 files without a Vulkan format (Basis Universal) are not supported");
+    };
+    let format = vk::Format::from_raw(format.value() as _);
+    let size = uvec2(header.pixel_width, header.pixel_height.max(1));
+
+    // Buffer to image copies need offsets aligned to 4 bytes and to the texel block size
+    let Some(first_level) = reader.levels().next() else {
+        bail!("KTX2 file has no mip levels");
+    };
+    let block_extent = format_texel_block(format).map_or(UVec2::ONE, |(extent, _)| extent);
+    let block_bytes = (first_level.data.len() / level_block_count(size, block_extent)).max(1);
+    let alignment = buffer_image_copy_alignment(block_bytes as u32) as usize;
+
+    let mut data = vec![];
+    let mut level_offsets = vec![];
+    for level in reader.levels() {
+        data.resize(data.len().next_multiple_of(alignment), 0);
+        level_offsets.push(data.len());
+        data.extend_from_slice(level.data);
+    }
+
+    Ok(TextureData {
+        format,
+        size,
+        data,
+        level_offsets,
+    })
+}
+
+fn read_dds(bytes: &[u8]) -> Result<TextureData> {
+    use ddsfile::{D3DFormat, Dds, DxgiFormat};
+
+    let dds = Dds::read(bytes)?;
+
+    if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 {
+        bail!("Only 2D DDS textures are supported");
+    }
+
+    let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+        (Some(DxgiFormat::R8G8B8A8_UNorm), _) | (_, Some(D3DFormat::A8B8G8R8)) => vk::Format::R8G8B8A8_UNORM,
+        (Some(DxgiFormat::R8G8B8A8_UNorm_sRGB), _) => vk::Format::R8G8B8A8_SRGB,
+        (Some(DxgiFormat::B8G8R8A8_UNorm), _) | (_, Some(D3DFormat::A8R8G8B8)) => vk::Format::B8G8R8A8_UNORM,
+        (Some(DxgiFormat::B8G8R8A8_UNorm_sRGB), _) => vk::Format::B8G8R8A8_SRGB,
+        (Some(DxgiFormat::R16G16B16A16_Float), _) | (_, Some(D3DFormat::A16B16G16R16F)) => vk::Format::R16G16B16A16_SFLOAT,
+        (Some(DxgiFormat::R32G32B32A32_Float), _) | (_, Some(D3DFormat::A32B32G32R32F)) => vk::Format::R32G32B32A32_SFLOAT,
+        (Some(DxgiFormat::BC1_UNorm), _) | (_, Some(D3DFormat::DXT1)) => vk::Format::BC1_RGBA_UNORM_BLOCK,
+        (Some(DxgiFormat::BC1_UNorm_sRGB), _) => vk::Format::BC1_RGBA_SRGB_BLOCK,
+        (Some(DxgiFormat::BC2_UNorm), _) | (_, Some(D3DFormat::DXT3)) => vk::Format::BC2_UNORM_BLOCK,
+        (Some(DxgiFormat::BC2_UNorm_sRGB), _) => vk::Format::BC2_SRGB_BLOCK,
+        (Some(DxgiFormat::BC3_UNorm), _) | (_, Some(D3DFormat::DXT5)) => vk::Format::BC3_UNORM_BLOCK,
+        (Some(DxgiFormat::BC3_UNorm_sRGB), _) => vk::Format::BC3_SRGB_BLOCK,
+        (Some(DxgiFormat::BC4_UNorm), _) => vk::Format::BC4_UNORM_BLOCK,
+        (Some(DxgiFormat::BC4_SNorm), _) => vk::Format::BC4_SNORM_BLOCK,
+        (Some(DxgiFormat::BC5_UNorm), _) => vk::Format::BC5_UNORM_BLOCK,
+        (Some(DxgiFormat::BC5_SNorm), _) => vk::Format::BC5_SNORM_BLOCK,
+        (Some(DxgiFormat::BC6H_UF16), _) => vk::Format::BC6H_UFLOAT_BLOCK,
+        (Some(DxgiFormat::BC6H_SF16), _) => vk::Format::BC6H_SFLOAT_BLOCK,
+        (Some(DxgiFormat::BC7_UNorm), _) => vk::Format::BC7_UNORM_BLOCK,
+        (Some(DxgiFormat::BC7_UNorm_sRGB), _) => vk::Format::BC7_SRGB_BLOCK,
+        (dxgi, d3d) => bail!("DDS format {dxgi:?} {d3d:?} is not supported"),
+    };
+
+    // All formats above are known
+    let (block_extent, block_bytes) = format_texel_block(format).unwrap();
+    let size = uvec2(dds.get_width(), dds.get_height());
+    let data = dds.get_data(0)?;
+
+    let mut level_offsets = vec![];
+    let mut offset = 0;
+    for level in 0..dds.get_num_mipmap_levels().max(1) {
+        let level_size = level_block_count((size >> level).max(UVec2::ONE), block_extent) * block_bytes as usize;
+        if offset + level_size > data.len() {
+            break;
+        }
+
+        level_offsets.push(offset);
+        offset += level_size;
+    }
+
+    if level_offsets.is_empty() {
+        bail!("DDS file has no complete mip level");
+    }
+
+    Ok(TextureData {
+        format,
+        size,
+        data: data[..offset].to_vec(),
+        level_offsets,
+    })
+}
+
+fn level_block_count(size: UVec2, block_extent: UVec2) -> usize {
+    ((size + block_extent - 1) / block_extent).element_product() as usize
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BcFormat {
+    Bc1 { alpha: bool },
+    Bc2,
+    Bc3,
+    Bc4 { signed: bool },
+    Bc5 { signed: bool },
+}
+
+/// Decodes BC1 to BC5 textures for devices without BC support. BC6H, BC7 and ASTC return None.
+fn decode_bc_to_rgba8(texture_data: &TextureData) -> Option<TextureData> {
+    use vk::Format as F;
+
+    let (bc_format, format) = match texture_data.format {
+        F::BC1_RGB_UNORM_BLOCK => (BcFormat::Bc1 { alpha: false }, F::R8G8B8A8_UNORM),
+        F::BC1_RGB_SRGB_BLOCK => (BcFormat::Bc1 { alpha: false }, F::R8G8B8A8_SRGB),
+        F::BC1_RGBA_UNORM_BLOCK => (BcFormat::Bc1 { alpha: true }, F::R8G8B8A8_UNORM),
+        F::BC1_RGBA_SRGB_BLOCK => (BcFormat::Bc1 { alpha: true }, F::R8G8B8A8_SRGB),
+        F::BC2_UNORM_BLOCK => (BcFormat::Bc2, F::R8G8B8A8_UNORM),
+        F::BC2_SRGB_BLOCK => (BcFormat::Bc2, F::R8G8B8A8_SRGB),
+        F::BC3_UNORM_BLOCK => (BcFormat::Bc3, F::R8G8B8A8_UNORM),
+        F::BC3_SRGB_BLOCK => (BcFormat::Bc3, F::R8G8B8A8_SRGB),
+        F::BC4_UNORM_BLOCK => (BcFormat::Bc4 { signed: false }, F::R8G8B8A8_UNORM),
+        F::BC4_SNORM_BLOCK => (BcFormat::Bc4 { signed: true }, F::R8G8B8A8_SNORM),
+        F::BC5_UNORM_BLOCK => (BcFormat::Bc5 { signed: false }, F::R8G8B8A8_UNORM),
+        F::BC5_SNORM_BLOCK => (BcFormat::Bc5 { signed: true }, F::R8G8B8A8_SNORM),
+        _ => return None,
+    };
+    let block_bytes = match bc_format {
+        BcFormat::Bc1 { .. } | BcFormat::Bc4 { .. } => 8,
+        _ => 16,
+    };
+
+    let mut data = vec![];
+    let mut level_offsets = vec![];
+    for (level, offset) in texture_data.level_offsets.iter().enumerate() {
+        let size = (texture_data.size >> level as u32).max(UVec2::ONE);
+        let blocks = (size + 3) / 4;
+        let src = texture_data.data.get(*offset..*offset + level_block_count(size, UVec2::splat(4)) * block_bytes)?;
+
+        level_offsets.push(data.len());
+        let start = data.len();
+        data.resize(start + size.element_product() as usize * 4, 0);
+        let dst = &mut data[start..];
+
+        for (i, block) in src.chunks_exact(block_bytes).enumerate() {
+            let block_pos = uvec2(i as u32 % blocks.x, i as u32 / blocks.x) * 4;
+            let texels = decode_bc_block(bc_format, block);
+
+            for (j, texel) in texels.iter().enumerate() {
+                let pos = block_pos + uvec2(j as u32 % 4, j as u32 / 4);
+                if pos.x < size.x && pos.y < size.y {
+                    let index = (pos.y * size.x + pos.x) as usize * 4;
+                    dst[index..index + 4].copy_from_slice(texel);
+                }
+            }
+        }
+    }
+
+    Some(TextureData {
+        format,
+        size: texture_data.size,
+        data,
+        level_offsets,
+    })
+}
+
+// Texels in row major order, signed channels as two's complement
+fn decode_bc_block(bc_format: BcFormat, block: &[u8]) -> [[u8; 4]; 16] {
+    match bc_format {
+        BcFormat::Bc1 { alpha } => decode_bc1_colors(block, true, alpha),
+        BcFormat::Bc2 => {
+            let mut texels = decode_bc1_colors(&block[8..], false, false);
+            let alphas = u64::from_le_bytes(block[..8].try_into().unwrap());
+            for (i, texel) in texels.iter_mut().enumerate() {
+                texel[3] = ((alphas >> (4 * i)) & 0xF) as u8 * 17;
+            }
+            texels
+        }
+        BcFormat::Bc3 => {
+            let mut texels = decode_bc1_colors(&block[8..], false, false);
+            let alphas = decode_bc4_channel(&block[..8], false);
+            for (texel, alpha) in texels.iter_mut().zip(alphas) {
+                texel[3] = alpha;
+            }
+            texels
+        }
+        BcFormat::Bc4 { signed } => {
+            let max = if signed { 127 } else { 255 };
+            decode_bc4_channel(block, signed).map(|r| [r, 0, 0, max])
+        }
+        BcFormat::Bc5 { signed } => {
+            let max = if signed { 127 } else { 255 };
+            let reds = decode_bc4_channel(&block[..8], signed);
+            let greens = decode_bc4_channel(&block[8..], signed);
+            std::array::from_fn(|i| [reds[i], greens[i], 0, max])
+        }
+    }
+}
+
+// The 3 color mode with transparent black is only used by BC1
+fn decode_bc1_colors(block: &[u8], bc1: bool, alpha: bool) -> [[u8; 4]; 16] {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let rgb565 = |c: u16| {
+        let (r, g, b) = ((c >> 11) as u8, ((c >> 5) & 0x3F) as u8, (c & 0x1F) as u8);
+        [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2), 255]
+    };
+    let (e0, e1) = (rgb565(c0), rgb565(c1));
+    let mix = |a: u32, b: u32, div: u32| -> [u8; 4] {
+        std::array::from_fn(|i| ((a * e0[i] as u32 + b * e1[i] as u32) / div) as u8)
+    };
+
+    let palette = if !bc1 || c0 > c1 {
+        [e0, e1, mix(2, 1, 3), mix(1, 2, 3)]
+    } else {
+        [e0, e1, mix(1, 1, 2), [0, 0, 0, if alpha { 0 } else { 255 }]]
+    };
+
+    let indices = u32::from_le_bytes(block[4..8].try_into().unwrap());
+    std::array::from_fn(|i| palette[((indices >> (2 * i)) & 3) as usize])
+}
+
+// BC4 block, also the alpha of BC3
+fn decode_bc4_channel(block: &[u8], signed: bool) -> [u8; 16] {
+    let (e0, e1, min, max) = if signed {
+        ((block[0] as i8).max(-127) as i32, (block[1] as i8).max(-127) as i32, -127, 127)
+    } else {
+        (block[0] as i32, block[1] as i32, 0, 255)
+    };
+
+    let mut palette = [e0, e1, 0, 0, 0, 0, min, max];
+    if e0 > e1 {
+        for i in 1..7 {
+            palette[i as usize + 1] = ((7 - i) * e0 + i * e1) / 7;
+        }
+    } else {
+        for i in 1..5 {
+            palette[i as usize + 1] = ((5 - i) * e0 + i * e1) / 5;
+        }
+    }
+
+    let mut index_bytes = [0; 8];
+    index_bytes[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(index_bytes);
+    std::array::from_fn(|i| palette[((indices >> (3 * i)) & 7) as usize] as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+    use glam::{uvec2, UVec2};
+
+    use super::{decode_bc4_channel, decode_bc_block, decode_bc_to_rgba8, read_dds, read_ktx2, BcFormat, TextureData};
+
+    const RED_565: [u8; 2] = 0xF800u16.to_le_bytes();
+    const BLUE_565: [u8; 2] = 0x001Fu16.to_le_bytes();
+
+    /// Minimal KTX2 writer, the level data is stored in index order.
+    fn ktx2_file(format: vk::Format, size: UVec2, levels: &[&[u8]]) -> Vec<u8> {
+        let index_end = ktx2::Header::LENGTH + levels.len() * ktx2::LevelIndex::LENGTH;
+        let header = ktx2::Header {
+            format: ktx2::Format::new(format.as_raw() as u32),
+            type_size: 1,
+            pixel_width: size.x,
+            pixel_height: size.y,
+            pixel_depth: 0,
+            layer_count: 0,
+            face_count: 1,
+            level_count: levels.len() as u32,
+            supercompression_scheme: None,
+            index: ktx2::Index {
+                dfd_byte_offset: index_end as u32,
+                dfd_byte_length: 4,
+                kvd_byte_offset: 0,
+                kvd_byte_length: 0,
+                sgd_byte_offset: 0,
+                sgd_byte_length: 0,
+            },
+        };
+
+        let mut bytes = header.as_bytes().to_vec();
+        let mut offset = index_end + 4;
+        for level in levels {
+            let index = ktx2::LevelIndex {
+                byte_offset: offset as u64,
+                byte_length: level.len() as u64,
+                uncompressed_byte_length: level.len() as u64,
+            };
+            bytes.extend_from_slice(&index.as_bytes());
+            offset += level.len();
+        }
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        for level in levels {
+            bytes.extend_from_slice(level);
+        }
+        bytes
+    }
+
+    fn dds_file(format: ddsfile::DxgiFormat, size: UVec2, mip_levels: u32) -> Vec<u8> {
+        let dds = ddsfile::Dds::new_dxgi(ddsfile::NewDxgiParams {
+            height: size.y,
+            width: size.x,
+            depth: None,
+            format,
+            mipmap_levels: Some(mip_levels),
+            array_layers: None,
+            caps2: None,
+            is_cubemap: false,
+            resource_dimension: ddsfile::D3D10ResourceDimension::Texture2D,
+            alpha_mode: ddsfile::AlphaMode::Unknown,
+        })
+        .unwrap();
+
+        let mut bytes = vec![];
+        dds.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn decodes_bc1_four_and_three_color_blocks() {
+        // Texel i uses palette entry i % 4
+        let indices = 0xE4E4E4E4u32.to_le_bytes();
+
+        let block = [RED_565, BLUE_565].concat().into_iter().chain(indices).collect::<Vec<_>>();
+        let texels = decode_bc_block(BcFormat::Bc1 { alpha: true }, &block);
+        assert_eq!(texels[..4], [[255, 0, 0, 255], [0, 0, 255, 255], [170, 0, 85, 255], [85, 0, 170, 255]]);
+        assert_eq!(texels[4..8], texels[..4]);
+
+        // c0 <= c1 switches to 3 colors and transparent black
+        let block = [BLUE_565, RED_565].concat().into_iter().chain(indices).collect::<Vec<_>>();
+        let texels = decode_bc_block(BcFormat::Bc1 { alpha: true }, &block);
+        assert_eq!(texels[..4], [[0, 0, 255, 255], [255, 0, 0, 255], [127, 0, 127, 255], [0, 0, 0, 0]]);
+        let texels = decode_bc_block(BcFormat::Bc1 { alpha: false }, &block);
+        assert_eq!(texels[3], [0, 0, 0, 255]);
+
+        // BC2 and BC3 colors always use 4 colors
+        let block = [0; 8].into_iter().chain([BLUE_565, RED_565].concat()).chain(indices).collect::<Vec<_>>();
+        let texels = decode_bc_block(BcFormat::Bc2, &block);
+        assert_eq!(texels[3], [170, 0, 85, 0]);
+    }
+
+    #[test]
+    fn decodes_bc2_and_bc3_alpha() {
+        let colors = [RED_565, RED_565, [0; 2], [0; 2]].concat();
+
+        // 4 bit alphas counting up
+        let alphas = 0xFEDCBA9876543210u64.to_le_bytes();
+        let block = alphas.into_iter().chain(colors.iter().copied()).collect::<Vec<_>>();
+        let texels = decode_bc_block(BcFormat::Bc2, &block);
+        assert_eq!(texels.map(|t| t[3]), std::array::from_fn(|i| i as u8 * 17));
+
+        // Interpolated alpha from 255 to 0, texel i uses index i % 8
+        let mut alphas = [255, 0, 0, 0, 0, 0, 0, 0];
+        alphas[2..].copy_from_slice(&0xFAC688FAC688u64.to_le_bytes()[..6]);
+        let block = alphas.into_iter().chain(colors.iter().copied()).collect::<Vec<_>>();
+        let texels = decode_bc_block(BcFormat::Bc3, &block);
+        assert_eq!(texels[..8].iter().map(|t| t[3]).collect::<Vec<_>>(), [255, 0, 218, 182, 145, 109, 72, 36]);
+        assert_eq!(texels[0][..3], [255, 0, 0]);
+    }
+
+    #[test]
+    fn decodes_bc4_palettes() {
+        let indices = 0xFAC688FAC688u64.to_le_bytes();
+        let block = |e0: u8, e1: u8| [e0, e1].into_iter().chain(indices[..6].iter().copied()).collect::<Vec<_>>();
+
+        assert_eq!(decode_bc4_channel(&block(255, 0), false)[..8], [255, 0, 218, 182, 145, 109, 72, 36]);
+        // e0 <= e1 interpolates 4 values and adds min and max
+        assert_eq!(decode_bc4_channel(&block(0, 255), false)[..8], [0, 255, 51, 102, 153, 204, 0, 255]);
+
+        let signed = decode_bc4_channel(&block(127, -127i8 as u8), true).map(|v| v as i8);
+        assert_eq!(signed[..8], [127, -127, 90, 54, 18, -18, -54, -90]);
+        // -128 is clamped to -127
+        let signed = decode_bc4_channel(&block(-128i8 as u8, 0), true).map(|v| v as i8);
+        assert_eq!(signed[0], -127);
+
+        let texels = decode_bc_block(BcFormat::Bc5 { signed: false }, &[block(255, 0), block(0, 255)].concat());
+        assert_eq!(texels[2], [218, 51, 0, 255]);
+    }
+
+    #[test]
+    fn decodes_bc_levels_with_partial_blocks() {
+        // A 6x5 texture has 2x2 blocks, the 3x2 mip one block
+        let red = [RED_565, RED_565, [0; 2], [0; 2]].concat();
+        let texture_data = TextureData {
+            format: vk::Format::BC1_RGB_SRGB_BLOCK,
+            size: uvec2(6, 5),
+            data: red.repeat(5),
+            level_offsets: vec![0, 32],
+        };
+
+        let decoded = decode_bc_to_rgba8(&texture_data).unwrap();
+        assert_eq!(decoded.format, vk::Format::R8G8B8A8_SRGB);
+        assert_eq!(decoded.level_offsets, [0, 6 * 5 * 4]);
+        assert_eq!(decoded.data.len(), (6 * 5 + 3 * 2) * 4);
+        assert!(decoded.data.chunks_exact(4).all(|t| t == [255, 0, 0, 255]));
+
+        // Missing level data and formats without a decoder
+        let truncated = TextureData { data: red.repeat(4), ..texture_data };
+        assert!(decode_bc_to_rgba8(&truncated).is_none());
+        let bc7 = TextureData { format: vk::Format::BC7_UNORM_BLOCK, ..truncated };
+        assert!(decode_bc_to_rgba8(&bc7).is_none());
+    }
+
+    #[test]
+    fn aligns_ktx2_level_offsets() {
+        // 3 byte texels need offsets aligned to 12
+        let levels: [&[u8]; 3] = [&[1; 4 * 3 * 3], &[2; 2 * 3], &[3; 3]];
+        let texture_data = read_ktx2(&ktx2_file(vk::Format::R8G8B8_UNORM, uvec2(4, 3), &levels)).unwrap();
+
+        assert_eq!(texture_data.level_offsets, [0, 36, 48]);
+        assert_eq!(texture_data.data[36..42], [2; 6]);
+        assert_eq!(texture_data.data[42..48], [0; 6]);
+        assert_eq!(texture_data.data[48..], [3; 3]);
+
+        // BC1 blocks are 8 bytes
+        let levels: [&[u8]; 2] = [&[1; 4 * 8], &[2; 8]];
+        let texture_data = read_ktx2(&ktx2_file(vk::Format::BC1_RGBA_UNORM_BLOCK, uvec2(6, 5), &levels)).unwrap();
+        assert_eq!(texture_data.level_offsets, [0, 32]);
+    }
+
+    #[test]
+    fn computes_dds_level_offsets() {
+        let texture_data = read_dds(&dds_file(ddsfile::DxgiFormat::BC1_UNorm, uvec2(6, 5), 3)).unwrap();
+        assert_eq!(texture_data.format, vk::Format::BC1_RGBA_UNORM_BLOCK);
+        assert_eq!(texture_data.level_offsets, [0, 32, 40]);
+        assert_eq!(texture_data.data.len(), 48);
+
+        let texture_data = read_dds(&dds_file(ddsfile::DxgiFormat::R8G8B8A8_UNorm, uvec2(4, 2), 2)).unwrap();
+        assert_eq!(texture_data.level_offsets, [0, 32]);
+        assert_eq!(texture_data.data.len(), 40);
+
+        // Without data for the first level
+        let error = read_dds(&dds_file(ddsfile::DxgiFormat::BC1_UNorm, uvec2(0, 0), 1)).unwrap_err();
+        assert!(error.to_string().contains("no complete mip level"));
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use ash::vk;
+
+use crate::vulkan::{CommandBuffer, CommandPool, Context, RenderingInheritance};
+
+/// One command pool per recording thread and in flight frame.
+/// Command pools are not thread safe, so every thread records into its own pool.
+#[derive(Debug)]
+pub struct ThreadCommandPools {
+    per_in_flight_frame: Vec<Vec<ThreadCommandPool>>,
+}
+
+#[derive(Debug)]
+pub struct ThreadCommandPool {
+    pool: CommandPool,
+    buffers: Vec<CommandBuffer>,
+    used: usize,
+}
+
+impl Context {
+    pub fn create_thread_command_pools(&self, thread_count: usize, in_flight_count: usize) -> Result<ThreadCommandPools> {
+        let per_in_flight_frame = (0..in_flight_count)
+            .map(|_| {
+                (0..thread_count)
+                    .map(|_| {
+                        let pool = self.create_command_pool(
+                            self.physical_device.graphics_queue_family,
+                            Some(vk::CommandPoolCreateFlags::TRANSIENT),
+                        )?;
+
+                        Ok(ThreadCommandPool {
+                            pool,
+                            buffers: vec![],
+                            used: 0,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ThreadCommandPools { per_in_flight_frame })
+    }
+}
+
+impl ThreadCommandPools {
+    pub fn thread_count(&self) -> usize {
+        self.per_in_flight_frame.first().map(|p| p.len()).unwrap_or(0)
+    }
+
+    /// The pools of the in flight frame. Hand one to each recording thread.
+    pub fn pools(&mut self, in_flight_index: usize) -> &mut [ThreadCommandPool] {
+        &mut self.per_in_flight_frame[in_flight_index]
+    }
+
+    /// Has to be called after the in flight frame finished on the device.
+    pub(crate) fn reset(&mut self, in_flight_index: usize) -> Result<()> {
+        for pool in self.per_in_flight_frame[in_flight_index].iter_mut() {
+            if pool.used == 0 {
+                continue;
+            }
+
+            pool.pool.reset()?;
+            pool.used = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl ThreadCommandPool {
+    /// Returns a secondary command buffer that already began recording.
+    pub fn begin_secondary(&mut self, inheritance: &RenderingInheritance) -> Result<&CommandBuffer> {
+        if self.used == self.buffers.len() {
+            let buffer = self.pool.allocate_command_buffer(vk::CommandBufferLevel::SECONDARY)?;
+            self.buffers.push(buffer);
+        }
+
+        let buffer = &self.buffers[self.used];
+        self.used += 1;
+        buffer.begin_secondary(inheritance)?;
+        buffer.gpu_profiler.add_frame_command_buffer(buffer);
+
+        Ok(buffer)
+    }
+
+    /// The secondary command buffers recorded this frame, in the order they were begun.
+    pub fn recorded(&self) -> &[CommandBuffer] {
+        &self.buffers[..self.used]
+    }
+}
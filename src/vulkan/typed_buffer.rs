@@ -0,0 +1,126 @@
+use std::marker::PhantomData;
+use std::mem::size_of;
+
+use anyhow::{bail, Result};
+use ash::vk;
+use bytemuck::Pod;
+use gpu_allocator::MemoryLocation;
+
+use crate::vulkan::{Buffer, Context, WriteDescriptorSetKind};
+
+/// Buffer holding len elements of T. All host access goes through T, so the element type can not mismatch between calls.
+#[derive(Debug)]
+pub struct TypedBuffer<T: Pod> {
+    buffer: Buffer,
+    len: usize,
+    _m: PhantomData<T>,
+}
+
+impl Context {
+    pub fn create_typed_buffer<T: Pod>(
+        &self,
+        usage: vk::BufferUsageFlags,
+        memory_location: MemoryLocation,
+        len: usize,
+    ) -> Result<TypedBuffer<T>> {
+        // Vulkan does not allow empty buffers.
+        let size = (len * size_of::<T>()).max(1);
+        let buffer = self.create_buffer(usage, memory_location, size as _)?;
+
+        Ok(TypedBuffer {
+            buffer,
+            len,
+            _m: PhantomData,
+        })
+    }
+
+    pub fn create_typed_buffer_from_data<T: Pod>(
+        &self,
+        usage: vk::BufferUsageFlags,
+        data: &[T],
+    ) -> Result<TypedBuffer<T>> {
+        let mut buffer = self.create_typed_buffer(usage, MemoryLocation::CpuToGpu, data.len())?;
+        buffer.write(data)?;
+
+        Ok(buffer)
+    }
+
+    pub fn create_gpu_only_typed_buffer_from_data<T: Pod>(
+        &self,
+        usage: vk::BufferUsageFlags,
+        data: &[T],
+    ) -> Result<TypedBuffer<T>> {
+        if data.is_empty() {
+            bail!("Can not create a gpu only typed buffer from empty data.");
+        }
+        let buffer = self.create_gpu_only_buffer_from_data(usage, data)?;
+
+        Ok(TypedBuffer {
+            buffer,
+            len: data.len(),
+            _m: PhantomData,
+        })
+    }
+}
+
+impl<T: Pod> TypedBuffer<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub fn write(&mut self, data: &[T]) -> Result<()> {
+        self.write_at(0, data)
+    }
+
+    pub fn write_at(&mut self, index: usize, data: &[T]) -> Result<()> {
+        if index + data.len() > self.len {
+            bail!("Writing {} elements at {index} is out of bounds for typed buffer of len {}", data.len(), self.len);
+        }
+        self.as_mut_slice()?[index..index + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn read(&self) -> Result<Vec<T>> {
+        self.check_host_visible()?;
+        Ok(self.buffer.get_data_from_buffer(self.len))
+    }
+
+    pub fn as_mut_slice(&mut self) -> Result<&mut [T]> {
+        self.check_host_visible()?;
+        let len = self.len;
+        Ok(&mut self.buffer.get_mapped_slice()[..len])
+    }
+
+    pub fn get_device_address(&self) -> u64 {
+        self.buffer.get_device_address()
+    }
+
+    pub fn storage_descriptor_kind(&self) -> WriteDescriptorSetKind<'_> {
+        WriteDescriptorSetKind::StorageBuffer {
+            buffer: &self.buffer,
+        }
+    }
+
+    pub fn uniform_descriptor_kind(&self) -> WriteDescriptorSetKind<'_> {
+        WriteDescriptorSetKind::UniformBuffer {
+            buffer: &self.buffer,
+        }
+    }
+
+    fn check_host_visible(&self) -> Result<()> {
+        if !self.buffer.is_host_visible() {
+            bail!("Typed buffer is not host visible. Use a staging buffer or the staging belt to upload data.");
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use std::mem::{align_of, size_of_val};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk::{self, Extent2D, Extent3D, Offset2D, Offset3D};
 use ash::vk::ImageUsageFlags;
 use glam::{uvec2, uvec3, UVec2, UVec3};
@@ -16,6 +16,83 @@ pub fn compute_aligned_size(size: u32, alignment: u32) -> u32 {
     (size + (alignment - 1)) & !(alignment - 1)
 }
 
+/// Texel block extent and bytes per block of color formats.
+/// None for depth stencil, multi planar and extension formats.
+pub fn format_texel_block(format: vk::Format) -> Option<(UVec2, u32)> {
+    use vk::Format as F;
+
+    // First and last format of ranges with the same block size
+    const UNCOMPRESSED: [(F, F, u32); 19] = [
+        (F::R4G4_UNORM_PACK8, F::R4G4_UNORM_PACK8, 1),
+        (F::R4G4B4A4_UNORM_PACK16, F::A1R5G5B5_UNORM_PACK16, 2),
+        (F::R8_UNORM, F::R8_SRGB, 1),
+        (F::R8G8_UNORM, F::R8G8_SRGB, 2),
+        (F::R8G8B8_UNORM, F::B8G8R8_SRGB, 3),
+        (F::R8G8B8A8_UNORM, F::A2B10G10R10_SINT_PACK32, 4),
+        (F::R16_UNORM, F::R16_SFLOAT, 2),
+        (F::R16G16_UNORM, F::R16G16_SFLOAT, 4),
+        (F::R16G16B16_UNORM, F::R16G16B16_SFLOAT, 6),
+        (F::R16G16B16A16_UNORM, F::R16G16B16A16_SFLOAT, 8),
+        (F::R32_UINT, F::R32_SFLOAT, 4),
+        (F::R32G32_UINT, F::R32G32_SFLOAT, 8),
+        (F::R32G32B32_UINT, F::R32G32B32_SFLOAT, 12),
+        (F::R32G32B32A32_UINT, F::R32G32B32A32_SFLOAT, 16),
+        (F::R64_UINT, F::R64_SFLOAT, 8),
+        (F::R64G64_UINT, F::R64G64_SFLOAT, 16),
+        (F::R64G64B64_UINT, F::R64G64B64_SFLOAT, 24),
+        (F::R64G64B64A64_UINT, F::R64G64B64A64_SFLOAT, 32),
+        (F::B10G11R11_UFLOAT_PACK32, F::E5B9G9R9_UFLOAT_PACK32, 4),
+    ];
+    const BLOCK_4X4: [(F, F, u32); 8] = [
+        (F::BC1_RGB_UNORM_BLOCK, F::BC1_RGBA_SRGB_BLOCK, 8),
+        (F::BC2_UNORM_BLOCK, F::BC3_SRGB_BLOCK, 16),
+        (F::BC4_UNORM_BLOCK, F::BC4_SNORM_BLOCK, 8),
+        (F::BC5_UNORM_BLOCK, F::BC7_SRGB_BLOCK, 16),
+        (F::ETC2_R8G8B8_UNORM_BLOCK, F::ETC2_R8G8B8A1_SRGB_BLOCK, 8),
+        (F::ETC2_R8G8B8A8_UNORM_BLOCK, F::ETC2_R8G8B8A8_SRGB_BLOCK, 16),
+        (F::EAC_R11_UNORM_BLOCK, F::EAC_R11_SNORM_BLOCK, 8),
+        (F::EAC_R11G11_UNORM_BLOCK, F::EAC_R11G11_SNORM_BLOCK, 16),
+    ];
+    const ASTC_EXTENTS: [(u32, u32); 14] = [
+        (4, 4), (5, 4), (5, 5), (6, 5), (6, 6), (8, 5), (8, 6),
+        (8, 8), (10, 5), (10, 6), (10, 8), (10, 10), (12, 10), (12, 12),
+    ];
+
+    let raw = format.as_raw();
+    let in_range = |first: F, last: F| (first.as_raw()..=last.as_raw()).contains(&raw);
+
+    if let Some(&(_, _, bytes)) = UNCOMPRESSED.iter().find(|(first, last, _)| in_range(*first, *last)) {
+        return Some((UVec2::ONE, bytes));
+    }
+    if let Some(&(_, _, bytes)) = BLOCK_4X4.iter().find(|(first, last, _)| in_range(*first, *last)) {
+        return Some((UVec2::splat(4), bytes));
+    }
+
+    let astc_index = if in_range(F::ASTC_4X4_UNORM_BLOCK, F::ASTC_12X12_SRGB_BLOCK) {
+        // UNORM and SRGB alternate
+        (raw - F::ASTC_4X4_UNORM_BLOCK.as_raw()) / 2
+    } else if in_range(F::ASTC_4X4_SFLOAT_BLOCK, F::ASTC_12X12_SFLOAT_BLOCK) {
+        raw - F::ASTC_4X4_SFLOAT_BLOCK.as_raw()
+    } else {
+        return None;
+    };
+    let (x, y) = ASTC_EXTENTS[astc_index as usize];
+    Some((uvec2(x, y), 16))
+}
+
+/// Buffer offsets of buffer to image copies have to be multiples of 4 and of the texel block size.
+pub fn buffer_image_copy_alignment(block_bytes: u32) -> u32 {
+    match block_bytes % 4 {
+        0 => block_bytes,
+        2 => block_bytes * 2,
+        _ => block_bytes * 4,
+    }
+}
+
+pub fn compute_mip_levels(size: UVec2) -> u32 {
+    32 - size.max_element().max(1).leading_zeros()
+}
+
 pub fn read_shader_from_bytes(bytes: &[u8]) -> Result<Vec<u32>> {
     let mut cursor = std::io::Cursor::new(bytes);
     Ok(ash::util::read_spv(&mut cursor)?)
@@ -36,8 +113,12 @@ impl Context {
         data: &[T],
         alignment: usize,
     ) -> Result<Buffer> {
-        let size = size_of_val(data) as _;
-        let staging_buffer = self.create_buffer(
+        if data.is_empty() {
+            bail!("Can not create a gpu only buffer from empty data.");
+        }
+        let (alignment, element_size) = Buffer::aligned_element_size::<T>(alignment);
+        let size = (data.len() * element_size) as _;
+        let mut staging_buffer = self.create_buffer(
             vk::BufferUsageFlags::TRANSFER_SRC,
             MemoryLocation::CpuToGpu,
             size,
@@ -57,13 +138,13 @@ impl Context {
         Ok(buffer)
     }
 
-    // Sometimes it would be more performand to reuse the staging buffer
+    // Blocks until the upload finished. Use the StagingBelt of the Engine to batch uploads into the frame.
     pub fn copy_data_to_gpu_only_buffer<T: Copy>(
         &self,
         data: &[T],
         buffer: &Buffer,
     ) -> Result<()> {
-        let staging_buffer = self.create_buffer(
+        let mut staging_buffer = self.create_buffer(
             vk::BufferUsageFlags::TRANSFER_SRC,
             MemoryLocation::CpuToGpu,
             buffer.size,
@@ -133,7 +214,7 @@ impl Context {
     ) -> Result<ImageAndView> {
 
         let size = size_of_val(data) as _;
-        let staging_buffer = self.create_buffer(
+        let mut staging_buffer = self.create_buffer(
             vk::BufferUsageFlags::TRANSFER_SRC,
             MemoryLocation::CpuToGpu,
             size,